@@ -0,0 +1,154 @@
+use std::{f32::consts::PI, sync::Arc};
+
+use rustfft::{Fft, FftPlanner, num_complex::Complex};
+
+use crate::synth::Key;
+
+/// Krumhansl–Kessler probe tone ratings, starting on the tonic
+const MAJOR_PROFILE: [f32; 12] = [
+    6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88,
+];
+const MINOR_PROFILE: [f32; 12] = [
+    6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17,
+];
+
+/// Energy per pitch class, index 0 is C
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Chroma(pub [f32; 12]);
+
+impl Chroma {
+    pub fn add(&mut self, other: &Chroma) {
+        self.0.iter_mut().zip(other.0).for_each(|(a, b)| *a += b);
+    }
+
+    pub fn add_note(&mut self, n: u8, weight: f32) {
+        self.0[n as usize % 12] += weight;
+    }
+
+    pub fn scale(&mut self, factor: f32) {
+        self.0.iter_mut().for_each(|c| *c *= factor);
+    }
+
+    pub fn is_silent(&self) -> bool {
+        self.0.iter().all(|c| *c <= 0.)
+    }
+
+    /// Best matching key by correlation with the Krumhansl–Schmuckler
+    /// profiles, and the correlation itself
+    pub fn key(&self) -> Option<(Key, f32)> {
+        if self.is_silent() {
+            return None;
+        }
+        (0..12_u8)
+            .flat_map(|tonic| [(tonic, false), (tonic, true)])
+            .map(|(tonic, minor)| {
+                let profile = if minor {
+                    &MINOR_PROFILE
+                } else {
+                    &MAJOR_PROFILE
+                };
+                let rotated: [f32; 12] =
+                    std::array::from_fn(|pc| profile[(pc + 12 - tonic as usize) % 12]);
+                (
+                    Key::from_tonic(tonic, minor),
+                    correlation(&self.0, &rotated),
+                )
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+    }
+}
+
+fn correlation(a: &[f32; 12], b: &[f32; 12]) -> f32 {
+    let (ma, mb) = (a.iter().sum::<f32>() / 12., b.iter().sum::<f32>() / 12.);
+    let (cov, va, vb) = a.iter().zip(b).fold((0., 0., 0.), |(cov, va, vb), (x, y)| {
+        let (dx, dy) = (x - ma, y - mb);
+        (cov + dx * dy, va + dx * dx, vb + dy * dy)
+    });
+    if va > 0. && vb > 0. {
+        cov / (va * vb).sqrt()
+    } else {
+        0.
+    }
+}
+
+/// Folds FFT bins between `LO` and `HI` Hz onto pitch classes
+pub struct ChromaAnalyzer {
+    sample_rate: u32,
+    fft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    buf: Vec<Complex<f32>>,
+}
+
+impl ChromaAnalyzer {
+    const LO: f32 = 55.;
+    const HI: f32 = 5000.;
+
+    pub fn new(sample_rate: u32, size: usize) -> Self {
+        let fft = FftPlanner::<f32>::new().plan_fft_forward(size);
+        let window = (0..size)
+            .map(|idx| 0.5 - 0.5 * (2. * PI * idx as f32 / (size - 1) as f32).cos())
+            .collect();
+        Self {
+            sample_rate,
+            fft,
+            window,
+            buf: vec![Complex::default(); size],
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn process(&mut self, frame: &[f32]) -> Chroma {
+        let size = self.buf.len();
+        self.buf.iter_mut().enumerate().for_each(|(idx, c)| {
+            *c = Complex::new(frame.get(idx).copied().unwrap_or(0.) * self.window[idx], 0.)
+        });
+        self.fft.process(&mut self.buf);
+
+        let mut chroma = Chroma::default();
+        (1..size / 2).for_each(|bin| {
+            let freq = bin as f32 * self.sample_rate as f32 / size as f32;
+            if (Self::LO..Self::HI).contains(&freq) {
+                let n = (69. + 12. * (freq / 440.).log2()).round() as i32;
+                chroma.0[n.rem_euclid(12) as usize] += self.buf[bin].norm_sqr();
+            }
+        });
+        chroma
+    }
+
+    /// Sum of the chroma of every half-overlapping frame of `samples`
+    pub fn analyze(&mut self, samples: &[f32]) -> Chroma {
+        let size = self.size();
+        let mut total = Chroma::default();
+        (0..samples.len().saturating_sub(size).max(1))
+            .step_by(size / 2)
+            .for_each(|start| {
+                let end = (start + size).min(samples.len());
+                total.add(&self.process(&samples[start..end]));
+            });
+        total
+    }
+}
+
+/// Chroma of recently played notes, fading so the estimate can follow
+/// modulations
+#[derive(Debug, Clone, Default)]
+pub struct NoteChroma {
+    chroma: Chroma,
+}
+
+impl NoteChroma {
+    /// Weight kept by older notes each time a new one arrives
+    const MEMORY: f32 = 0.95;
+
+    pub fn note_on(&mut self, n: u8) {
+        self.chroma.scale(Self::MEMORY);
+        self.chroma.add_note(n, 1.);
+    }
+
+    pub fn key(&self) -> Option<Key> {
+        self.chroma.key().map(|(key, _)| key)
+    }
+}
@@ -0,0 +1,124 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::Sender,
+    },
+    thread,
+    time::Instant,
+};
+
+use crate::{
+    midi::MidiParser,
+    midi_clock::{ClockFollower, SyncEvent},
+    msg::Msg,
+    smf::Transport,
+};
+
+/// Tempo the generative sequences are written at
+pub const REFERENCE_BPM: f64 = 120.;
+
+/// Smallest change in BPM passed on to the synth
+const TEMPO_STEP: f64 = 0.05;
+
+/// Time for the generative sequences: free-running, or following an
+/// external MIDI clock, where it only moves while the transport runs
+#[derive(Debug)]
+pub struct ClockSync {
+    bpm: AtomicU64,
+    running: AtomicBool,
+}
+
+impl ClockSync {
+    /// Real time, running
+    pub fn free() -> Arc<Self> {
+        Arc::new(Self {
+            bpm: AtomicU64::new(REFERENCE_BPM.to_bits()),
+            running: AtomicBool::new(true),
+        })
+    }
+
+    pub fn bpm(&self) -> f64 {
+        f64::from_bits(self.bpm.load(Ordering::Relaxed))
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    /// Waits out `seconds` at `REFERENCE_BPM`, scaled to the current tempo
+    pub fn wait(&self, seconds: f32) {
+        let mut left = seconds as f64;
+        let mut last = Instant::now();
+        while left > 0. {
+            thread::yield_now();
+            let now = Instant::now();
+            if self.is_running() {
+                left -= (now - last).as_secs_f64() * self.bpm() / REFERENCE_BPM;
+            }
+            last = now;
+        }
+    }
+}
+
+/// Follows the clock and transport arriving on an ALSA raw MIDI port, on a
+/// thread of its own. Time stands still until the first start or continue.
+pub fn follow(path: impl AsRef<Path>, tx: Sender<Msg>) -> io::Result<Arc<ClockSync>> {
+    let mut file = File::open(path)?;
+    let sync = Arc::new(ClockSync {
+        bpm: AtomicU64::new(REFERENCE_BPM.to_bits()),
+        running: AtomicBool::new(false),
+    });
+    let shared = sync.clone();
+    thread::spawn(move || {
+        let mut parser = MidiParser::new();
+        let mut follower = ClockFollower::new();
+        let mut sent = None;
+        let mut buf = [0; 256];
+        loop {
+            let len = match file.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(e) => {
+                    println!("MIDI clock input stopped: {e}");
+                    break;
+                }
+            };
+            let now = Instant::now();
+            for message in buf[..len].iter().filter_map(|b| parser.push(*b)) {
+                let msgs = match follower.handle(&message, now) {
+                    Some(SyncEvent::Start) => vec![
+                        Msg::Transport(Transport::Seek(0.)),
+                        Msg::Transport(Transport::Play),
+                    ],
+                    Some(SyncEvent::Continue) => vec![Msg::Transport(Transport::Play)],
+                    Some(SyncEvent::Stop) => vec![Msg::Transport(Transport::Pause)],
+                    Some(SyncEvent::Locate(beats)) => vec![Msg::Locate(beats)],
+                    None => Vec::new(),
+                };
+                shared
+                    .running
+                    .store(follower.is_running(), Ordering::Relaxed);
+                let tempo = follower
+                    .bpm()
+                    .filter(|bpm| sent.is_none_or(|s: f64| (bpm - s).abs() >= TEMPO_STEP));
+                let tempo = tempo.map(|bpm| {
+                    sent = Some(bpm);
+                    shared.bpm.store(bpm.to_bits(), Ordering::Relaxed);
+                    Msg::Tempo(bpm as f32)
+                });
+                if msgs
+                    .into_iter()
+                    .chain(tempo)
+                    .any(|msg| tx.send(msg).is_err())
+                {
+                    return;
+                }
+            }
+        }
+    });
+    Ok(sync)
+}
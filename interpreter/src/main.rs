@@ -1,115 +1,303 @@
+// The `#[path]` modules are sound-studies' own; the interpreter only uses
+// part of each, so their dead code is allowed
+#[allow(dead_code)]
+#[path = "../../src/alsa_midi.rs"]
+mod alsa_midi;
+mod chroma;
+mod clock_sync;
+mod meter;
+#[allow(dead_code)]
+#[path = "../../src/midi.rs"]
+mod midi;
+#[allow(dead_code)]
+#[path = "../../src/midi_clock.rs"]
+mod midi_clock;
+#[allow(dead_code)]
+#[path = "../../src/midi_device.rs"]
+mod midi_device;
+#[allow(dead_code)]
+#[path = "../../src/midi_input.rs"]
+mod midi_input;
+mod midi_out;
+#[allow(dead_code)]
+#[path = "../../src/midi_output.rs"]
+mod midi_output;
 mod msg;
+mod nodes;
+mod part;
+#[allow(dead_code)]
+#[path = "../../src/patch.rs"]
+mod patch;
 mod player;
+#[allow(dead_code)]
+#[path = "../../src/smf.rs"]
+mod smf;
 mod synth;
 mod track;
+#[allow(dead_code)]
+#[path = "../../src/usb_midi.rs"]
+mod usb_midi;
 mod utils;
+#[allow(dead_code)]
+#[path = "../../src/wav.rs"]
+mod wav;
 
 use msg::Msg::*;
-use ndarray::{Array1, Ix1, array};
 use std::{
-    sync::{Arc, Mutex, mpsc::channel},
+    sync::mpsc::channel,
     thread,
     time::{Duration, Instant},
 };
 use synth::Synth;
+use track::Seq;
 
 use crate::player::Player;
 
 static DURATIONS: [f32; 1] = [0.75];
 static PITCHES: [u8; 11] = [57, 60, 64, 69, 72, 76, 69, 64, 60, 57, 74];
 
+/// Value following `name` on the command line
+fn arg(name: &str) -> Option<String> {
+    std::env::args().skip_while(|a| a != name).nth(1)
+}
+
+fn flag(name: &str) -> bool {
+    std::env::args().any(|a| a == name)
+}
+
+/// Plays a MIDI file from the synth thread until it ends, or forever with
+/// `--loop`. Following a MIDI clock, it waits for a start and plays on.
+fn play_smf(
+    handle: &synth::EngineHandle,
+    path: &str,
+    synced: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let track = arg("--track").and_then(|t| t.parse().ok());
+    // the player clamps it too; the wait for the end has to agree
+    let tempo = arg("--tempo")
+        .and_then(|t| t.parse::<f64>().ok())
+        .filter(|t| t.is_finite())
+        .unwrap_or(1.)
+        .clamp(smf::MIN_TEMPO_SCALE, smf::MAX_TEMPO_SCALE);
+    let sequence = smf::Smf::load(path)?.sequence(track);
+    let length = sequence.length / tempo;
+    let mut player = smf::SmfPlayer::new(sequence, player::STREAM_CONFIG.sample_rate());
+    player.apply(smf::Transport::TempoScale(tempo));
+    player.apply(smf::Transport::Loop(flag("--loop")));
+    handle.send(Sequence(Box::new(player)))?;
+    if synced {
+        println!("{path}: waiting for MIDI start");
+    } else {
+        handle.send(Transport(smf::Transport::Play))?;
+        println!("{path}: {length:.1}s");
+    }
+    let now = Instant::now();
+    while synced || flag("--loop") || now.elapsed() < Duration::from_secs_f64(length + 1.) {
+        thread::sleep(Duration::from_millis(100));
+    }
+    Ok(())
+}
+
+/// Key of a recording from its FFT chroma
+fn key_of(path: &str) -> Result<synth::Key, Box<dyn std::error::Error>> {
+    let wav = wav::Wav::read(path)?;
+    let chroma = chroma::ChromaAnalyzer::new(wav.sample_rate, 8192).analyze(&wav.mono());
+    let (key, fit) = chroma.key().ok_or("recording is silent")?;
+    println!("{path}: {key:?} (r = {fit:.2})");
+    Ok(key)
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(path) = arg("--meter") {
+        println!("{path}: {}", meter::analyze_wav(&path)?);
+        return Ok(());
+    }
+
+    // `--notes <file>` plays a transcribed note list; `--onsets <file>`
+    // locks the sequence to the gaps of a recorded loop
+    let (pitches, durations): (&'static [u8], &'static [f32]) = match arg("--notes") {
+        Some(path) => {
+            let (pitches, durations) = utils::load_note_list(path)?;
+            (pitches.leak(), durations.leak())
+        }
+        None => (&PITCHES, &DURATIONS),
+    };
+    let durations: &'static [f32] = match arg("--onsets") {
+        Some(path) => utils::load_durations(path)?.leak(),
+        None => durations,
+    };
+
+    // `--tremolo <beats>` adds a tremolo of that period, kept in time with
+    // the MIDI clock, as the first node of the chain; `--lowpass <Hz>` and
+    // `--gain <0-4>` follow it
+    let mut seq = Seq::new();
+    let sample_rate = player::STREAM_CONFIG.sample_rate();
+    let tremolo = arg("--tremolo").and_then(|d| d.parse().ok());
+    if let Some(division) = tremolo {
+        let bpm = clock_sync::REFERENCE_BPM as f32;
+        seq.add_layer(nodes::Tremolo::new(sample_rate, bpm, division, 0.5));
+    }
+    if let Some(cutoff) = arg("--lowpass").and_then(|c| c.parse().ok()) {
+        seq.add_layer(nodes::LowPass::new(sample_rate, cutoff));
+    }
+    if let Some(gain) = arg("--gain").and_then(|g| g.parse().ok()) {
+        seq.add_layer(nodes::Gain::new(gain));
+    }
     let synth = Synth::default();
     let player = Player::new();
-    let handle = synth.connect(player);
+    let handle = synth.connect(player, seq.build());
+    // `--tremolo-depth <0-1>` changes its depth from 0.5
+    if let Some(depth) = arg("--tremolo-depth").and_then(|d| d.parse().ok())
+        && tremolo.is_some()
+    {
+        handle.send(SetParam(0, 1, depth))?;
+    }
+
+    // `--midi-clock <port>` slaves sequences, the generative threads and
+    // tempo-synced nodes to the clock and transport coming in on that port
+    let sync = match arg("--midi-clock") {
+        Some(path) => {
+            println!("following MIDI clock on {path}");
+            clock_sync::follow(path, handle.sender())?
+        }
+        None => clock_sync::ClockSync::free(),
+    };
+    let synced = flag("--midi-clock");
 
     handle.send(SetVolume(0.0025))?;
+    if let Some(path) = arg("--key-from") {
+        handle.send(SetKey(key_of(&path)?))?;
+    }
+    handle.send(FollowKey(flag("--follow-key")))?;
+    handle.send(SnapToKey(flag("--snap")))?;
     handle.send(Play)?;
 
-    let duration_sum: f32 = DURATIONS.iter().sum();
-    let pitch_count = PITCHES.iter().len();
+    // `--programs a,b,c` gives the generative threads, on channels 1, 2 and
+    // 3, General MIDI programs of their own
+    let programs = arg("--programs")
+        .map(|p| p.split(',').map(|p| p.trim().parse::<u8>()).collect())
+        .transpose()?
+        .unwrap_or(Vec::new());
+    for (channel, program) in programs.iter().enumerate() {
+        handle.send(Program(channel as u8, *program))?;
+    }
+
+    if let Some(path) = arg("--smf") {
+        play_smf(&handle, &path, synced)?;
+        handle.send(Stop)?;
+        handle.send(Disconnect)?;
+        handle.join().unwrap();
+        return Ok(());
+    }
+
+    let duration_sum: f32 = durations.iter().sum();
 
     let (tx, rx) = channel();
     let tx1 = tx.clone();
     let tx2 = tx.clone();
     let tx3 = tx.clone();
+    let sync1 = sync.clone();
+    let sync2 = sync.clone();
 
     let t1 = thread::spawn(move || {
-        DURATIONS
-                .iter()
-                .rev()
-                .cycle()
-                .enumerate()
-                .for_each(|(idx, duration_1)| {
-                    let mut p_iter = PITCHES.iter().rev().cycle();
-                    let mut p_iter_2 = PITCHES.iter().cycle();
-                    let tx1 = tx1.clone();
-                    DURATIONS.iter().cycle().take(idx).enumerate().for_each(
-                        |(idx2, duration_2)| {
-                            // let p1 = p_iter.next().unwrap() + (idx2 % 2) as u8 * (12 / 1 + idx % 3) as u8;
-                            let p1 = *p_iter.next().unwrap() as u8 + ((idx % 2) * 7) as u8;
-                            let p2 = *p_iter_2.next().unwrap() as u8 + ((idx % 2) * 7) as u8;
-                            let d1 = *duration_2 as f32 / duration_sum as f32 * *duration_1 / (1. + (idx % 3) as f32 )
+        durations
+            .iter()
+            .rev()
+            .cycle()
+            .enumerate()
+            .for_each(|(idx, duration_1)| {
+                let mut p_iter = pitches.iter().rev().cycle();
+                let mut p_iter_2 = pitches.iter().cycle();
+                let tx1 = tx1.clone();
+                durations.iter().cycle().take(idx).for_each(|duration_2| {
+                    // let p1 = p_iter.next().unwrap() + (idx2 % 2) as u8 * (12 / 1 + idx % 3) as u8;
+                    let p1 = (*p_iter.next().unwrap() + ((idx % 2) * 7) as u8).min(127);
+                    let p2 = (*p_iter_2.next().unwrap() + ((idx % 2) * 7) as u8).min(127);
+                    let d1 = *duration_2 / duration_sum * *duration_1 / (1. + (idx % 3) as f32 )
                             // + 2.
                             // + (f32::sin(idx2 as f32)) * 2.
                             ;
-                            // + f32::abs(f32::sin(idx as f32 * 1.15));
-                            tx1.send(NoteOn(p1)).unwrap();
-                            tx1.send(NoteOn(p2)).unwrap();
-                            let now = Instant::now();
-                            while now.elapsed() < Duration::from_secs_f32(d1) {}
-                            tx1.send(NoteOff(p1));
-                            tx1.send(NoteOff(p2));
-                        },
-                    );
+                    // + f32::abs(f32::sin(idx as f32 * 1.15));
+                    tx1.send(NoteOn(0, p1)).unwrap();
+                    tx1.send(NoteOn(0, p2)).unwrap();
+                    sync1.wait(d1);
+                    tx1.send(NoteOff(0, p1)).unwrap();
+                    tx1.send(NoteOff(0, p2)).unwrap();
                 });
+            });
     });
     let t2 = thread::spawn(move || {
-        DURATIONS
+        durations
             .iter()
             .cycle()
             .enumerate()
             .for_each(|(idx, duration_1)| {
-                let mut p_iter = PITCHES.iter().rev().cycle();
-                let mut p_iter_2 = PITCHES.iter().cycle();
+                let mut p_iter = pitches.iter().rev().cycle();
+                let mut p_iter_2 = pitches.iter().cycle();
                 let tx2 = tx2.clone();
-                DURATIONS
-                    .iter()
-                    .cycle()
-                    .enumerate()
-                    .for_each(|(idx2, duration_2)| {
-                        // let p1 = p_iter.next().unwrap() + (idx2 % 2) as u8 * (12 / 1 + idx % 3) as u8;
-                        let p1 = *p_iter.next().unwrap() as u8 - 12 + ((idx % 2) * 7) as u8;
-                        let p2 = *p_iter_2.next().unwrap() as u8 - 24 + ((idx % 2) * 7) as u8;
-                        let d1 = (
-                            *duration_2 as f32 / duration_sum as f32 * *duration_1 / (1. + (idx % 3) as f32 )
+                durations.iter().cycle().for_each(|duration_2| {
+                    // let p1 = p_iter.next().unwrap() + (idx2 % 2) as u8 * (12 / 1 + idx % 3) as u8;
+                    let p1 = p_iter.next().unwrap().saturating_sub(12) + ((idx % 2) * 7) as u8;
+                    let p2 = p_iter_2.next().unwrap().saturating_sub(24) + ((idx % 2) * 7) as u8;
+                    let d1 = (
+                            *duration_2 / duration_sum * *duration_1 / (1. + (idx % 3) as f32 )
                             // + (f32::cos(idx2 as f32)) * 2.
                         ) / 2.
                         // * ((idx2 % 3) as f32 / 4.)
                         ;
-                        // + f32::abs(f32::sin(idx as f32 * 1.15));
-                        tx2.send(NoteOn(p1)).unwrap();
-                        tx2.send(NoteOn(p2)).unwrap();
-                        let now = Instant::now();
-                        while now.elapsed() < Duration::from_secs_f32(d1) {}
-                        tx2.send(NoteOff(p1));
-                        tx2.send(NoteOff(p2));
-                    });
+                    // + f32::abs(f32::sin(idx as f32 * 1.15));
+                    tx2.send(NoteOn(1, p1)).unwrap();
+                    tx2.send(NoteOn(1, p2)).unwrap();
+                    sync2.wait(d1);
+                    tx2.send(NoteOff(1, p1)).unwrap();
+                    tx2.send(NoteOff(1, p2)).unwrap();
+                });
             });
     });
 
-    let t3 = thread::spawn(move || {
-        tx3.send(NoteOn(37)).unwrap();
-        tx3.send(NoteOn(32)).unwrap();
-        tx3.send(NoteOn(25)).unwrap();
-        loop {}
+    let _t3 = thread::spawn(move || {
+        tx3.send(NoteOn(2, 37)).unwrap();
+        tx3.send(NoteOn(2, 32)).unwrap();
+        tx3.send(NoteOn(2, 25)).unwrap();
+        loop {
+            thread::park();
+        }
     });
 
+    // `--midi-out <device>` plays the notes on external gear too, each thread
+    // on its own channel from `--midi-channel` (1-16) up. The device is
+    // picked as in sound-studies: a USB-MIDI device, or with `--midi-backend
+    // alsa` a raw MIDI port such as `/dev/snd/midiC1D0`.
+    let channel = arg("--midi-channel")
+        .and_then(|c| c.parse::<u8>().ok())
+        .unwrap_or(1);
+    let alsa = arg("--midi-backend").is_some_and(|backend| backend == "alsa");
+    let mut midi_out = arg("--midi-out")
+        .map(|device| midi_out::MidiOut::open(&device, alsa, channel.saturating_sub(1)))
+        .transpose()?;
+    if let Some(out) = midi_out.as_mut() {
+        for (channel, program) in programs.iter().enumerate() {
+            out.send(&Program(channel as u8, *program))?;
+        }
+    }
+
+    let mut status = Instant::now();
     loop {
         if let Ok(msg) = rx.try_recv() {
+            if let Some(Err(e)) = midi_out.as_mut().map(|out| out.send(&msg)) {
+                println!("MIDI output stopped: {e}");
+                midi_out = None;
+            }
             handle.send(msg).unwrap();
         }
 
+        if status.elapsed() >= Duration::from_secs(1) {
+            status = Instant::now();
+            if let Some(levels) = handle.levels() {
+                println!("{levels}");
+            }
+        }
+
         if t1.is_finished() && t2.is_finished() {
             break;
         }
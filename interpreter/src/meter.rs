@@ -0,0 +1,287 @@
+use std::{collections::VecDeque, f64::consts::PI, fmt, path::Path};
+
+use crate::wav::Wav;
+
+/// Loudness is measured in 100ms steps; momentary spans 4, short-term 30
+const STEP_MS: usize = 100;
+const MOMENTARY_STEPS: usize = 4;
+const SHORT_TERM_STEPS: usize = 30;
+const RMS_STEPS: usize = 3;
+const ABSOLUTE_GATE: f64 = -70.;
+const RELATIVE_GATE: f64 = -10.;
+/// Block loudness histogram for the gated integral: 0.1 LU bins from the
+/// absolute gate up to +30 LUFS
+const BIN_LU: f64 = 0.1;
+const BINS: usize = 1000;
+/// Taps per phase of the 4x true-peak interpolator
+const TP_TAPS: usize = 12;
+const TP_FACTOR: usize = 4;
+
+/// Everything in dB (LUFS for loudness); silence reads as `-inf`
+#[derive(Debug, Clone, Copy)]
+pub struct Levels {
+    pub momentary: f32,
+    pub short_term: f32,
+    pub integrated: f32,
+    /// Highest inter-sample peak since the last reset, in dBTP
+    pub true_peak: f32,
+    /// Highest sample since the last reset
+    pub sample_peak: f32,
+    /// Unweighted RMS over the last 300ms
+    pub rms: f32,
+}
+
+impl fmt::Display for Levels {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "M {:6.1}  S {:6.1}  I {:6.1} LUFS  TP {:6.1} dBTP  peak {:6.1}  RMS {:6.1} dB",
+            self.momentary,
+            self.short_term,
+            self.integrated,
+            self.true_peak,
+            self.sample_peak,
+            self.rms
+        )
+    }
+}
+
+/// Direct form I biquad in f64, stable enough for the 38Hz high-pass
+#[derive(Debug, Clone, Default)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+/// BS.1770 K-weighting: a +4dB high shelf then the RLB high-pass, designed
+/// for any sample rate from the analogue prototypes
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    let fs = sample_rate as f64;
+
+    let (f0, gain_db, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+    let k = (PI * f0 / fs).tan();
+    let vh = 10_f64.powf(gain_db / 20.);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1. + k / q + k * k;
+    let shelf = Biquad {
+        b: [
+            (vh + vb * k / q + k * k) / a0,
+            2. * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        a: [2. * (k * k - 1.) / a0, (1. - k / q + k * k) / a0],
+        ..Default::default()
+    };
+
+    let (f0, q) = (38.13547087602444, 0.5003270373238773);
+    let k = (PI * f0 / fs).tan();
+    let a0 = 1. + k / q + k * k;
+    let high_pass = Biquad {
+        b: [1., -2., 1.],
+        a: [2. * (k * k - 1.) / a0, (1. - k / q + k * k) / a0],
+        ..Default::default()
+    };
+
+    [shelf, high_pass]
+}
+
+/// Windowed-sinc phases for 4x oversampling
+fn true_peak_phases() -> [[f32; TP_TAPS]; TP_FACTOR] {
+    let len = TP_TAPS * TP_FACTOR;
+    let centre = (len - 1) as f64 / 2.;
+    std::array::from_fn(|phase| {
+        std::array::from_fn(|tap| {
+            let idx = tap * TP_FACTOR + phase;
+            let t = (idx as f64 - centre) / TP_FACTOR as f64;
+            let sinc = if t.abs() < 1e-9 {
+                1.
+            } else {
+                (PI * t).sin() / (PI * t)
+            };
+            let window = 0.5 - 0.5 * (2. * PI * (idx as f64 + 0.5) / len as f64).cos();
+            (sinc * window) as f32
+        })
+    })
+}
+
+fn power_to_lufs(power: f64) -> f32 {
+    if power > 0. {
+        (-0.691 + 10. * power.log10()) as f32
+    } else {
+        f32::NEG_INFINITY
+    }
+}
+
+/// Histogram bin of a loudness above the absolute gate
+fn bin(lufs: f64) -> usize {
+    (((lufs - ABSOLUTE_GATE) / BIN_LU).max(0.) as usize).min(BINS - 1)
+}
+
+fn to_db(x: f64) -> f32 {
+    if x > 0. {
+        (20. * x.log10()) as f32
+    } else {
+        f32::NEG_INFINITY
+    }
+}
+
+/// Master output meter. Feed it interleaved frames with `process`.
+pub struct Meter {
+    sample_rate: u32,
+    channels: usize,
+    step_len: usize,
+    filters: Vec<[Biquad; 2]>,
+    /// Inter-sample history per channel for the true-peak interpolator
+    history: Vec<VecDeque<f32>>,
+    phases: [[f32; TP_TAPS]; TP_FACTOR],
+    /// Accumulators for the step in progress
+    count: usize,
+    weighted: f64,
+    squared: f64,
+    /// Mean K-weighted and unweighted power of recent steps, newest last
+    steps: VecDeque<(f64, f64)>,
+    /// Blocks above the absolute gate, and their summed power, per bin
+    /// of loudness
+    histogram: Vec<(u64, f64)>,
+    /// Totals over the whole histogram
+    gated: (u64, f64),
+    integrated: f32,
+    true_peak: f32,
+    sample_peak: f32,
+}
+
+impl Meter {
+    pub fn new(sample_rate: u32, channels: usize) -> Self {
+        let channels = channels.max(1);
+        Self {
+            sample_rate,
+            channels,
+            step_len: sample_rate as usize * STEP_MS / 1000,
+            filters: (0..channels).map(|_| k_weighting(sample_rate)).collect(),
+            history: (0..channels)
+                .map(|_| VecDeque::from(vec![0.; TP_TAPS]))
+                .collect(),
+            phases: true_peak_phases(),
+            count: 0,
+            weighted: 0.,
+            squared: 0.,
+            steps: VecDeque::with_capacity(SHORT_TERM_STEPS),
+            histogram: vec![(0, 0.); BINS],
+            gated: (0, 0.),
+            integrated: f32::NEG_INFINITY,
+            true_peak: 0.,
+            sample_peak: 0.,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new(self.sample_rate, self.channels);
+    }
+
+    pub fn process(&mut self, interleaved: &[f32]) {
+        interleaved.chunks(self.channels).for_each(|frame| {
+            frame.iter().enumerate().for_each(|(ch, x)| {
+                let [shelf, high_pass] = &mut self.filters[ch];
+                let y = high_pass.process(shelf.process(*x as f64));
+                self.weighted += y * y;
+                self.squared += (*x as f64) * (*x as f64) / self.channels as f64;
+
+                self.sample_peak = self.sample_peak.max(x.abs());
+                let history = &mut self.history[ch];
+                history.pop_front();
+                history.push_back(*x);
+                let peak = self.phases.iter().fold(0.0_f32, |acc, phase| {
+                    let y: f32 = phase.iter().zip(history.iter()).map(|(h, s)| h * s).sum();
+                    acc.max(y.abs())
+                });
+                self.true_peak = self.true_peak.max(peak);
+            });
+
+            self.count += 1;
+            if self.count == self.step_len {
+                self.finish_step();
+            }
+        });
+    }
+
+    fn finish_step(&mut self) {
+        let n = self.count as f64;
+        if self.steps.len() == SHORT_TERM_STEPS {
+            self.steps.pop_front();
+        }
+        self.steps.push_back((self.weighted / n, self.squared / n));
+        (self.count, self.weighted, self.squared) = (0, 0., 0.);
+
+        if self.steps.len() >= MOMENTARY_STEPS {
+            self.add_block(self.mean_power(MOMENTARY_STEPS));
+        }
+    }
+
+    fn add_block(&mut self, power: f64) {
+        let lufs = power_to_lufs(power) as f64;
+        if lufs <= ABSOLUTE_GATE {
+            return;
+        }
+        let (count, sum) = &mut self.histogram[bin(lufs)];
+        *count += 1;
+        *sum += power;
+        self.gated = (self.gated.0 + 1, self.gated.1 + power);
+        self.integrated = self.integrated();
+    }
+
+    /// Mean K-weighted power of the last `steps` steps
+    fn mean_power(&self, steps: usize) -> f64 {
+        let recent = self.steps.iter().rev().take(steps);
+        let len = recent.len().max(1) as f64;
+        recent.map(|(p, _)| p).sum::<f64>() / len
+    }
+
+    /// Two-stage gated mean of the 400ms blocks (BS.1770-4). The relative
+    /// gate falls on the bin holding the threshold, so it is good to 0.1 LU.
+    fn integrated(&self) -> f32 {
+        let (count, sum) = self.gated;
+        if count == 0 {
+            return f32::NEG_INFINITY;
+        }
+        let threshold = power_to_lufs(sum / count as f64) as f64 + RELATIVE_GATE;
+        let (count, sum) = self.histogram[bin(threshold)..]
+            .iter()
+            .fold((0, 0.), |(n, total), (count, sum)| (n + count, total + sum));
+        power_to_lufs(sum / count.max(1) as f64)
+    }
+
+    pub fn levels(&self) -> Levels {
+        let rms = self.steps.iter().rev().take(RMS_STEPS);
+        let len = rms.len().max(1) as f64;
+        let rms = (rms.map(|(_, sq)| sq).sum::<f64>() / len).sqrt();
+        Levels {
+            momentary: power_to_lufs(self.mean_power(MOMENTARY_STEPS)),
+            short_term: power_to_lufs(self.mean_power(SHORT_TERM_STEPS)),
+            integrated: self.integrated,
+            true_peak: to_db(self.true_peak as f64),
+            sample_peak: to_db(self.sample_peak as f64),
+            rms: to_db(rms),
+        }
+    }
+}
+
+/// Meters a whole file; momentary and short-term are those at the end
+pub fn analyze_wav(path: impl AsRef<Path>) -> Result<Levels, hound::Error> {
+    let wav = Wav::read(path)?;
+    let mut meter = Meter::new(wav.sample_rate, wav.channels.len());
+    meter.process(&wav.interleaved());
+    Ok(meter.levels())
+}
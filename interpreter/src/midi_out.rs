@@ -0,0 +1,132 @@
+use std::{io, sync::Arc};
+
+use rusb::Context;
+
+use crate::{
+    alsa_midi,
+    midi::MidiMessage,
+    midi_device::{self, DeviceSelector},
+    midi_output::{MidiOutput, UsbOutput},
+    msg::Msg,
+};
+
+const VELOCITY: u8 = 100;
+
+/// Plays the sequences' notes on external gear as well, through a USB-MIDI
+/// device or, with `alsa`, a raw MIDI port. Each part goes out on its own
+/// channel, counted from `channel`. Notes still held when it's dropped are
+/// released.
+pub struct MidiOut {
+    output: Box<dyn MidiOutput>,
+    /// 0-15, for part 0
+    channel: u8,
+    /// (channel, note) of the notes on
+    held: Vec<(u8, u8)>,
+}
+
+impl MidiOut {
+    pub fn open(device: &str, alsa: bool, channel: u8) -> io::Result<Self> {
+        let selector: DeviceSelector = device.parse().unwrap_or_else(|e| match e {});
+        Ok(Self {
+            output: open_output(&selector, alsa)?,
+            channel: channel & 0x0f,
+            held: Vec::new(),
+        })
+    }
+
+    fn channel(&self, part: u8) -> u8 {
+        (self.channel + part) & 0x0f
+    }
+
+    /// Sends the note and program messages; the rest only concern the synth
+    pub fn send(&mut self, msg: &Msg) -> io::Result<()> {
+        let message = match *msg {
+            Msg::NoteOn(part, n) => {
+                let channel = self.channel(part);
+                self.held.push((channel, n));
+                MidiMessage::NoteOn {
+                    channel,
+                    note: n & 0x7f,
+                    velocity: VELOCITY,
+                }
+            }
+            Msg::NoteOff(part, n) => {
+                let channel = self.channel(part);
+                self.held.retain(|h| *h != (channel, n));
+                MidiMessage::NoteOff {
+                    channel,
+                    note: n & 0x7f,
+                    velocity: 0,
+                }
+            }
+            Msg::Program(part, program) => MidiMessage::ProgramChange {
+                channel: self.channel(part),
+                program: program & 0x7f,
+            },
+            _ => return Ok(()),
+        };
+        self.output.send(&message)
+    }
+
+    pub fn all_notes_off(&mut self) -> io::Result<()> {
+        let mut channels: Vec<u8> = self.held.iter().map(|(channel, _)| *channel).collect();
+        channels.push(self.channel);
+        channels.sort();
+        channels.dedup();
+        let notes_off = self
+            .held
+            .drain(..)
+            .map(|(channel, n)| MidiMessage::NoteOff {
+                channel,
+                note: n & 0x7f,
+                velocity: 0,
+            });
+        let all_off = channels
+            .into_iter()
+            .map(|channel| MidiMessage::ControlChange {
+                channel,
+                controller: 123,
+                value: 0,
+            });
+        notes_off
+            .chain(all_off)
+            .collect::<Vec<_>>()
+            .iter()
+            .try_for_each(|message| self.output.send(message))
+    }
+}
+
+impl Drop for MidiOut {
+    fn drop(&mut self) {
+        let _ = self.all_notes_off();
+    }
+}
+
+/// Opens the first USB-MIDI device matching `selector`, or with `alsa` the
+/// first raw MIDI port, for output alone
+fn open_output(selector: &DeviceSelector, alsa: bool) -> io::Result<Box<dyn MidiOutput>> {
+    if alsa {
+        return Ok(Box::new(alsa_midi::open_output(selector)?));
+    }
+    let context = Context::new().map_err(io::Error::other)?;
+    let device = midi_device::discover(&context)
+        .map_err(io::Error::other)?
+        .into_iter()
+        .enumerate()
+        .find(|(idx, device)| selector.matches(*idx, device))
+        .map(|(_, device)| device)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no USB-MIDI device matches {selector:?}"),
+            )
+        })?;
+    let connection = Arc::new(device.open().map_err(io::Error::other)?);
+    match UsbOutput::new(&device.name, connection) {
+        Some(output) => Ok(Box::new(output)),
+        None => Err(io::Error::other(format!(
+            "{} has no MIDI OUT endpoint",
+            device.name
+        ))),
+    }
+}
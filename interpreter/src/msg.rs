@@ -1,12 +1,36 @@
+use std::sync::mpsc::Sender;
+
+use crate::{
+    meter::Levels,
+    smf::{SmfPlayer, Transport},
+    synth::Key,
+};
+
 #[repr(u8)]
 pub enum Msg {
-    NoteOn(u8) = 0,
-    NoteOff(u8),
+    /// Channel, note
+    NoteOn(u8, u8) = 0,
+    NoteOff(u8, u8),
+    /// Channel, General MIDI program
+    Program(u8, u8),
     Play,
     Stop,
     SetVolume(f32),
-    Sample(f32),
-    Volume(f32),
-    GetVolume,
+    /// Node index in the chain, parameter index, value
+    SetParam(usize, usize, f32),
+    SetKey(Key),
+    /// Track the key of the notes played
+    FollowKey(bool),
+    /// Snap incoming notes to the current key
+    SnapToKey(bool),
+    /// Replies with the master output levels
+    GetLevels(Sender<Levels>),
+    /// Plays a MIDI file from the synth thread, timed to the sample
+    Sequence(Box<SmfPlayer>),
+    Transport(Transport),
+    /// BPM of an external clock, for sequences and tempo-synced nodes
+    Tempo(f32),
+    /// Song position in quarter notes
+    Locate(f64),
     Disconnect,
 }
@@ -0,0 +1,126 @@
+use std::f32::consts::PI;
+
+use crate::track::{AudioNode, Param};
+
+pub struct Gain {
+    /// Gain, 0-4
+    params: [Param; 1],
+}
+
+impl Gain {
+    pub fn new(gain: f32) -> Self {
+        Self {
+            params: [Param::new(gain, 0.0, 4.0)],
+        }
+    }
+}
+
+impl AudioNode for Gain {
+    fn process(&mut self, block: &mut [f32]) {
+        let gain = self.params[0].value;
+        block.iter_mut().for_each(|s| *s *= gain);
+    }
+
+    fn set_param(&mut self, idx: usize, value: f32) {
+        if let Some(p) = self.params.get_mut(idx) {
+            p.set(value);
+        }
+    }
+}
+
+/// One-pole low-pass filter
+pub struct LowPass {
+    /// Cutoff in Hz
+    params: [Param; 1],
+    sample_rate: u32,
+    coeff: f32,
+    z: f32,
+}
+
+impl LowPass {
+    pub fn new(sample_rate: u32, cutoff: f32) -> Self {
+        let nyquist = sample_rate as f32 / 2.;
+        let mut lp = Self {
+            params: [Param::new(cutoff, 10.0, nyquist)],
+            sample_rate,
+            coeff: 0.,
+            z: 0.,
+        };
+        lp.update();
+        lp
+    }
+
+    fn update(&mut self) {
+        self.coeff = f32::exp(-2. * PI * self.params[0].value / self.sample_rate as f32);
+    }
+}
+
+impl AudioNode for LowPass {
+    fn process(&mut self, block: &mut [f32]) {
+        block.iter_mut().for_each(|s| {
+            self.z = *s + self.coeff * (self.z - *s);
+            *s = self.z;
+        });
+    }
+
+    fn set_param(&mut self, idx: usize, value: f32) {
+        if let Some(p) = self.params.get_mut(idx) {
+            p.set(value);
+            self.update();
+        }
+    }
+
+    fn reset(&mut self) {
+        self.z = 0.;
+    }
+}
+
+/// Amplitude modulation locked to the tempo, one cycle per `division` beats
+pub struct Tremolo {
+    /// Division in beats, depth 0-1
+    params: [Param; 2],
+    sample_rate: u32,
+    bpm: f32,
+    /// Cycles, 0-1
+    phase: f32,
+}
+
+impl Tremolo {
+    pub fn new(sample_rate: u32, bpm: f32, division: f32, depth: f32) -> Self {
+        Self {
+            params: [
+                Param::new(division, 0.125, 16.0),
+                Param::new(depth, 0.0, 1.0),
+            ],
+            sample_rate,
+            bpm,
+            phase: 0.,
+        }
+    }
+}
+
+impl AudioNode for Tremolo {
+    fn process(&mut self, block: &mut [f32]) {
+        let [division, depth] = [self.params[0].value, self.params[1].value];
+        let step = self.bpm / 60. / division / self.sample_rate as f32;
+        block.iter_mut().for_each(|s| {
+            let lfo = 0.5 + 0.5 * f32::cos(2. * PI * self.phase);
+            *s *= 1. - depth * lfo;
+            self.phase = (self.phase + step).fract();
+        });
+    }
+
+    fn set_param(&mut self, idx: usize, value: f32) {
+        if let Some(p) = self.params.get_mut(idx) {
+            p.set(value);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.phase = 0.;
+    }
+
+    fn set_tempo(&mut self, bpm: f32) {
+        self.bpm = bpm.max(1.);
+    }
+}
@@ -0,0 +1,123 @@
+use bit_set::BitSet;
+
+use crate::patch::Patch;
+use crate::utils::{delta, note};
+
+/// Note indices, up to the top of the synth's range
+const NOTES: usize = 154;
+
+/// The synth's instrument on one MIDI channel: a patch and the notes it
+/// holds. The engine is mono, so parts have no pan.
+#[derive(Debug, Clone)]
+pub struct Part {
+    note_mask: BitSet,
+    /// Per note, per harmonic
+    deltas: Vec<Vec<f32>>,
+    levels: Vec<Vec<f32>>,
+    decays: Vec<Vec<f32>>,
+    envelopes: Vec<Vec<f32>>,
+    phases: Vec<Vec<f32>>,
+    /// Frequency ratio of the pitch bend
+    bend: f32,
+    /// Channel volume from CC 7
+    gain: f32,
+    sample_rate: u32,
+}
+
+impl Part {
+    pub fn new(sample_rate: u32) -> Self {
+        let mut part = Self {
+            note_mask: BitSet::new(),
+            deltas: Vec::new(),
+            levels: Vec::new(),
+            decays: Vec::new(),
+            envelopes: Vec::new(),
+            phases: Vec::new(),
+            bend: 1.,
+            gain: 1.,
+            sample_rate,
+        };
+        part.set_patch(&Patch::sine());
+        part
+    }
+
+    /// Sines above Nyquist are left out
+    pub fn set_patch(&mut self, patch: &Patch) {
+        let nyquist = self.sample_rate as f32 / 2.;
+        let harmonics = |n: usize| {
+            patch
+                .harmonics
+                .iter()
+                .filter(move |h| n > 0 && note(n as f32) * h.ratio < nyquist)
+        };
+        self.deltas = (0..NOTES)
+            .map(|n| {
+                harmonics(n)
+                    .map(|h| delta(note(n as f32) * h.ratio, self.sample_rate))
+                    .collect()
+            })
+            .collect();
+        self.levels = (0..NOTES)
+            .map(|n| harmonics(n).map(|h| h.amplitude).collect())
+            .collect();
+        self.decays = (0..NOTES)
+            .map(|n| {
+                harmonics(n)
+                    .map(|h| 10.0_f32.powf(h.decay / 20. / self.sample_rate as f32))
+                    .collect()
+            })
+            .collect();
+        self.envelopes = self.levels.clone();
+        self.phases = self.deltas.iter().map(|d| vec![0.; d.len()]).collect();
+    }
+
+    /// Switches to the patch of a General MIDI program
+    pub fn set_program(&mut self, program: u8) {
+        self.note_mask.clear();
+        self.set_patch(&Patch::program(program));
+    }
+
+    pub fn note_on(&mut self, n: u8) {
+        let idx = n as usize % NOTES;
+        if self.note_mask.insert(idx) {
+            self.envelopes[idx].clone_from(&self.levels[idx]);
+        }
+    }
+
+    pub fn note_off(&mut self, n: u8) {
+        self.note_mask.remove(n as usize % NOTES);
+    }
+
+    pub fn all_notes_off(&mut self) {
+        self.note_mask.clear();
+    }
+
+    pub fn set_pitch_bend(&mut self, semitones: f32) {
+        self.bend = 2.0_f32.powf(semitones / 12.);
+    }
+
+    pub fn set_gain(&mut self, gain: f32) {
+        self.gain = gain;
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.note_mask.is_empty()
+    }
+
+    pub fn next(&mut self) -> f32 {
+        let next = self.note_mask.iter().fold(0.0, |acc, n| {
+            let harmonics = self.phases[n]
+                .iter_mut()
+                .zip(&self.deltas[n])
+                .zip(self.envelopes[n].iter_mut().zip(&self.decays[n]));
+            acc + harmonics.fold(0.0, |acc, ((phase, delta), (env, decay))| {
+                *phase = (*phase + delta * self.bend) % std::f32::consts::TAU;
+                let s = f32::sin(*phase) * *env;
+                // flush to zero before the envelope goes denormal
+                *env = if *env > 1e-6 { *env * decay } else { 0. };
+                acc + s
+            })
+        });
+        next * self.gain
+    }
+}
@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 
 use crate::{
+    meter::Meter,
     msg::Msg::{self, *},
     utils::{f32_to_u32, u32_to_f32},
 };
@@ -8,22 +9,14 @@ use cpal::{
     Host, Stream, SupportedStreamConfig,
     traits::{DeviceTrait, HostTrait, StreamTrait},
 };
-use ringbuf::{
-    SharedRb, StaticRb,
-    storage::Owning,
-    traits::{Consumer, Producer, Split},
-};
+use ringbuf::{SharedRb, storage::Owning, traits::Consumer};
 use std::{
     mem::MaybeUninit,
-    sync::{
-        Arc, LazyLock, Mutex, RwLock,
-        atomic::AtomicU32,
-        mpsc::{Receiver, Sender},
-    },
+    sync::{Arc, LazyLock, Mutex, atomic::AtomicU32, mpsc::Receiver},
     thread::{self, JoinHandle},
 };
 
-static HOST: LazyLock<Host> = std::sync::LazyLock::new(|| cpal::default_host());
+static HOST: LazyLock<Host> = std::sync::LazyLock::new(cpal::default_host);
 pub static OUTPUT_DEVICE: LazyLock<cpal::Device> =
     std::sync::LazyLock::new(|| HOST.default_output_device().unwrap());
 pub static STREAM_CONFIG: LazyLock<SupportedStreamConfig> =
@@ -47,19 +40,26 @@ impl Player {
         let volume = Arc::new(AtomicU32::new(f32_to_u32(0.2)));
         let volume = volume.clone();
         let new_volume = volume.clone();
+        let config = OUTPUT_DEVICE.default_output_config().unwrap();
+        let meter = Arc::new(Mutex::new(Meter::new(
+            config.sample_rate(),
+            config.channels() as usize,
+        )));
+        let output_meter = meter.clone();
         self.ostream = Some(
             OUTPUT_DEVICE
                 .build_output_stream::<f32, _, _>(
-                    &OUTPUT_DEVICE.default_output_config().unwrap().config(),
+                    &config.config(),
                     move |data, _cb_info| {
                         let vol = u32_to_f32(volume.load(std::sync::atomic::Ordering::Relaxed));
                         let mut next = 0.0;
-                        data.into_iter().enumerate().for_each(|(idx, s)| {
+                        data.iter_mut().enumerate().for_each(|(idx, s)| {
                             if idx % 2 == 0 {
                                 next = cons.try_pop().unwrap_or(0.0);
                             }
                             *s = next * vol;
                         });
+                        output_meter.lock().unwrap().process(data);
                     },
                     |e| {
                         println!("{e}");
@@ -72,11 +72,19 @@ impl Player {
         thread::spawn(move || {
             for msg in rx.into_iter() {
                 match msg {
-                    Play => self.play(),
+                    // integrated loudness starts over with each take
+                    Play => {
+                        meter.lock().unwrap().reset();
+                        self.play()
+                    }
                     Stop => self.stop(),
                     SetVolume(val) => {
                         new_volume.store(f32_to_u32(val), std::sync::atomic::Ordering::Relaxed)
                     }
+                    GetLevels(tx) => {
+                        let levels = meter.lock().unwrap().levels();
+                        let _ = tx.send(levels);
+                    }
                     Disconnect => break,
                     _ => continue,
                 }
@@ -1,23 +1,19 @@
 use crate::Player;
+use crate::chroma::NoteChroma;
+use crate::meter::Levels;
+use crate::midi::BEND_RANGE;
 use crate::msg::{Msg, Msg::*};
-use crate::utils::*;
+use crate::part::Part;
+use crate::smf::{SmfPlayer, Transport};
+use crate::track::{AudioNode, Chain};
 use std::any::Any;
-use std::cell::LazyCell;
-use std::sync::Arc;
-use std::sync::mpsc::{Receiver, SendError, Sender};
+use std::sync::mpsc::{SendError, Sender};
 use std::thread;
-use std::{
-    sync::{LazyLock, atomic::AtomicU32, mpsc::channel},
-    thread::JoinHandle,
-};
+use std::{sync::mpsc::channel, thread::JoinHandle};
 
 use crate::player::STREAM_CONFIG;
-use bit_set::BitSet;
 use macros::keys;
 
-static DELTA: LazyLock<Vec<Vec<f32>>> =
-    std::sync::LazyLock::new(|| notes(STREAM_CONFIG.sample_rate()));
-
 keys!();
 use Key::*;
 use ringbuf::StaticRb;
@@ -31,6 +27,17 @@ impl EngineHandle {
         self.1.send(msg)
     }
 
+    pub fn sender(&self) -> Sender<Msg> {
+        self.1.clone()
+    }
+
+    /// Current master output levels, `None` once the engine has stopped
+    pub fn levels(&self) -> Option<Levels> {
+        let (tx, rx) = channel();
+        self.send(GetLevels(tx)).ok()?;
+        rx.recv().ok()
+    }
+
     pub fn join(self) -> Result<(), Box<dyn Any + Send + 'static>> {
         self.0.join()
     }
@@ -38,32 +45,100 @@ impl EngineHandle {
 
 #[derive(Debug)]
 pub struct Synth {
-    note_mask: BitSet,
-    volume: f32,
-    phases: Vec<Vec<f32>>,
+    /// One per MIDI channel
+    parts: Vec<Part>,
     key: Key,
+    chroma: NoteChroma,
+    /// Re-estimate `key` from the notes played
+    follow_key: bool,
+    /// Move incoming notes onto the scale of `key`
+    snap: bool,
+    /// Note actually sounding for each incoming note, per part
+    sounding: [[u8; 128]; PARTS],
+    sequencer: Option<SmfPlayer>,
+    /// BPM of the external clock, once there is one
+    tempo: Option<f32>,
 }
 
+/// One part per MIDI channel
+pub const PARTS: usize = 16;
+
 impl Synth {
-    pub fn volume(&self) -> f32 {
-        println!("volume: {}", self.volume);
-        self.volume
+    pub fn set_key(&mut self, key: Key) {
+        self.key = key;
+    }
+
+    /// Plays a note on the part of `channel`, 0-15
+    pub fn note_on(&mut self, channel: u8, n: u8) {
+        println!("note {n} on");
+        self.chroma.note_on(n);
+        if self.follow_key
+            && let Some(key) = self.chroma.key()
+            && key != self.key
+        {
+            println!("key: {key:?}");
+            self.key = key;
+        }
+        let sounding = if self.snap { self.key.snap(n) } else { n };
+        let channel = channel as usize % PARTS;
+        self.sounding[channel][n as usize % 128] = sounding;
+        self.parts[channel].note_on(sounding);
     }
 
-    pub fn set_volume(&mut self, vol: f32) {
-        self.volume = vol;
+    pub fn note_off(&mut self, channel: u8, n: u8) {
+        let channel = channel as usize % PARTS;
+        self.parts[channel].note_off(self.sounding[channel][n as usize % 128]);
     }
 
-    pub fn note_on(&mut self, n: u8) {
-        println!("note {n} on");
-        self.note_mask.insert(n as usize);
+    pub fn set_program(&mut self, channel: u8, program: u8) {
+        let part = &mut self.parts[channel as usize % PARTS];
+        part.set_program(program);
+        println!("part {}: program {program}", channel % 16 + 1);
+    }
+
+    /// Plays the sequence at `tempo` rather than its own; files timed in
+    /// SMPTE frames keep theirs
+    fn sync_sequencer(&mut self) {
+        let (Some(sequencer), Some(tempo)) = (self.sequencer.as_mut(), self.tempo) else {
+            return;
+        };
+        if let Some(bpm) = sequencer.sequence().tempo.bpm(0) {
+            sequencer.apply(Transport::TempoScale(tempo as f64 / bpm));
+        }
     }
 
-    pub fn note_off(&mut self, n: u8) {
-        self.note_mask.remove(n as usize);
+    /// Moves the sequence to a song position in quarter notes
+    fn locate(&mut self, quarters: f64) {
+        if let Some(sequencer) = self.sequencer.as_mut()
+            && let Some(seconds) = sequencer.sequence().tempo.quarters_seconds(quarters)
+        {
+            sequencer.apply(Transport::Seek(seconds));
+        }
     }
 
-    pub fn connect(mut self, player: Player) -> EngineHandle {
+    /// Plays a channel message from a sequence on the part of its channel:
+    /// notes, program change, volume (CC 7), all notes off (CC 120 and 123)
+    /// and pitch bend
+    fn midi(&mut self, bytes: &[u8]) {
+        let data = |idx: usize| bytes.get(idx).copied().unwrap_or(0);
+        let channel = bytes[0] & 0x0f;
+        let part = &mut self.parts[channel as usize];
+        match (bytes[0] & 0xf0, data(1), data(2)) {
+            (0x90, n, velocity) if velocity > 0 => self.note_on(channel, n),
+            (0x80 | 0x90, n, _) => self.note_off(channel, n),
+            (0xc0, program, _) => self.set_program(channel, program),
+            (0xb0, 7, value) => part.set_gain(value as f32 / 127.),
+            (0xb0, 120 | 123, _) => part.all_notes_off(),
+            (0xe0, lsb, msb) => {
+                let value = ((msb as i16) << 7 | lsb as i16) - 8192;
+                part.set_pitch_bend(value as f32 / 8192. * BEND_RANGE)
+            }
+            _ => (),
+        }
+    }
+
+    /// Runs the synth on its own thread, feeding the player through `chain`
+    pub fn connect(mut self, player: Player, mut chain: Chain) -> EngineHandle {
         let (player_tx, player_rx) = channel();
         let (synth_tx, synth_rx) = channel();
         let buf = StaticRb::<f32, 8192>::default();
@@ -72,13 +147,38 @@ impl Synth {
         player.connect(cons, player_rx);
 
         let handle = thread::spawn(move || {
+            let mut block = [0.0_f32; 1024];
             loop {
                 if let Ok(msg) = synth_rx.try_recv() {
                     match msg {
-                        NoteOff(n) => self.note_off(n),
-                        NoteOn(n) => self.note_on(n),
+                        NoteOff(channel, n) => self.note_off(channel, n),
+                        NoteOn(channel, n) => self.note_on(channel, n),
+                        Program(channel, program) => self.set_program(channel, program),
                         Play => player_tx.send(Play).unwrap(),
                         Stop => player_tx.send(Stop).unwrap(),
+                        GetLevels(tx) => player_tx.send(GetLevels(tx)).unwrap(),
+                        SetParam(node, idx, val) => chain.set_node_param(node, idx, val),
+                        SetKey(key) => self.set_key(key),
+                        FollowKey(on) => self.follow_key = on,
+                        SnapToKey(on) => self.snap = on,
+                        Sequence(player) => {
+                            self.sequencer = Some(*player);
+                            self.sync_sequencer();
+                        }
+                        Transport(transport) => {
+                            if let Some(sequencer) = self.sequencer.as_mut() {
+                                sequencer.apply(transport)
+                            }
+                        }
+                        Tempo(bpm) => {
+                            self.tempo = Some(bpm);
+                            self.sync_sequencer();
+                            chain.set_tempo(bpm);
+                        }
+                        Locate(quarters) => {
+                            self.locate(quarters);
+                            chain.reset();
+                        }
                         Disconnect => {
                             player_tx.send(Disconnect).unwrap();
                             break;
@@ -86,11 +186,22 @@ impl Synth {
                         _ => (),
                     }
                 }
-                // if prod.vacant_len() < 1024 {
-                //     thread::yield_now();
-                // } else {
-                prod.push_iter(self.by_ref().take(1024));
-                // }
+                let len = prod.vacant_len().min(block.len());
+                if len == 0 {
+                    thread::yield_now();
+                    continue;
+                }
+                let block = &mut block[..len];
+                let mut sequencer = self.sequencer.take();
+                block.iter_mut().for_each(|s| {
+                    if let Some(sequencer) = sequencer.as_mut() {
+                        sequencer.tick(|bytes| self.midi(bytes));
+                    }
+                    *s = self.next().unwrap_or(0.);
+                });
+                self.sequencer = sequencer;
+                chain.process(block);
+                prod.push_slice(block);
             }
         });
 
@@ -100,20 +211,16 @@ impl Synth {
 
 impl Default for Synth {
     fn default() -> Self {
-        let note_mask = BitSet::new();
-        let volume: f32 = 0.0;
-        let mut phases = Vec::<Vec<f32>>::new();
-        phases.resize_with(154, || {
-            let mut v = Vec::<f32>::new();
-            v.resize(1, 0.0);
-            v
-        });
         let key = CMaj;
         Self {
-            note_mask,
-            volume,
-            phases,
+            parts: vec![Part::new(STREAM_CONFIG.sample_rate()); PARTS],
             key,
+            chroma: NoteChroma::default(),
+            follow_key: false,
+            snap: false,
+            sounding: [std::array::from_fn(|n| n as u8); PARTS],
+            sequencer: None,
+            tempo: None,
         }
     }
 }
@@ -122,25 +229,12 @@ impl Iterator for Synth {
     type Item = f32;
 
     fn next(&mut self) -> Option<Self::Item> {
-        // println!("Synth::next()");
-        // let mut phases = std::mem::take(&mut self.phases);
-
-        let next = self.note_mask.iter().fold(0.0, |acc, n| {
-            // println!("n: {n}");
-            let next = self.phases[n]
-                .iter_mut()
-                .enumerate()
-                .fold(0.0, |acc, (idx, m)| {
-                    // println!("DELTA[{n}][{idx}]: {}", DELTA[n][idx]);
-                    *m += DELTA[n][idx];
-                    acc + f32::sin(*m)
-                });
-
-            acc + next
-        });
-
-        // println!("next: {next}");
-        // self.phases = phases;
+        let next = self
+            .parts
+            .iter_mut()
+            .filter(|part| !part.is_idle())
+            .map(|part| part.next())
+            .sum();
         Some(next)
     }
 }
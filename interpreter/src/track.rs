@@ -1,36 +1,82 @@
-use std::any::Any;
+#[derive(Debug, Clone)]
+pub struct Param {
+    pub value: f32,
+    pub min: f32,
+    pub max: f32,
+}
+
+impl Param {
+    pub fn new(value: f32, min: f32, max: f32) -> Self {
+        Self { value, min, max }
+    }
+
+    pub fn set(&mut self, value: f32) {
+        self.value = value.clamp(self.min, self.max);
+    }
+}
 
-use ndarray::Array1;
+/// A block-processing stage between the synth and the player
+pub trait AudioNode: Send {
+    fn process(&mut self, block: &mut [f32]);
 
-pub type Transformer<T> = fn(data: T) -> T;
+    fn set_param(&mut self, _idx: usize, _value: f32) {}
 
-pub struct Seq<T>
-where
-    T:,
-{
-    layers: Vec<Transformer<T>>,
+    /// Clears any internal state, e.g. filter memory or delay lines
+    fn reset(&mut self) {}
+
+    /// For nodes timed in beats
+    fn set_tempo(&mut self, _bpm: f32) {}
+}
+
+pub struct Seq {
+    layers: Vec<Box<dyn AudioNode>>,
 }
 
-impl<T> Seq<T>
-where
-    T: Iterator,
-{
+impl Seq {
     pub fn new() -> Self {
         Self {
-            layers: Vec::<Transformer<T>>::new(),
+            layers: Vec::<Box<dyn AudioNode>>::new(),
         }
     }
 
-    pub fn layer(mut self, l: Transformer<T>) -> Self {
-        self.layers.push(l);
-        self
+    pub fn add_layer(&mut self, l: impl AudioNode + 'static) {
+        self.layers.push(Box::new(l));
+    }
+
+    pub fn build(self) -> Chain {
+        Chain { nodes: self.layers }
+    }
+}
+
+impl Default for Seq {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Nodes run in the order they were layered
+pub struct Chain {
+    nodes: Vec<Box<dyn AudioNode>>,
+}
+
+impl Chain {
+    pub fn set_node_param(&mut self, node: usize, idx: usize, value: f32) {
+        if let Some(node) = self.nodes.get_mut(node) {
+            node.set_param(idx, value);
+        }
+    }
+}
+
+impl AudioNode for Chain {
+    fn process(&mut self, block: &mut [f32]) {
+        self.nodes.iter_mut().for_each(|node| node.process(block));
     }
 
-    pub fn add_layer(&mut self, l: Transformer<T>) {
-        self.layers.push(l);
+    fn reset(&mut self) {
+        self.nodes.iter_mut().for_each(|node| node.reset());
     }
 
-    pub fn build(&self) {
-        todo!();
+    fn set_tempo(&mut self, bpm: f32) {
+        self.nodes.iter_mut().for_each(|node| node.set_tempo(bpm));
     }
 }
@@ -1,4 +1,5 @@
 use core::f32;
+use std::{fs, path::Path};
 
 const PI: f32 = f32::consts::PI;
 
@@ -10,23 +11,61 @@ pub const fn delta(freq: f32, sample_rate: u32) -> f32 {
     2. * PI * freq / sample_rate as f32
 }
 
-pub fn notes(sample_rate: u32) -> Vec<Vec<f32>> {
-    println!("synth::notes({sample_rate})");
-    let mut delta_angles = Vec::<Vec<f32>>::new();
-    delta_angles.resize(154, Vec::<f32>::new());
-    for n in 1..=153 {
-        let idx = n as usize;
-        let freq = note(n as f32);
-        let freq_delta_angle = delta(freq, sample_rate);
-        delta_angles[idx].extend([freq_delta_angle]);
-    }
-
-    delta_angles
-}
-
 pub fn f32_to_u32(n: f32) -> u32 {
     n.to_bits()
 }
 pub fn u32_to_f32(n: u32) -> f32 {
     f32::from_bits(n)
 }
+
+/// Gaps between the timestamps in a file written by `sound-studies onsets`,
+/// one time in seconds per line
+pub fn load_durations(path: impl AsRef<Path>) -> Result<Vec<f32>, Box<dyn std::error::Error>> {
+    let times = fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(str::parse)
+        .collect::<Result<Vec<f32>, _>>()?;
+    let durations: Vec<f32> = times.windows(2).map(|w| w[1] - w[0]).collect();
+    if durations.iter().any(|d| *d <= 0.) || durations.is_empty() {
+        return Err("timestamps must contain at least two increasing times".into());
+    }
+    Ok(durations)
+}
+
+/// `PITCHES` and `DURATIONS` lines of a note list, as written by
+/// `sound-studies transcribe out.txt`
+pub fn load_note_list(
+    path: impl AsRef<Path>,
+) -> Result<(Vec<u8>, Vec<f32>), Box<dyn std::error::Error>> {
+    let text = fs::read_to_string(path)?;
+    let line = |name: &str| {
+        text.lines()
+            .find_map(|l| l.trim().strip_prefix(name))
+            .ok_or(format!("note list has no {name} line"))
+    };
+    let pitches = line("PITCHES")?
+        .split_whitespace()
+        .map(str::parse)
+        .collect::<Result<Vec<u8>, _>>()?;
+    let durations = line("DURATIONS")?
+        .split_whitespace()
+        .map(str::parse)
+        .collect::<Result<Vec<f32>, _>>()?;
+    if pitches.is_empty() || durations.is_empty() || durations.iter().any(|d| *d <= 0.) {
+        return Err("note list needs at least one note and positive durations".into());
+    }
+    if pitches.len() != durations.len() {
+        return Err(format!(
+            "note list has {} pitches but {} durations",
+            pitches.len(),
+            durations.len()
+        )
+        .into());
+    }
+    if let Some(p) = pitches.iter().find(|p| **p > 127) {
+        return Err(format!("pitch {p} is not a MIDI note").into());
+    }
+    Ok((pitches, durations))
+}
@@ -1,13 +1,6 @@
-use std::cell::LazyCell;
-
-use proc_macro::{Ident, Punct, TokenStream, TokenTree};
+use proc_macro::TokenStream;
 use quote::{format_ident, quote};
 
-#[inline(always)]
-fn note(n: f32) -> f32 {
-    440. * 2.0_f32.powf((n - 49.) / 12.)
-}
-
 // #[proc_macro]
 // pub fn n(ts: TokenStream) -> TokenStream {
 //     let mut out = TokenStream::new();
@@ -48,6 +41,8 @@ fn note(n: f32) -> f32 {
 //     out
 // }
 
+// unfinished: the scales and key names aren't emitted yet
+#[allow(unused_variables, clippy::useless_vec)]
 #[proc_macro]
 pub fn midi_notes(_: TokenStream) -> TokenStream {
     let major_scale = vec![2, 2, 1, 2, 2, 2, 1];
@@ -122,22 +117,95 @@ pub fn midi_notes(_: TokenStream) -> TokenStream {
 
 #[proc_macro]
 pub fn keys(_ts: proc_macro::TokenStream) -> proc_macro::TokenStream {
-    let variants: Vec<_> = [
-        "C", "CSh", "Db", "D", "DSh", "E", "F", "FSh", "Gb", "G", "Ab", "A", "Bb",
-    ]
-    .iter()
-    // .map(|n| n.to_string())
-    .flat_map(|n| {
-        let n = *n;
-        [format_ident!("{n}Maj"), format_ident!("{n}Min")]
-    })
-    .collect();
+    // spelling and pitch class of each tonic
+    let tonics = [
+        ("C", 0_u8),
+        ("CSh", 1),
+        ("Db", 1),
+        ("D", 2),
+        ("DSh", 3),
+        ("E", 4),
+        ("F", 5),
+        ("FSh", 6),
+        ("Gb", 6),
+        ("G", 7),
+        ("Ab", 8),
+        ("A", 9),
+        ("Bb", 10),
+        ("B", 11),
+    ];
+    let variants: Vec<_> = tonics
+        .iter()
+        // .map(|n| n.to_string())
+        .flat_map(|(n, _)| [format_ident!("{n}Maj"), format_ident!("{n}Min")])
+        .collect();
+    let tonic_arms = tonics.iter().map(|(n, pc)| {
+        let (maj, min) = (format_ident!("{n}Maj"), format_ident!("{n}Min"));
+        quote! { Key::#maj | Key::#min => #pc }
+    });
+    let minor_variants = tonics.iter().map(|(n, _)| format_ident!("{n}Min"));
+
+    // conventional spellings when there is a choice
+    let major_names = [
+        "C", "Db", "D", "DSh", "E", "F", "FSh", "G", "Ab", "A", "Bb", "B",
+    ];
+    let minor_names = [
+        "C", "CSh", "D", "DSh", "E", "F", "FSh", "G", "Ab", "A", "Bb", "B",
+    ];
+    let from_arms = (0..12_u8).map(|pc| {
+        let maj = format_ident!("{}Maj", major_names[pc as usize]);
+        let min = format_ident!("{}Min", minor_names[pc as usize]);
+        quote! {
+            (#pc, false) => Key::#maj,
+            (#pc, true) => Key::#min,
+        }
+    });
 
     quote! {
-      #[derive(Clone, Debug)]
-      enum Key {
+      #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+      pub enum Key {
         #(#variants),*
       }
+
+      impl Key {
+        /// Key on pitch class `pc` (0 is C), spelled the usual way
+        pub fn from_tonic(pc: u8, minor: bool) -> Key {
+          match (pc % 12, minor) {
+            #(#from_arms)*
+            _ => unreachable!(),
+          }
+        }
+
+        /// Pitch class of the tonic, 0 is C
+        pub fn tonic(&self) -> u8 {
+          match self {
+            #(#tonic_arms),*
+          }
+        }
+
+        pub fn is_minor(&self) -> bool {
+          matches!(self, #(Key::#minor_variants)|*)
+        }
+
+        /// Pitch classes of the major or natural minor scale
+        pub fn scale(&self) -> [u8; 7] {
+          let steps: [u8; 7] = if self.is_minor() {
+            [0, 2, 3, 5, 7, 8, 10]
+          } else {
+            [0, 2, 4, 5, 7, 9, 11]
+          };
+          steps.map(|s| (s + self.tonic()) % 12)
+        }
+
+        /// Moves MIDI note `n` down to the nearest note of the scale
+        pub fn snap(&self, n: u8) -> u8 {
+          let scale = self.scale();
+          (0..12)
+            .map(|d| n.saturating_sub(d))
+            .find(|m| scale.contains(&(m % 12)))
+            .unwrap_or(n)
+        }
+      }
     }
     .into()
 }
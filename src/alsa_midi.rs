@@ -0,0 +1,165 @@
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    os::fd::AsRawFd,
+    path::PathBuf,
+    time::Duration,
+};
+
+use crate::{
+    midi::{MidiMessage, MidiParser},
+    midi_device::DeviceSelector,
+    midi_input::MidiInput,
+    midi_output::MidiOutput,
+    usb_midi::UsbMidiEvent,
+};
+
+/// An ALSA raw MIDI port, `/dev/snd/midiC<card>D<device>`. Unlike reading
+/// the USB endpoint it leaves the kernel driver attached, and covers every
+/// kind of MIDI interface, including `snd-virmidi` loopbacks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawMidiPort {
+    pub path: PathBuf,
+    pub card: u32,
+    pub device: u32,
+    pub name: String,
+}
+
+impl fmt::Display for RawMidiPort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.name, self.path.display())
+    }
+}
+
+/// Card and device numbers of a `midiC<card>D<device>` file name
+fn port_numbers(file_name: &str) -> Option<(u32, u32)> {
+    let (card, device) = file_name.strip_prefix("midiC")?.split_once('D')?;
+    Some((card.parse().ok()?, device.parse().ok()?))
+}
+
+/// Every raw MIDI port, by card and device
+pub fn ports() -> io::Result<Vec<RawMidiPort>> {
+    let mut ports: Vec<RawMidiPort> = fs::read_dir("/dev/snd")?
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let (card, device) = port_numbers(path.file_name()?.to_str()?)?;
+            // the first line of the proc entry names the port
+            let name = fs::read_to_string(format!("/proc/asound/card{card}/midi{device}"))
+                .ok()
+                .and_then(|info| info.lines().next().map(str::to_string))
+                .unwrap_or_else(|| format!("card {card} device {device}"));
+            Some(RawMidiPort {
+                path,
+                card,
+                device,
+                name,
+            })
+        })
+        .collect();
+    ports.sort_by_key(|port| (port.card, port.device));
+    Ok(ports)
+}
+
+/// Reads a raw MIDI port, a plain byte stream, through a `MidiParser`
+pub struct RawMidiInput {
+    name: String,
+    file: File,
+    parser: MidiParser,
+}
+
+impl RawMidiInput {
+    pub fn open(port: &RawMidiPort) -> io::Result<Self> {
+        Ok(Self {
+            name: port.name.clone(),
+            file: File::open(&port.path)?,
+            parser: MidiParser::new(),
+        })
+    }
+}
+
+impl MidiInput for RawMidiInput {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn read(&mut self, timeout: Duration) -> io::Result<Vec<UsbMidiEvent>> {
+        let mut fd = libc::pollfd {
+            fd: self.file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // a signal interrupting the wait isn't the port going away
+        let ready = loop {
+            let ready = unsafe { libc::poll(&mut fd, 1, timeout.as_millis() as libc::c_int) };
+            if ready >= 0 {
+                break ready;
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        };
+        if ready == 0 {
+            return Ok(Vec::new());
+        }
+        let mut buf = [0_u8; 256];
+        let size = match self.file.read(&mut buf) {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => return Ok(Vec::new()),
+            size => size?,
+        };
+        if size == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(self
+            .parser
+            .parse(&buf[..size])
+            .into_iter()
+            .map(|message| UsbMidiEvent { cable: 0, message })
+            .collect())
+    }
+}
+
+/// Writes to a raw MIDI port
+#[derive(Debug)]
+pub struct RawMidiOutput {
+    name: String,
+    file: File,
+}
+
+impl RawMidiOutput {
+    pub fn open(port: &RawMidiPort) -> io::Result<Self> {
+        Ok(Self {
+            name: port.name.clone(),
+            file: OpenOptions::new().write(true).open(&port.path)?,
+        })
+    }
+}
+
+/// Opens the first port matching `selector` for writing
+pub fn open_output(selector: &DeviceSelector) -> io::Result<RawMidiOutput> {
+    let ports = ports()?;
+    let port = ports
+        .iter()
+        .enumerate()
+        .find(|(idx, port)| selector.matches_port(*idx, &port.to_string()))
+        .map(|(_, port)| port)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no ALSA raw MIDI port matches {selector:?}"),
+            )
+        })?;
+    RawMidiOutput::open(port)
+        .map_err(|e| io::Error::new(e.kind(), format!("can't open {port} for output: {e}")))
+}
+
+impl MidiOutput for RawMidiOutput {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn send(&mut self, message: &MidiMessage) -> io::Result<()> {
+        self.file.write_all(&message.to_bytes())
+    }
+}
@@ -0,0 +1,147 @@
+use std::{
+    fmt::Write,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use ringbuf::{HeapCons, HeapProd, HeapRb, traits::*};
+
+use crate::{
+    sine_generator::{freq_to_note, note_name},
+    spectrum::{Spectrum, Window, bin_freq, parabolic_peak, peaks, to_db},
+};
+
+const FFT_SIZE: usize = 4096;
+const ROWS: usize = 16;
+const MIN_DB: f32 = -90.;
+const LO: f32 = 30.;
+const HI: f32 = 16000.;
+
+/// Producer side of the output tap, written by the audio callback
+pub type Tap = HeapProd<f32>;
+
+pub struct Analyzer {
+    spectrum: Spectrum,
+    sample_rate: u32,
+    history: Vec<f32>,
+    cons: HeapCons<f32>,
+}
+
+impl Analyzer {
+    pub fn new(sample_rate: u32) -> (Self, Tap) {
+        let (prod, cons) = HeapRb::<f32>::new(FFT_SIZE * 4).split();
+        let analyzer = Self {
+            spectrum: Spectrum::new(FFT_SIZE, Window::Hann),
+            sample_rate,
+            history: vec![0.; FFT_SIZE],
+            cons,
+        };
+        (analyzer, prod)
+    }
+
+    /// Pulls whatever the callback has written into the analysis window
+    fn drain(&mut self) {
+        let mut fresh = [0.0_f32; 1024];
+        loop {
+            let n = self.cons.pop_slice(&mut fresh);
+            if n == 0 {
+                break;
+            }
+            self.history.drain(..n);
+            self.history.extend_from_slice(&fresh[..n]);
+        }
+    }
+
+    pub fn render(&mut self, width: usize) -> String {
+        let mags = self.spectrum.magnitudes(&self.history);
+        let bins = mags.len();
+        let freq_of = |bin: f32| bin_freq(bin, FFT_SIZE, self.sample_rate);
+
+        // log-spaced bars, each taking the loudest bin in its range
+        let bars: Vec<f32> = (0..width)
+            .map(|col| {
+                let lo = LO * (HI / LO).powf(col as f32 / width as f32);
+                let hi = LO * (HI / LO).powf((col + 1) as f32 / width as f32);
+                let lo_bin = (lo * FFT_SIZE as f32 / self.sample_rate as f32) as usize;
+                let hi_bin = ((hi * FFT_SIZE as f32 / self.sample_rate as f32) as usize)
+                    .max(lo_bin + 1)
+                    .min(bins);
+                let peak = mags[lo_bin.min(bins - 1)..hi_bin]
+                    .iter()
+                    .fold(0.0_f32, |acc, m| acc.max(*m));
+                ((to_db(peak) - MIN_DB) / -MIN_DB).clamp(0., 1.)
+            })
+            .collect();
+
+        let mut out = String::from("\x1b[H\x1b[2J");
+        for row in (0..ROWS).rev() {
+            let level = row as f32 / ROWS as f32;
+            let line: String = bars
+                .iter()
+                .map(|b| if *b > level { '█' } else { ' ' })
+                .collect();
+            let _ = writeln!(out, "{line}");
+        }
+
+        let mut axis = vec![' '; width];
+        [50., 100., 200., 500., 1000., 2000., 5000., 10000.]
+            .iter()
+            .for_each(|f: &f32| {
+                let col = ((f / LO).ln() / (HI / LO).ln() * width as f32) as usize;
+                let label = if *f >= 1000. {
+                    format!("{}k", f / 1000.)
+                } else {
+                    format!("{f}")
+                };
+                label.chars().enumerate().for_each(|(idx, c)| {
+                    if let Some(slot) = axis.get_mut(col + idx) {
+                        *slot = c;
+                    }
+                });
+            });
+        let _ = writeln!(out, "{}", axis.into_iter().collect::<String>());
+
+        peaks(&mags, 6, -60.).into_iter().for_each(|bin| {
+            let (bin, mag) = parabolic_peak(&mags, bin);
+            let freq = freq_of(bin);
+            let n = freq_to_note(freq);
+            let nearest = n.round().clamp(0., 127.);
+            let cents = (n - nearest) * 100.;
+            let _ = write!(
+                out,
+                "{} {cents:+.0}c {freq:.1}Hz {:.0}dB   ",
+                note_name(nearest as u8),
+                to_db(mag)
+            );
+        });
+        out
+    }
+
+    /// Redraws the spectrum while `enabled` is set
+    pub fn spawn(mut self, enabled: Arc<AtomicBool>) -> JoinHandle<()> {
+        thread::spawn(move || {
+            loop {
+                self.drain();
+                if enabled.load(Ordering::Relaxed) {
+                    let width = terminal_width().saturating_sub(1).max(16);
+                    println!("{}", self.render(width));
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        })
+    }
+}
+
+fn terminal_width() -> usize {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    let ok = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+    if ok == 0 && size.ws_col > 0 {
+        size.ws_col as usize
+    } else {
+        80
+    }
+}
@@ -0,0 +1,363 @@
+use std::{collections::VecDeque, path::Path, sync::Arc};
+
+use rustfft::{Fft, FftPlanner, num_complex::Complex};
+
+use crate::wav::{Wav, resample};
+
+/// Samples faded out at the end of a trimmed response
+const FADE: usize = 256;
+
+#[derive(Debug, Clone)]
+pub struct ImpulseResponse {
+    channels: Vec<Vec<f32>>,
+    sample_rate: u32,
+}
+
+impl ImpulseResponse {
+    pub fn new(channels: Vec<Vec<f32>>, sample_rate: u32) -> Self {
+        Self {
+            channels,
+            sample_rate,
+        }
+    }
+
+    /// Loads a mono or stereo response, resampled to `sample_rate`. Only
+    /// the first two channels of a multichannel file are used.
+    pub fn from_wav(path: impl AsRef<Path>, sample_rate: u32) -> Result<Self, hound::Error> {
+        let wav = Wav::read(path)?;
+        let ratio = sample_rate as f32 / wav.sample_rate as f32;
+        let channels = wav
+            .channels
+            .iter()
+            .take(2)
+            .map(|ch| {
+                if wav.sample_rate == sample_rate {
+                    ch.clone()
+                } else {
+                    resample(ch, ratio)
+                }
+            })
+            .collect();
+        Ok(Self::new(channels, sample_rate).normalise())
+    }
+
+    pub fn channels(&self) -> &[Vec<f32>] {
+        &self.channels
+    }
+
+    pub fn len(&self) -> usize {
+        self.channels.first().map_or(0, Vec::len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Keeps `length` seconds starting at `start` seconds
+    pub fn trim(mut self, start: f32, length: Option<f32>) -> Self {
+        let sr = self.sample_rate as f32;
+        let start = ((start * sr) as usize).min(self.len());
+        let end = length.map_or(self.len(), |l| (start + (l * sr) as usize).min(self.len()));
+        self.channels.iter_mut().for_each(|ch| {
+            ch.truncate(end);
+            ch.drain(..start);
+        });
+        self.fade_out();
+        self
+    }
+
+    /// Removes leading and trailing samples quieter than `threshold_db`
+    /// relative to the peak
+    pub fn trim_silence(mut self, threshold_db: f32) -> Self {
+        let peak = self
+            .channels
+            .iter()
+            .flatten()
+            .fold(0.0_f32, |acc, s| acc.max(s.abs()));
+        let threshold = peak * 10.0_f32.powf(threshold_db / 20.);
+        let loud = |idx: usize| self.channels.iter().any(|ch| ch[idx].abs() > threshold);
+        let Some(start) = (0..self.len()).find(|idx| loud(*idx)) else {
+            return self;
+        };
+        let end = (0..self.len())
+            .rev()
+            .find(|idx| loud(*idx))
+            .unwrap_or(start)
+            + 1;
+        self.channels.iter_mut().for_each(|ch| {
+            ch.truncate(end);
+            ch.drain(..start);
+        });
+        self.fade_out();
+        self
+    }
+
+    /// Lengthens or shortens the decay by `factor` without changing pitch.
+    /// The exponential envelope measured by `decay_time` is swapped for one
+    /// `factor` times slower; a longer response continues by repeating its
+    /// second half under the new envelope.
+    pub fn stretch(mut self, factor: f32) -> Self {
+        let Some(tau) = self.decay_time().filter(|_| factor > 0.) else {
+            return self;
+        };
+        let len = self.len();
+        let new_len = (len as f32 * factor).round() as usize;
+        let half = len / 2;
+        self.channels.iter_mut().for_each(|ch| {
+            *ch = (0..new_len)
+                .map(|t| {
+                    let src = if t < len {
+                        t
+                    } else {
+                        half + (t - len) % (len - half)
+                    };
+                    let (t, src_t) = (t as f32, src as f32);
+                    ch[src] * (src_t / tau - t / (tau * factor)).exp()
+                })
+                .collect()
+        });
+        self.fade_out();
+        self.normalise()
+    }
+
+    /// Time constant of the amplitude decay in samples, from the slope of
+    /// the backward-integrated energy between -5 and -25 dB (Schroeder, 1965)
+    pub fn decay_time(&self) -> Option<f32> {
+        let mut energy: Vec<f32> = (0..self.len())
+            .map(|t| self.channels.iter().map(|ch| ch[t] * ch[t]).sum())
+            .collect();
+        (0..energy.len().saturating_sub(1))
+            .rev()
+            .for_each(|t| energy[t] += energy[t + 1]);
+        let total = *energy.first()?;
+        let below = |db: f32| energy.iter().position(|e| 10. * (e / total).log10() <= db);
+        let (t5, t25) = (below(-5.)?, below(-25.)?);
+        // amplitude exp(-t / tau) loses 20 / ln 10 dB of energy per tau
+        (t25 > t5).then(|| (t25 - t5) as f32 / std::f32::consts::LN_10)
+    }
+
+    /// Scales the response to unit energy in its loudest channel
+    pub fn normalise(mut self) -> Self {
+        let energy = self
+            .channels
+            .iter()
+            .map(|ch| ch.iter().map(|s| s * s).sum::<f32>())
+            .fold(0.0_f32, f32::max);
+        if energy > 0. {
+            let scale = energy.sqrt().recip();
+            self.channels.iter_mut().flatten().for_each(|s| *s *= scale);
+        }
+        self
+    }
+
+    fn fade_out(&mut self) {
+        self.channels.iter_mut().for_each(|ch| {
+            let len = ch.len().min(FADE);
+            let start = ch.len() - len;
+            ch[start..]
+                .iter_mut()
+                .enumerate()
+                .for_each(|(idx, s)| *s *= 1. - idx as f32 / len as f32);
+        });
+    }
+}
+
+/// Uniformly partitioned overlap-save convolution. Latency is one block.
+pub struct Convolver {
+    block: usize,
+    fft: Arc<dyn Fft<f32>>,
+    ifft: Arc<dyn Fft<f32>>,
+    partitions: Vec<Vec<Complex<f32>>>,
+    // frequency-domain delay line of past input spectra
+    fdl: Vec<Vec<Complex<f32>>>,
+    fdl_pos: usize,
+    input: Vec<f32>,
+    output: Vec<f32>,
+    fill: usize,
+    spectrum: Vec<Complex<f32>>,
+    acc: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+}
+
+impl Convolver {
+    pub fn new(ir: &[f32], block: usize) -> Self {
+        let block = block.max(1);
+        let size = 2 * block;
+        let mut planner = FftPlanner::<f32>::new();
+        let fft = planner.plan_fft_forward(size);
+        let ifft = planner.plan_fft_inverse(size);
+        let mut scratch = vec![
+            Complex::default();
+            fft.get_inplace_scratch_len()
+                .max(ifft.get_inplace_scratch_len())
+        ];
+
+        let partitions: Vec<Vec<Complex<f32>>> = ir
+            .chunks(block)
+            .map(|chunk| {
+                let mut h = vec![Complex::default(); size];
+                h.iter_mut().zip(chunk).for_each(|(h, s)| h.re = *s);
+                fft.process_with_scratch(&mut h, &mut scratch);
+                h
+            })
+            .collect();
+        let count = partitions.len().max(1);
+
+        Self {
+            block,
+            fft,
+            ifft,
+            partitions,
+            fdl: vec![vec![Complex::default(); size]; count],
+            fdl_pos: 0,
+            input: vec![0.; size],
+            output: vec![0.; block],
+            fill: 0,
+            spectrum: vec![Complex::default(); size],
+            acc: vec![Complex::default(); size],
+            scratch,
+        }
+    }
+
+    pub fn process(&mut self, x: f32) -> f32 {
+        self.input[self.block + self.fill] = x;
+        let y = self.output[self.fill];
+        self.fill += 1;
+        if self.fill == self.block {
+            self.process_block();
+            self.fill = 0;
+        }
+        y
+    }
+
+    fn process_block(&mut self) {
+        let count = self.fdl.len();
+        self.spectrum
+            .iter_mut()
+            .zip(self.input.iter())
+            .for_each(|(c, s)| *c = Complex::new(*s, 0.));
+        self.fft
+            .process_with_scratch(&mut self.spectrum, &mut self.scratch);
+
+        self.fdl_pos = (self.fdl_pos + count - 1) % count;
+        self.fdl[self.fdl_pos].copy_from_slice(&self.spectrum);
+
+        self.acc.fill(Complex::default());
+        self.partitions.iter().enumerate().for_each(|(k, h)| {
+            let x = &self.fdl[(self.fdl_pos + k) % count];
+            self.acc
+                .iter_mut()
+                .zip(x.iter().zip(h.iter()))
+                .for_each(|(acc, (x, h))| *acc += x * h);
+        });
+        self.ifft
+            .process_with_scratch(&mut self.acc, &mut self.scratch);
+
+        let scale = 1. / (2 * self.block) as f32;
+        self.output
+            .iter_mut()
+            .zip(self.acc[self.block..].iter())
+            .for_each(|(y, c)| *y = c.re * scale);
+        self.input.copy_within(self.block.., 0);
+    }
+}
+
+/// Stereo convolution reverb. Each side is convolved with its channel of
+/// the response; a mono response serves both.
+pub struct Reverb {
+    convolvers: [Convolver; 2],
+    pre_delay: [VecDeque<f32>; 2],
+    sample_rate: u32,
+    wet: f32,
+    dry: f32,
+}
+
+impl Reverb {
+    pub fn new(ir: &ImpulseResponse, block: usize) -> Self {
+        let left = ir.channels().first().map_or(&[][..], |ch| &ch[..]);
+        let right = ir.channels().get(1).map_or(left, |ch| &ch[..]);
+        Self {
+            convolvers: [Convolver::new(left, block), Convolver::new(right, block)],
+            pre_delay: Default::default(),
+            sample_rate: ir.sample_rate,
+            wet: 0.3,
+            dry: 1.,
+        }
+    }
+
+    pub fn wet(mut self, wet: f32) -> Self {
+        self.set_wet(wet);
+        self
+    }
+
+    pub fn pre_delay(mut self, ms: f32) -> Self {
+        self.set_pre_delay(ms);
+        self
+    }
+
+    pub fn set_wet(&mut self, wet: f32) {
+        self.wet = wet.max(0.);
+    }
+
+    pub fn set_dry(&mut self, dry: f32) {
+        self.dry = dry.max(0.);
+    }
+
+    pub fn set_pre_delay(&mut self, ms: f32) {
+        let len = (ms.max(0.) * 0.001 * self.sample_rate as f32) as usize;
+        self.pre_delay
+            .iter_mut()
+            .for_each(|delay| delay.resize(len, 0.));
+    }
+
+    pub fn process_frame(&mut self, frame: [f32; 2]) -> [f32; 2] {
+        let mut out = [0.; 2];
+        for (ch, x) in frame.into_iter().enumerate() {
+            let delay = &mut self.pre_delay[ch];
+            let wet_in = if delay.is_empty() {
+                x
+            } else {
+                delay.push_back(x);
+                delay.pop_front().unwrap_or(0.)
+            };
+            out[ch] = x * self.dry + self.convolvers[ch].process(wet_in) * self.wet;
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Exponentially decaying noise with time constant `tau` samples
+    fn room(tau: f32, len: usize) -> ImpulseResponse {
+        let mut seed = 1_u32;
+        let noise = (0..len).map(|_| {
+            seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (seed >> 8) as f32 / (1 << 23) as f32 - 1.
+        });
+        let ch = noise
+            .enumerate()
+            .map(|(t, n)| n * (-(t as f32) / tau).exp())
+            .collect();
+        ImpulseResponse::new(vec![ch], 48000)
+    }
+
+    #[test]
+    fn stretch_scales_decay() {
+        let ir = room(4800., 48000);
+        let tau = ir.decay_time().unwrap();
+        assert!((tau / 4800. - 1.).abs() < 0.1, "{tau}");
+
+        let longer = ir.clone().stretch(2.);
+        assert_eq!(longer.len(), 96000);
+        let ratio = longer.decay_time().unwrap() / tau;
+        assert!((ratio - 2.).abs() < 0.2, "{ratio}");
+
+        let shorter = ir.stretch(0.5);
+        assert_eq!(shorter.len(), 24000);
+        let ratio = shorter.decay_time().unwrap() / tau;
+        assert!((ratio - 0.5).abs() < 0.05, "{ratio}");
+    }
+}
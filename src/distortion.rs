@@ -0,0 +1,326 @@
+use std::{f32::consts::PI, str::FromStr};
+
+use crate::dynamics::db_to_gain;
+
+/// Taps in each half-band interpolation/decimation filter
+const TAPS: usize = 47;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Curve {
+    Tanh,
+    HardClip,
+    Foldback,
+    /// Asymmetric, even-harmonic saturation
+    Tube,
+    /// Cubic soft clipper, flat above ±1
+    SoftClip,
+    /// Quantises to `bits` and holds every sample for `hold` samples
+    BitCrush {
+        bits: u32,
+        hold: u32,
+    },
+}
+
+impl Curve {
+    /// Memoryless transfer function. `BitCrush` only quantises here; the
+    /// sample-rate reduction lives in `Distortion`.
+    pub fn shape(&self, x: f32) -> f32 {
+        match self {
+            Curve::Tanh => x.tanh(),
+            Curve::HardClip => x.clamp(-1., 1.),
+            Curve::Foldback => {
+                // reflect about ±1 until inside the range
+                let t = (x + 1.).rem_euclid(4.);
+                if t < 2. { t - 1. } else { 3. - t }
+            }
+            Curve::Tube => {
+                if x >= 0. {
+                    1. - (-x).exp()
+                } else {
+                    let x = x.max(-3.);
+                    -(1. - (x * 0.6).exp()) / 0.6 * 0.5
+                }
+            }
+            Curve::SoftClip => {
+                if x.abs() >= 1. {
+                    x.signum() * 2. / 3.
+                } else {
+                    x - x * x * x / 3.
+                }
+            }
+            Curve::BitCrush { bits, .. } => {
+                let steps = 2.0_f32.powi(*bits as i32 - 1);
+                (x.clamp(-1., 1.) * steps).round() / steps
+            }
+        }
+    }
+}
+
+impl FromStr for Curve {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tanh" => Ok(Curve::Tanh),
+            "hard" => Ok(Curve::HardClip),
+            "fold" => Ok(Curve::Foldback),
+            "tube" => Ok(Curve::Tube),
+            "soft" => Ok(Curve::SoftClip),
+            "crush" => Ok(Curve::BitCrush { bits: 8, hold: 4 }),
+            _ => Err(format!("unknown distortion curve: {s}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Oversampling {
+    X1,
+    X2,
+    X4,
+    X8,
+}
+
+impl Oversampling {
+    fn stages(&self) -> usize {
+        match self {
+            Oversampling::X1 => 0,
+            Oversampling::X2 => 1,
+            Oversampling::X4 => 2,
+            Oversampling::X8 => 3,
+        }
+    }
+}
+
+impl TryFrom<usize> for Oversampling {
+    type Error = String;
+
+    fn try_from(factor: usize) -> Result<Self, Self::Error> {
+        match factor {
+            1 => Ok(Oversampling::X1),
+            2 => Ok(Oversampling::X2),
+            4 => Ok(Oversampling::X4),
+            8 => Ok(Oversampling::X8),
+            _ => Err(format!("unsupported oversampling factor: {factor}")),
+        }
+    }
+}
+
+/// Blackman-windowed sinc low-pass at a quarter of the sample rate, split
+/// into its even and odd polyphase components
+fn halfband() -> (Vec<f32>, Vec<f32>) {
+    let m = (TAPS - 1) as f32 / 2.;
+    let h: Vec<f32> = (0..TAPS)
+        .map(|n| {
+            let t = n as f32 - m;
+            let sinc = if t == 0. {
+                0.5
+            } else {
+                (0.5 * PI * t).sin() / (PI * t)
+            };
+            let w = 2. * PI * n as f32 / (TAPS - 1) as f32;
+            sinc * (0.42 - 0.5 * w.cos() + 0.08 * (2. * w).cos())
+        })
+        .collect();
+    let sum: f32 = h.iter().sum();
+    let even = h.iter().step_by(2).map(|c| c / sum).collect();
+    let odd = h.iter().skip(1).step_by(2).map(|c| c / sum).collect();
+    (even, odd)
+}
+
+#[derive(Debug, Clone)]
+struct Upsampler2 {
+    even: Vec<f32>,
+    odd: Vec<f32>,
+    history: Vec<f32>,
+    pos: usize,
+}
+
+impl Upsampler2 {
+    fn new() -> Self {
+        let (even, odd) = halfband();
+        let len = even.len();
+        Self {
+            even,
+            odd,
+            history: vec![0.; len],
+            pos: 0,
+        }
+    }
+
+    fn process(&mut self, x: f32) -> [f32; 2] {
+        let len = self.history.len();
+        self.pos = (self.pos + len - 1) % len;
+        self.history[self.pos] = x;
+        let dot = |taps: &[f32]| -> f32 {
+            taps.iter()
+                .enumerate()
+                .map(|(k, c)| c * self.history[(self.pos + k) % len])
+                .sum()
+        };
+        [2. * dot(&self.even), 2. * dot(&self.odd)]
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Downsampler2 {
+    even: Vec<f32>,
+    odd: Vec<f32>,
+    even_history: Vec<f32>,
+    odd_history: Vec<f32>,
+    pos: usize,
+}
+
+impl Downsampler2 {
+    fn new() -> Self {
+        let (even, odd) = halfband();
+        Self {
+            even_history: vec![0.; even.len()],
+            odd_history: vec![0.; even.len()],
+            even,
+            odd,
+            pos: 0,
+        }
+    }
+
+    /// Takes two consecutive samples at the high rate
+    fn process(&mut self, x: [f32; 2]) -> f32 {
+        let len = self.even_history.len();
+        self.pos = (self.pos + len - 1) % len;
+        self.even_history[self.pos] = x[0];
+        // the odd phase sees the previous high-rate sample
+        self.odd_history[self.pos] = x[1];
+        let even: f32 = self
+            .even
+            .iter()
+            .enumerate()
+            .map(|(k, c)| c * self.even_history[(self.pos + k) % len])
+            .sum();
+        let odd: f32 = self
+            .odd
+            .iter()
+            .enumerate()
+            .map(|(k, c)| c * self.odd_history[(self.pos + k + 1) % len])
+            .sum();
+        even + odd
+    }
+}
+
+/// Cascaded 2× polyphase stages around a nonlinearity
+#[derive(Debug, Clone)]
+pub struct Oversampler {
+    ups: Vec<Upsampler2>,
+    downs: Vec<Downsampler2>,
+    buf: [f32; 8],
+}
+
+impl Oversampler {
+    pub fn new(factor: Oversampling) -> Self {
+        Self {
+            ups: (0..factor.stages()).map(|_| Upsampler2::new()).collect(),
+            downs: (0..factor.stages()).map(|_| Downsampler2::new()).collect(),
+            buf: [0.; 8],
+        }
+    }
+
+    pub fn process(&mut self, x: f32, mut f: impl FnMut(f32) -> f32) -> f32 {
+        let mut len = 1;
+        self.buf[0] = x;
+        for up in self.ups.iter_mut() {
+            let input = self.buf;
+            input[..len].iter().enumerate().for_each(|(idx, s)| {
+                let [a, b] = up.process(*s);
+                self.buf[2 * idx] = a;
+                self.buf[2 * idx + 1] = b;
+            });
+            len *= 2;
+        }
+        self.buf[..len].iter_mut().for_each(|s| *s = f(*s));
+        for down in self.downs.iter_mut().rev() {
+            len /= 2;
+            for idx in 0..len {
+                self.buf[idx] = down.process([self.buf[2 * idx], self.buf[2 * idx + 1]]);
+            }
+        }
+        self.buf[0]
+    }
+}
+
+/// Waveshaping distortion. The input is scaled by `drive`, shaped at the
+/// oversampled rate and DC-blocked.
+#[derive(Debug, Clone)]
+pub struct Distortion {
+    curve: Curve,
+    drive: f32,
+    output: f32,
+    mix: f32,
+    oversampler: Oversampler,
+    held: f32,
+    counter: u32,
+    dc_x: f32,
+    dc_y: f32,
+}
+
+impl Distortion {
+    pub fn new(curve: Curve, oversampling: Oversampling) -> Self {
+        Self {
+            curve,
+            drive: 1.,
+            output: 1.,
+            mix: 1.,
+            oversampler: Oversampler::new(oversampling),
+            held: 0.,
+            counter: 0,
+            dc_x: 0.,
+            dc_y: 0.,
+        }
+    }
+
+    /// Safety clipper for the raw sine sums
+    pub fn soft_clip() -> Self {
+        Self::new(Curve::SoftClip, Oversampling::X2).output(1.5)
+    }
+
+    pub fn drive(mut self, db: f32) -> Self {
+        self.set_drive(db);
+        self
+    }
+
+    pub fn output(mut self, gain: f32) -> Self {
+        self.output = gain;
+        self
+    }
+
+    pub fn set_drive(&mut self, db: f32) {
+        self.drive = db_to_gain(db);
+    }
+
+    pub fn set_mix(&mut self, mix: f32) {
+        self.mix = mix.clamp(0., 1.);
+    }
+
+    pub fn process(&mut self, x: f32) -> f32 {
+        let curve = self.curve;
+        let driven = x * self.drive;
+        let shaped = match curve {
+            // aliasing is the point of a sample-rate reducer
+            Curve::BitCrush { hold, .. } => {
+                if self.counter == 0 {
+                    self.held = curve.shape(driven);
+                }
+                self.counter = (self.counter + 1) % hold.max(1);
+                self.held
+            }
+            _ => self.oversampler.process(driven, |s| curve.shape(s)),
+        };
+
+        let y = if curve == Curve::Tube {
+            self.dc_y = shaped - self.dc_x + 0.995 * self.dc_y;
+            self.dc_x = shaped;
+            self.dc_y
+        } else {
+            shaped
+        };
+
+        (x * (1. - self.mix) + y * self.mix) * self.output
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::VecDeque;
+
+const EPSILON: f32 = 1e-9;
+
+pub fn db_to_gain(db: f32) -> f32 {
+    10.0_f32.powf(db / 20.)
+}
+
+pub fn gain_to_db(gain: f32) -> f32 {
+    20. * (gain.abs() + EPSILON).log10()
+}
+
+/// One-pole smoothing coefficient for a time constant in milliseconds
+pub fn time_coeff(ms: f32, sample_rate: u32) -> f32 {
+    if ms <= 0. {
+        0.
+    } else {
+        (-1. / (ms * 0.001 * sample_rate as f32)).exp()
+    }
+}
+
+/// Feed-forward compressor with a soft knee, computed in the log domain
+#[derive(Debug, Clone)]
+pub struct Compressor {
+    sample_rate: u32,
+    threshold: f32,
+    ratio: f32,
+    knee: f32,
+    attack: f32,
+    release: f32,
+    makeup: f32,
+    attack_coeff: f32,
+    release_coeff: f32,
+    reduction: f32,
+}
+
+impl Compressor {
+    pub fn new(sample_rate: u32) -> Self {
+        let mut compressor = Self {
+            sample_rate,
+            threshold: -18.,
+            ratio: 4.,
+            knee: 6.,
+            attack: 5.,
+            release: 120.,
+            makeup: 0.,
+            attack_coeff: 0.,
+            release_coeff: 0.,
+            reduction: 0.,
+        };
+        compressor.update_coeffs();
+        compressor
+    }
+
+    pub fn set_threshold(&mut self, db: f32) {
+        self.threshold = db;
+    }
+
+    pub fn set_ratio(&mut self, ratio: f32) {
+        self.ratio = ratio.max(1.);
+    }
+
+    pub fn set_knee(&mut self, db: f32) {
+        self.knee = db.max(0.);
+    }
+
+    pub fn set_attack(&mut self, ms: f32) {
+        self.attack = ms.max(0.);
+        self.update_coeffs();
+    }
+
+    pub fn set_release(&mut self, ms: f32) {
+        self.release = ms.max(0.);
+        self.update_coeffs();
+    }
+
+    pub fn set_makeup(&mut self, db: f32) {
+        self.makeup = db;
+    }
+
+    /// Current gain reduction in dB, as a positive number
+    pub fn gain_reduction_db(&self) -> f32 {
+        self.reduction
+    }
+
+    fn update_coeffs(&mut self) {
+        self.attack_coeff = time_coeff(self.attack, self.sample_rate);
+        self.release_coeff = time_coeff(self.release, self.sample_rate);
+    }
+
+    /// Static gain curve: the level the detector input is mapped to
+    fn gain_computer(&self, level: f32) -> f32 {
+        let over = level - self.threshold;
+        let slope = 1. / self.ratio - 1.;
+        if 2. * over < -self.knee {
+            level
+        } else if self.knee > 0. && 2. * over.abs() <= self.knee {
+            level + slope * (over + self.knee / 2.).powi(2) / (2. * self.knee)
+        } else {
+            level + slope * over
+        }
+    }
+
+    /// Advances the detector by one sample of `key` and returns the linear
+    /// gain to apply, including makeup. `key` is the signal itself, its
+    /// stereo peak or a sidechain.
+    pub fn gain(&mut self, key: f32) -> f32 {
+        let level = gain_to_db(key);
+        let target = level - self.gain_computer(level);
+        let coeff = if target > self.reduction {
+            self.attack_coeff
+        } else {
+            self.release_coeff
+        };
+        self.reduction = coeff * self.reduction + (1. - coeff) * target;
+        db_to_gain(self.makeup - self.reduction)
+    }
+}
+
+/// Look-ahead brickwall limiter. The output is delayed by the look-ahead
+/// time and never exceeds the ceiling. Stereo frames share one gain.
+#[derive(Debug, Clone)]
+pub struct Limiter {
+    ceiling: f32,
+    release_coeff: f32,
+    lookahead: usize,
+    delay: VecDeque<[f32; 2]>,
+    // (sample index, required gain), increasing in gain
+    minima: VecDeque<(usize, f32)>,
+    window: VecDeque<f32>,
+    window_sum: f32,
+    held: f32,
+    gain: f32,
+    pos: usize,
+}
+
+impl Limiter {
+    pub fn new(sample_rate: u32, lookahead_ms: f32, release_ms: f32, ceiling_db: f32) -> Self {
+        let lookahead = ((lookahead_ms * 0.001 * sample_rate as f32) as usize).max(1);
+        Self {
+            ceiling: db_to_gain(ceiling_db),
+            release_coeff: time_coeff(release_ms, sample_rate),
+            lookahead,
+            delay: VecDeque::from(vec![[0.0; 2]; lookahead - 1]),
+            minima: VecDeque::with_capacity(lookahead),
+            window: VecDeque::from(vec![1.0; lookahead]),
+            window_sum: lookahead as f32,
+            held: 1.,
+            gain: 1.,
+            pos: 0,
+        }
+    }
+
+    pub fn set_ceiling(&mut self, db: f32) {
+        self.ceiling = db_to_gain(db);
+    }
+
+    /// Current gain reduction in dB, as a positive number
+    pub fn gain_reduction_db(&self) -> f32 {
+        -gain_to_db(self.gain)
+    }
+
+    pub fn process_frame(&mut self, frame: [f32; 2]) -> [f32; 2] {
+        let peak = frame[0].abs().max(frame[1].abs());
+        let required = if peak > self.ceiling {
+            self.ceiling / peak
+        } else {
+            1.
+        };
+
+        // minimum required gain over the look-ahead window
+        while self.minima.back().is_some_and(|(_, g)| *g >= required) {
+            self.minima.pop_back();
+        }
+        self.minima.push_back((self.pos, required));
+        while self
+            .minima
+            .front()
+            .is_some_and(|(idx, _)| idx + self.lookahead <= self.pos)
+        {
+            self.minima.pop_front();
+        }
+        let min = self.minima.front().map_or(1., |(_, g)| *g);
+        self.pos = self.pos.wrapping_add(1);
+
+        // drop instantly, recover exponentially
+        self.held = if min < self.held {
+            min
+        } else {
+            min + self.release_coeff * (self.held - min)
+        };
+
+        // a box filter as long as the look-ahead ramps the gain down in time for the peak
+        self.window_sum += self.held - self.window.pop_front().unwrap_or(1.);
+        self.window.push_back(self.held);
+        if self.pos.is_multiple_of(self.lookahead) {
+            // keep the running sum from drifting
+            self.window_sum = self.window.iter().sum();
+        }
+        self.gain = (self.window_sum / self.lookahead as f32).min(1.);
+
+        self.delay.push_back(frame);
+        let delayed = self.delay.pop_front().unwrap_or([0.; 2]);
+        delayed.map(|s| (s * self.gain).clamp(-self.ceiling, self.ceiling))
+    }
+}
@@ -0,0 +1,439 @@
+use std::{
+    f32::consts::{FRAC_1_SQRT_2, PI},
+    str::FromStr,
+};
+
+use crate::dynamics::time_coeff;
+
+/// Samples between coefficient updates while a parameter is moving
+const UPDATE_INTERVAL: usize = 32;
+const SETTLED: f32 = 1e-4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Slope {
+    Db12,
+    Db24,
+    Db36,
+    Db48,
+}
+
+impl Slope {
+    fn sections(&self) -> usize {
+        match self {
+            Slope::Db12 => 1,
+            Slope::Db24 => 2,
+            Slope::Db36 => 3,
+            Slope::Db48 => 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BandKind {
+    LowShelf,
+    HighShelf,
+    Peaking,
+    LowPass(Slope),
+    HighPass(Slope),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Band {
+    pub kind: BandKind,
+    pub freq: f32,
+    pub gain_db: f32,
+    pub q: f32,
+}
+
+impl Band {
+    pub fn new(kind: BandKind, freq: f32, gain_db: f32, q: f32) -> Self {
+        Self {
+            kind,
+            freq,
+            gain_db,
+            q,
+        }
+    }
+}
+
+impl FromStr for Band {
+    type Err = String;
+
+    /// `kind:freq[:gain_db[:q]]`, e.g. `peak:1000:3:2` or `highpass24:80`;
+    /// pass slopes are 12 to 48 dB per octave
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.trim().split(':').collect();
+        let slope = |slope: &str| match slope {
+            "" | "12" => Ok(Slope::Db12),
+            "24" => Ok(Slope::Db24),
+            "36" => Ok(Slope::Db36),
+            "48" => Ok(Slope::Db48),
+            _ => Err(format!("no {slope} dB slope")),
+        };
+        let kind = match fields[0] {
+            "lowshelf" => BandKind::LowShelf,
+            "highshelf" => BandKind::HighShelf,
+            "peak" => BandKind::Peaking,
+            kind => match (kind.strip_prefix("lowpass"), kind.strip_prefix("highpass")) {
+                (Some(s), _) => BandKind::LowPass(slope(s)?),
+                (_, Some(s)) => BandKind::HighPass(slope(s)?),
+                _ => return Err(format!("unknown EQ band: {kind}")),
+            },
+        };
+        let number = |idx: usize, default: f32| {
+            fields.get(idx).map_or(Ok(default), |f| {
+                f.parse::<f32>().map_err(|e| format!("{f}: {e}"))
+            })
+        };
+        let freq = number(1, 0.)?;
+        if !freq.is_finite() || freq <= 0. {
+            return Err(format!("EQ band `{s}` needs a frequency"));
+        }
+        Ok(Band::new(
+            kind,
+            freq,
+            number(2, 0.)?,
+            number(3, FRAC_1_SQRT_2)?,
+        ))
+    }
+}
+
+/// Normalised biquad coefficients, a0 == 1
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Coeffs {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+}
+
+impl Coeffs {
+    pub const IDENTITY: Coeffs = Coeffs {
+        b0: 1.,
+        b1: 0.,
+        b2: 0.,
+        a1: 0.,
+        a2: 0.,
+    };
+
+    /// Coefficients from the RBJ audio EQ cookbook
+    pub fn rbj(kind: BandKind, freq: f32, gain_db: f32, q: f32, sample_rate: u32) -> Self {
+        let w0 = 2. * PI * freq.clamp(1., sample_rate as f32 * 0.499) / sample_rate as f32;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2. * q.max(0.01));
+        let a = 10.0_f32.powf(gain_db / 40.);
+
+        let (b0, b1, b2, a0, a1, a2) = match kind {
+            BandKind::Peaking => (
+                1. + alpha * a,
+                -2. * cos,
+                1. - alpha * a,
+                1. + alpha / a,
+                -2. * cos,
+                1. - alpha / a,
+            ),
+            BandKind::LowShelf => {
+                let s = 2. * a.sqrt() * alpha;
+                (
+                    a * ((a + 1.) - (a - 1.) * cos + s),
+                    2. * a * ((a - 1.) - (a + 1.) * cos),
+                    a * ((a + 1.) - (a - 1.) * cos - s),
+                    (a + 1.) + (a - 1.) * cos + s,
+                    -2. * ((a - 1.) + (a + 1.) * cos),
+                    (a + 1.) + (a - 1.) * cos - s,
+                )
+            }
+            BandKind::HighShelf => {
+                let s = 2. * a.sqrt() * alpha;
+                (
+                    a * ((a + 1.) + (a - 1.) * cos + s),
+                    -2. * a * ((a - 1.) + (a + 1.) * cos),
+                    a * ((a + 1.) + (a - 1.) * cos - s),
+                    (a + 1.) - (a - 1.) * cos + s,
+                    2. * ((a - 1.) - (a + 1.) * cos),
+                    (a + 1.) - (a - 1.) * cos - s,
+                )
+            }
+            BandKind::LowPass(_) => (
+                (1. - cos) / 2.,
+                1. - cos,
+                (1. - cos) / 2.,
+                1. + alpha,
+                -2. * cos,
+                1. - alpha,
+            ),
+            BandKind::HighPass(_) => (
+                (1. + cos) / 2.,
+                -(1. + cos),
+                (1. + cos) / 2.,
+                1. + alpha,
+                -2. * cos,
+                1. - alpha,
+            ),
+        };
+
+        Self {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+        }
+    }
+
+    /// |H(e^jw)| at `freq`
+    pub fn magnitude(&self, freq: f32, sample_rate: u32) -> f32 {
+        let w = 2. * PI * freq / sample_rate as f32;
+        let (s1, c1) = w.sin_cos();
+        let (s2, c2) = (2. * w).sin_cos();
+        let num_re = self.b0 + self.b1 * c1 + self.b2 * c2;
+        let num_im = -(self.b1 * s1 + self.b2 * s2);
+        let den_re = 1. + self.a1 * c1 + self.a2 * c2;
+        let den_im = -(self.a1 * s1 + self.a2 * s2);
+        ((num_re * num_re + num_im * num_im) / (den_re * den_re + den_im * den_im)).sqrt()
+    }
+}
+
+/// Transposed direct form II biquad
+#[derive(Debug, Clone, Copy)]
+pub struct Biquad {
+    coeffs: Coeffs,
+    z1: f32,
+    z2: f32,
+}
+
+impl Biquad {
+    pub fn new(coeffs: Coeffs) -> Self {
+        Self {
+            coeffs,
+            z1: 0.,
+            z2: 0.,
+        }
+    }
+
+    pub fn set_coeffs(&mut self, coeffs: Coeffs) {
+        self.coeffs = coeffs;
+    }
+
+    #[inline(always)]
+    pub fn process(&mut self, x: f32) -> f32 {
+        let c = &self.coeffs;
+        let y = c.b0 * x + self.z1;
+        self.z1 = c.b1 * x - c.a1 * y + self.z2;
+        self.z2 = c.b2 * x - c.a2 * y;
+        y
+    }
+}
+
+/// Butterworth section Qs for a cascade of `sections` second order filters
+fn butterworth_qs(sections: usize) -> Vec<f32> {
+    let order = 2 * sections;
+    (1..=sections)
+        .map(|k| 1. / (2. * f32::cos((2 * k - 1) as f32 * PI / (2 * order) as f32)))
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+struct BandState {
+    target: Band,
+    current: Band,
+    sections: Vec<Biquad>,
+}
+
+impl BandState {
+    fn new(band: Band, sample_rate: u32) -> Self {
+        let sections = match band.kind {
+            BandKind::LowPass(slope) | BandKind::HighPass(slope) => slope.sections(),
+            _ => 1,
+        };
+        let mut state = Self {
+            target: band,
+            current: band,
+            sections: vec![Biquad::new(Coeffs::IDENTITY); sections],
+        };
+        state.update(sample_rate);
+        state
+    }
+
+    fn section_coeffs(band: &Band, sample_rate: u32) -> Vec<Coeffs> {
+        match band.kind {
+            BandKind::LowPass(slope) | BandKind::HighPass(slope) if slope != Slope::Db12 => {
+                butterworth_qs(slope.sections())
+                    .into_iter()
+                    .map(|q| Coeffs::rbj(band.kind, band.freq, band.gain_db, q, sample_rate))
+                    .collect()
+            }
+            _ => vec![Coeffs::rbj(
+                band.kind,
+                band.freq,
+                band.gain_db,
+                band.q,
+                sample_rate,
+            )],
+        }
+    }
+
+    fn update(&mut self, sample_rate: u32) {
+        Self::section_coeffs(&self.current, sample_rate)
+            .into_iter()
+            .zip(self.sections.iter_mut())
+            .for_each(|(coeffs, section)| section.set_coeffs(coeffs));
+    }
+
+    fn settled(&self) -> bool {
+        (self.current.freq / self.target.freq - 1.).abs() < SETTLED
+            && (self.current.gain_db - self.target.gain_db).abs() < SETTLED
+            && (self.current.q - self.target.q).abs() < SETTLED
+    }
+
+    /// Moves the current parameters towards the target. Frequency glides
+    /// in the log domain so sweeps sound even.
+    fn glide(&mut self, coeff: f32) {
+        let (current, target) = (&mut self.current, &self.target);
+        current.freq = (target.freq.ln() + coeff * (current.freq.ln() - target.freq.ln())).exp();
+        current.gain_db = target.gain_db + coeff * (current.gain_db - target.gain_db);
+        current.q = target.q + coeff * (current.q - target.q);
+        if self.settled() {
+            self.current = self.target;
+        }
+    }
+
+    #[inline(always)]
+    fn process(&mut self, x: f32) -> f32 {
+        self.sections
+            .iter_mut()
+            .fold(x, |acc, section| section.process(acc))
+    }
+}
+
+/// Multi-band parametric equaliser. Parameter changes glide over a few
+/// milliseconds, with the biquad coefficients recomputed along the way.
+#[derive(Debug, Clone)]
+pub struct ParametricEq {
+    sample_rate: u32,
+    bands: Vec<BandState>,
+    glide_coeff: f32,
+    counter: usize,
+    moving: bool,
+}
+
+impl ParametricEq {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            bands: Vec::new(),
+            // time constant per coefficient update, not per sample
+            glide_coeff: time_coeff(20. / UPDATE_INTERVAL as f32, sample_rate),
+            counter: 0,
+            moving: false,
+        }
+    }
+
+    pub fn band(mut self, band: Band) -> Self {
+        self.add_band(band);
+        self
+    }
+
+    pub fn add_band(&mut self, band: Band) -> usize {
+        self.bands.push(BandState::new(band, self.sample_rate));
+        self.bands.len() - 1
+    }
+
+    pub fn len(&self) -> usize {
+        self.bands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bands.is_empty()
+    }
+
+    /// Changes a band's frequency, gain and Q. The kind of a band is fixed.
+    pub fn set_band(&mut self, idx: usize, freq: f32, gain_db: f32, q: f32) {
+        let target = &mut self.bands[idx].target;
+        target.freq = freq.max(1.);
+        target.gain_db = gain_db;
+        target.q = q.max(0.01);
+        self.moving = true;
+    }
+
+    pub fn set_gain(&mut self, idx: usize, gain_db: f32) {
+        let Band { freq, q, .. } = self.bands[idx].target;
+        self.set_band(idx, freq, gain_db, q);
+    }
+
+    /// Magnitude response in dB at `freq`, for the target settings
+    pub fn magnitude_db(&self, freq: f32) -> f32 {
+        let gain: f32 = self
+            .bands
+            .iter()
+            .flat_map(|b| BandState::section_coeffs(&b.target, self.sample_rate))
+            .map(|c| c.magnitude(freq, self.sample_rate))
+            .product();
+        20. * gain.max(1e-9).log10()
+    }
+
+    /// `points` log-spaced (frequency, dB) pairs between `lo` and `hi` Hz
+    pub fn magnitude_response(&self, lo: f32, hi: f32, points: usize) -> Vec<(f32, f32)> {
+        let step = (hi / lo).ln() / (points.max(2) - 1) as f32;
+        (0..points)
+            .map(|i| {
+                let freq = lo * (step * i as f32).exp();
+                (freq, self.magnitude_db(freq))
+            })
+            .collect()
+    }
+
+    pub fn process(&mut self, x: f32) -> f32 {
+        if self.moving {
+            if self.counter == 0 {
+                let (sample_rate, coeff) = (self.sample_rate, self.glide_coeff);
+                self.moving = false;
+                self.bands.iter_mut().for_each(|b| {
+                    if b.current != b.target {
+                        b.glide(coeff);
+                        b.update(sample_rate);
+                        self.moving |= b.current != b.target;
+                    }
+                });
+                self.counter = UPDATE_INTERVAL;
+            }
+            self.counter -= 1;
+        }
+
+        self.bands.iter_mut().fold(x, |acc, band| band.process(acc))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peaking_band_response() {
+        let eq = ParametricEq::new(48000).band(Band::new(BandKind::Peaking, 1000., 6., 1.));
+        let response = eq.magnitude_response(250., 4000., 5);
+        let freqs: Vec<f32> = response.iter().map(|(freq, _)| *freq).collect();
+        [250., 500., 1000., 2000., 4000.]
+            .iter()
+            .zip(&freqs)
+            .for_each(|(want, got)| assert!((want - got).abs() < 0.5, "{freqs:?}"));
+
+        let (_, centre) = response[2];
+        assert!((centre - 6.).abs() < 0.1, "{centre}");
+        [response[0], response[4]]
+            .iter()
+            .for_each(|(freq, db)| assert!(db.abs() < 0.5, "{freq} Hz: {db}"));
+    }
+
+    #[test]
+    fn low_shelf_reaches_gain_at_dc() {
+        let eq = ParametricEq::new(48000).band(Band::new(BandKind::LowShelf, 200., -9., 0.707));
+        assert!(
+            (eq.magnitude_db(0.) + 9.).abs() < 0.01,
+            "{}",
+            eq.magnitude_db(0.)
+        );
+        assert!(eq.magnitude_db(10000.).abs() < 0.1);
+    }
+}
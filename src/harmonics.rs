@@ -0,0 +1,214 @@
+use std::{fmt::Write as _, fs, path::Path};
+
+use crate::{
+    patch::Patch,
+    pitch::Yin,
+    spectrum::{Spectrum, Window, bin_freq, parabolic_peak, to_db},
+};
+
+const FFT_SIZE: usize = 8192;
+const HOP: usize = 1024;
+/// Harmonic peaks quieter than this, relative to the loudest, are ignored
+const FLOOR_DB: f32 = -80.;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Partial {
+    pub harmonic: usize,
+    /// Measured frequency over the fundamental
+    pub ratio: f32,
+    /// Peak amplitude relative to the loudest partial
+    pub amplitude: f32,
+    /// Deviation from `harmonic * f0`
+    pub cents: f32,
+    /// Decay rate after the peak, in dB per second
+    pub decay: f32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartialTable {
+    pub f0: f32,
+    pub partials: Vec<Partial>,
+}
+
+impl PartialTable {
+    pub fn save(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        fs::write(path, self.to_string())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut f0 = 0.;
+        let mut partials = Vec::new();
+        for line in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            if let Some(value) = line.strip_prefix("# f0") {
+                f0 = value.trim().parse()?;
+                continue;
+            }
+            if line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [harmonic, ratio, amplitude, cents, decay] = fields[..] else {
+                return Err(format!("malformed partial: {line}").into());
+            };
+            partials.push(Partial {
+                harmonic: harmonic.parse()?,
+                ratio: ratio.parse()?,
+                amplitude: amplitude.parse()?,
+                cents: cents.parse()?,
+                decay: decay.parse()?,
+            });
+        }
+        Ok(Self { f0, partials })
+    }
+}
+
+/// A patch's sines, for `SineGenerator::set_partials`
+impl From<&Patch> for PartialTable {
+    fn from(patch: &Patch) -> Self {
+        let partials = patch
+            .harmonics
+            .iter()
+            .map(|h| Partial {
+                harmonic: h.ratio.round().max(1.) as usize,
+                ratio: h.ratio,
+                amplitude: h.amplitude,
+                cents: 1200. * (h.ratio / h.ratio.round().max(1.)).log2(),
+                decay: h.decay,
+            })
+            .collect();
+        Self { f0: 0., partials }
+    }
+}
+
+impl std::fmt::Display for PartialTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut out = format!(
+            "# f0 {:.3}\n# harmonic ratio amplitude cents decay_db_per_s\n",
+            self.f0
+        );
+        self.partials.iter().for_each(|p| {
+            let _ = writeln!(
+                out,
+                "{} {:.5} {:.5} {:.2} {:.2}",
+                p.harmonic, p.ratio, p.amplitude, p.cents, p.decay
+            );
+        });
+        f.write_str(&out)
+    }
+}
+
+/// Median YIN estimate over the middle half of the recording
+pub fn fundamental(samples: &[f32], sample_rate: u32) -> Option<f32> {
+    let mut yin = Yin::new(sample_rate, 30., 2000.);
+    let frame = yin.frame_len();
+    let (start, end) = (samples.len() / 4, samples.len() * 3 / 4);
+    let mut estimates: Vec<f32> = (start..end.saturating_sub(frame))
+        .step_by(frame / 2)
+        .filter_map(|idx| yin.detect(&samples[idx..idx + frame]))
+        .filter(|p| p.clarity > 0.8)
+        .map(|p| p.freq)
+        .collect();
+    if estimates.is_empty() {
+        return None;
+    }
+    estimates.sort_by(f32::total_cmp);
+    Some(estimates[estimates.len() / 2])
+}
+
+/// Measures up to `harmonics` partials of a sustained note
+pub fn analyze(samples: &[f32], sample_rate: u32, harmonics: usize) -> Option<PartialTable> {
+    let f0 = fundamental(samples, sample_rate)?;
+    let mut spectrum = Spectrum::new(FFT_SIZE, Window::Blackman);
+    let bin_of = |freq: f32| freq * FFT_SIZE as f32 / sample_rate as f32;
+    let nyquist = sample_rate as f32 / 2.;
+    let harmonics = harmonics.min((nyquist / f0) as usize);
+
+    // (frequency, magnitude) of each harmonic in each frame
+    let tracks: Vec<Vec<(f32, f32)>> = (0..samples.len().saturating_sub(FFT_SIZE).max(1))
+        .step_by(HOP)
+        .map(|start| {
+            let end = (start + FFT_SIZE).min(samples.len());
+            let mags = spectrum.magnitudes(&samples[start..end]);
+            (1..=harmonics)
+                .map(|h| {
+                    // search within a third of the fundamental either side
+                    let centre = bin_of(h as f32 * f0);
+                    let width = bin_of(f0 / 3.).max(2.);
+                    let lo = ((centre - width) as usize).max(1);
+                    let hi = ((centre + width) as usize).min(mags.len() - 2);
+                    let peak = (lo..=hi).max_by(|a, b| mags[*a].total_cmp(&mags[*b]))?;
+                    let (bin, mag) = parabolic_peak(&mags, peak);
+                    Some((bin_freq(bin, FFT_SIZE, sample_rate), mag))
+                })
+                .map(|p| p.unwrap_or((0., 0.)))
+                .collect()
+        })
+        .collect();
+
+    let frame_secs = HOP as f32 / sample_rate as f32;
+    let loudest = tracks
+        .iter()
+        .flatten()
+        .fold(0.0_f32, |acc, (_, mag)| acc.max(*mag));
+    if loudest <= 0. {
+        return None;
+    }
+    let floor = loudest * 10.0_f32.powf(FLOOR_DB / 20.);
+
+    let partials = (0..harmonics)
+        .filter_map(|h| {
+            let track: Vec<(f32, f32)> = tracks.iter().map(|frame| frame[h]).collect();
+            let (peak_frame, &(_, peak)) = track
+                .iter()
+                .enumerate()
+                .max_by(|a, b| a.1.1.total_cmp(&b.1.1))?;
+            if peak < floor {
+                return None;
+            }
+
+            let mut freqs: Vec<f32> = track
+                .iter()
+                .filter(|(_, mag)| *mag > peak * 0.1)
+                .map(|(freq, _)| *freq)
+                .collect();
+            freqs.sort_by(f32::total_cmp);
+            let freq = freqs[freqs.len() / 2];
+            let harmonic = h + 1;
+
+            // least-squares slope of level against time after the peak
+            let tail: Vec<(f32, f32)> = track[peak_frame..]
+                .iter()
+                .enumerate()
+                .take_while(|(_, (_, mag))| *mag > floor)
+                .map(|(idx, (_, mag))| (idx as f32 * frame_secs, to_db(*mag)))
+                .collect();
+            let decay = if tail.len() > 2 {
+                let n = tail.len() as f32;
+                let (st, sl) = tail
+                    .iter()
+                    .fold((0., 0.), |(st, sl), (t, l)| (st + t, sl + l));
+                let (mt, ml) = (st / n, sl / n);
+                let (cov, var) = tail.iter().fold((0., 0.), |(cov, var), (t, l)| {
+                    (cov + (t - mt) * (l - ml), var + (t - mt) * (t - mt))
+                });
+                if var > 0. { (cov / var).min(0.) } else { 0. }
+            } else {
+                0.
+            };
+
+            Some(Partial {
+                harmonic,
+                ratio: freq / f0,
+                amplitude: peak / loudest,
+                cents: 1200. * (freq / (harmonic as f32 * f0)).log2(),
+                decay,
+            })
+        })
+        .collect();
+
+    Some(PartialTable { f0, partials })
+}
@@ -1,87 +1,759 @@
 #![allow(unsafe_code)]
+mod alsa_midi;
+mod analyzer;
+mod convolver;
+mod distortion;
+mod dynamics;
+mod eq;
+mod harmonics;
+mod master_bus;
+mod midi;
+mod midi_clock;
+mod midi_device;
 mod midi_event_handler;
+mod midi_input;
+mod midi_map;
+mod midi_output;
+mod onset;
+mod parts;
+mod patch;
+mod pitch;
 mod sine_generator;
+mod smf;
+mod sms;
+mod spectrogram;
+mod spectrum;
+mod transcribe;
+mod usb_midi;
+mod vocoder;
+mod wav;
 
 use std::{
-    cell::RefCell,
-    ffi::c_str,
     fs::File,
-    io::{Read, Seek, Write},
-    ops::Deref,
-    os::fd::{AsRawFd, FromRawFd},
-    rc::Rc,
-    sync::{Arc, LazyLock, Mutex, RwLock, mpsc},
+    io::Write,
+    os::fd::FromRawFd,
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    },
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+use analyzer::Analyzer;
+use convolver::{ImpulseResponse, Reverb};
 use cpal::{
-    Host, SupportedBufferSize, SupportedStreamConfig, SupportedStreamConfigRange,
-    traits::{DeviceTrait, HostTrait, StreamTrait},
-};
-use macros::midi;
-use rusb::{
-    Context, Device, EndpointDescriptor, UsbContext,
-    ffi::{
-        libusb_context, libusb_device, libusb_device_descriptor, libusb_device_handle,
-        libusb_get_descriptor, libusb_get_device_descriptor, libusb_get_pollfds, libusb_pollfd,
-    },
+    SupportedBufferSize,
+    traits::{DeviceTrait, StreamTrait},
 };
-use sine_generator::{OUTPUT_DEVICE, STREAM_CONFIG, SineGenerator, note};
+use distortion::{Curve, Distortion, Oversampling};
+use eq::{Band, ParametricEq};
+use harmonics::PartialTable;
+use master_bus::MasterBus;
+use midi::MidiMessage;
+use midi_clock::{ClockFollower, SyncEvent};
+use midi_device::DeviceSelector;
+use midi_event_handler::InputEvent;
+use midi_map::{MidiMap, Param};
+use onset::BeatTracker;
+use parts::Parts;
+use pitch::Tuner;
+use ringbuf::traits::Producer;
+use rusb::Context;
+use sine_generator::{INPUT_DEVICE, OUTPUT_DEVICE, STREAM_CONFIG, SineGenerator};
+use spectrogram::{Spectrogram, SpectrogramConfig};
+use vocoder::PitchShifter;
 
-use libc::{ECHO, ICANON, STDERR_FILENO, TCSANOW, c_char, getchar, poll, pollfd};
+use libc::{ECHO, ICANON, STDERR_FILENO, TCSANOW, getchar};
 
 #[inline(always)]
-fn note_on(synth: Arc<RwLock<SineGenerator>>, n: u8, velocity: u8) {
+fn note_on(synth: Arc<RwLock<Parts>>, channel: u8, n: u8, velocity: u8) {
     let mut guard = synth.write().unwrap();
-    guard.note(n, velocity);
+    guard.note(channel, n, velocity);
 }
 
-#[inline(always)]
-fn vol(synth: Arc<RwLock<SineGenerator>>, volume: u8) {
-    let mut guard = synth.write().unwrap();
-    guard.update_volume(volume);
+/// Sets a parameter driven by a mapped controller
+fn set_param(
+    param: Param,
+    value: f32,
+    synth: &RwLock<Parts>,
+    bus: &RwLock<MasterBus>,
+    clock: &midi_output::MidiClock,
+) {
+    match param {
+        Param::Volume => synth.write().unwrap().set_volume(value),
+        Param::Tempo => clock.set_bpm(value as f64),
+        _ => {
+            let mut bus = bus.write().unwrap();
+            match param {
+                Param::ReverbWet => bus.reverb().into_iter().for_each(|r| r.set_wet(value)),
+                Param::ReverbDry => bus.reverb().into_iter().for_each(|r| r.set_dry(value)),
+                Param::DistortionDrive => {
+                    bus.distortion().iter_mut().for_each(|d| d.set_drive(value))
+                }
+                Param::DistortionMix => bus.distortion().iter_mut().for_each(|d| d.set_mix(value)),
+                Param::CompressorThreshold => bus.compressor().set_threshold(value),
+                Param::CompressorRatio => bus.compressor().set_ratio(value),
+                Param::EqGain(band) => bus
+                    .eq()
+                    .iter_mut()
+                    .filter(|eq| band < eq.len())
+                    .for_each(|eq| eq.set_gain(band, value)),
+                _ => (),
+            }
+        }
+    }
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let buf_sz = STREAM_CONFIG.buffer_size();
-    if let SupportedBufferSize::Range { min, max } = buf_sz {
-        println!("buffer size range: min: {min} max: {max}");
+/// Value following `name` on the command line
+fn arg(name: &str) -> Option<String> {
+    std::env::args().skip_while(|a| a != name).nth(1)
+}
+
+fn flag(name: &str) -> bool {
+    std::env::args().any(|a| a == name)
+}
+
+/// Master bus with its compressor set from `--comp-threshold`, `--comp-ratio`,
+/// `--comp-knee`, `--comp-attack`, `--comp-release` and `--comp-makeup` (dB
+/// and ms) and its limiter from `--ceiling` (dBFS). `--no-compressor` leaves
+/// only the limiter.
+fn master_bus(sample_rate: u32) -> Result<MasterBus, Box<dyn std::error::Error>> {
+    let mut bus = MasterBus::new(sample_rate);
+    let value = |name| arg(name).map(|v| v.parse::<f32>()).transpose();
+    let compressor = bus.compressor();
+    if let Some(db) = value("--comp-threshold")? {
+        compressor.set_threshold(db);
+    }
+    if let Some(ratio) = value("--comp-ratio")? {
+        compressor.set_ratio(ratio);
+    }
+    if let Some(db) = value("--comp-knee")? {
+        compressor.set_knee(db);
     }
+    if let Some(ms) = value("--comp-attack")? {
+        compressor.set_attack(ms);
+    }
+    if let Some(ms) = value("--comp-release")? {
+        compressor.set_release(ms);
+    }
+    if let Some(db) = value("--comp-makeup")? {
+        compressor.set_makeup(db);
+    }
+    if let Some(db) = value("--ceiling")? {
+        bus.limiter().set_ceiling(db);
+    }
+    bus.set_compress(!flag("--no-compressor"));
+    Ok(bus)
+}
 
-    let timeout = std::time::Duration::from_secs(10);
+/// Prints the pitch track of a recording, one line per 50ms
+fn tuner(path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let sample_rate = hound::WavReader::open(path)?.spec().sample_rate;
+    let hop = sample_rate as usize / 20;
+    for (time, pitch) in pitch::track_wav(path, hop)? {
+        match pitch {
+            Some(pitch) if pitch.clarity > 0.8 => println!("{time:7.3}s  {}", pitch.readout()),
+            _ => println!("{time:7.3}s  --"),
+        }
+    }
+    Ok(())
+}
 
-    let keyboard_device = rusb::devices()?
-        .iter()
-        .find(|device| {
-            let dev = device.open().unwrap();
-            let dd = device.device_descriptor().unwrap();
-            let language = dev.read_languages(timeout).unwrap()[0];
-            let product = dev.read_product_string(language, &dd, timeout).unwrap();
-            product.as_str() == "USB Keystation 49e"
+/// Lists USB-MIDI devices and ALSA raw MIDI ports with the indexes
+/// `--midi-device` accepts for each backend
+fn midi_devices() -> Result<(), Box<dyn std::error::Error>> {
+    match Context::new().and_then(|context| midi_device::discover(&context)) {
+        Ok(devices) if devices.is_empty() => println!("no USB-MIDI devices"),
+        Ok(devices) => {
+            println!("usb:");
+            devices
+                .iter()
+                .enumerate()
+                .for_each(|(idx, device)| println!("{idx}: {device}"));
+        }
+        Err(e) => println!("can't list USB devices: {e}"),
+    }
+    match alsa_midi::ports() {
+        Ok(ports) if ports.is_empty() => println!("no ALSA raw MIDI ports"),
+        Ok(ports) => {
+            println!("alsa:");
+            ports
+                .iter()
+                .enumerate()
+                .for_each(|(idx, port)| println!("{idx}: {port}"));
+        }
+        Err(e) => println!("can't list ALSA raw MIDI ports: {e}"),
+    }
+    Ok(())
+}
+
+/// MIDI config file, `--midi-config` or `midi.conf`
+fn midi_config() -> String {
+    arg("--midi-config").unwrap_or(midi_device::DEFAULT_CONFIG.to_string())
+}
+
+/// Devices named by `--midi-device`, else by `device` in the config file;
+/// `None` takes every device
+fn midi_selector() -> Option<DeviceSelector> {
+    arg("--midi-device")
+        .or_else(|| midi_device::config_value(midi_config(), "device"))
+        .and_then(|s| s.parse().ok())
+}
+
+/// Device named by `--midi-out`, else by `output` in the config file
+fn midi_output_selector() -> Option<DeviceSelector> {
+    arg("--midi-out")
+        .or_else(|| midi_device::config_value(midi_config(), "output"))
+        .and_then(|s| s.parse().ok())
+}
+
+/// Starts MIDI input from `--midi-backend` (or `backend` in the config
+/// file): `usb`, the default, or `alsa`. The output is on the same backend.
+fn watch_midi(events: mpsc::Sender<InputEvent>) -> Option<midi_event_handler::Watcher> {
+    let backend = arg("--midi-backend")
+        .or_else(|| midi_device::config_value(midi_config(), "backend"))
+        .unwrap_or("usb".to_string());
+    let (input, output) = (midi_selector(), midi_output_selector());
+    let watcher = match backend.as_str() {
+        "alsa" => midi_event_handler::watch_alsa(events, input, output).map_err(|e| e.to_string()),
+        _ => Context::new()
+            .and_then(|ctx| midi_event_handler::watch(&ctx, events, input, output))
+            .map_err(|e| e.to_string()),
+    };
+    watcher
+        .inspect_err(|e| println!("{backend} MIDI unavailable ({e}), MIDI input disabled"))
+        .ok()
+}
+
+/// Live recorder from `--bpm` (120), `--time-signature` (4/4), `--ppq`
+/// (480) and the `--punch-in`/`--punch-out` bars
+fn recorder() -> Result<smf::Recorder, Box<dyn std::error::Error>> {
+    let bpm = arg("--bpm").and_then(|b| b.parse().ok()).unwrap_or(120.);
+    let (numerator, denominator) = arg("--time-signature")
+        .and_then(|ts| {
+            let (n, d) = ts.split_once('/')?;
+            Some((n.parse().ok()?, d.parse().ok()?))
         })
-        .unwrap();
-    let interfaces = keyboard_device
-        .active_config_descriptor()?
-        .interfaces()
-        .for_each(|interface| {
-            interface.descriptors().for_each(|desc| {
-                println!(
-                    "iface: {}, endpoints: {}, endpoint descriptors: {:#?}",
-                    desc.interface_number(),
-                    desc.num_endpoints(),
-                    desc.endpoint_descriptors()
-                        .map(|ed| ed)
-                        .collect::<Vec<EndpointDescriptor>>(),
-                );
-            })
-        });
+        .unwrap_or((4, 4));
+    let ppq = arg("--ppq").and_then(|p| p.parse().ok()).unwrap_or(480);
+    let mut recorder = smf::Recorder::new(bpm, numerator, denominator, ppq);
+    let bar = |name| arg(name).and_then(|b| b.parse::<f64>().ok());
+    if let (Some(from), to) = (bar("--punch-in"), bar("--punch-out")) {
+        let to = to.unwrap_or(f64::MAX);
+        if from.is_nan() || to.is_nan() || from >= to {
+            return Err("--punch-in must come before --punch-out".into());
+        }
+        recorder.set_punch_bars(Some((from, to)));
+    }
+    Ok(recorder)
+}
+
+/// Writes a take to `--record <file>` (take.mid), numbering takes after
+/// the first
+fn save_take(take: &smf::Smf, count: usize) {
+    let path = arg("--record").unwrap_or("take.mid".to_string());
+    let path = match (count, path.rsplit_once('.')) {
+        (1, _) => path,
+        (n, Some((stem, ext))) => format!("{stem}-{n}.{ext}"),
+        (n, None) => format!("{path}-{n}"),
+    };
+    match take.save(&path) {
+        Ok(()) => println!("wrote {path}"),
+        Err(e) => println!("can't write {path}: {e}"),
+    }
+}
+
+/// Mono samples from `--wav <file>`, or an offline render of the notes in
+/// `--render 60,64,67`
+fn source() -> Result<(Vec<f32>, u32), Box<dyn std::error::Error>> {
+    if let Some(path) = arg("--wav") {
+        let wav = wav::Wav::read(&path)?;
+        if wav.is_empty() {
+            return Err(format!("{path} has no samples").into());
+        }
+        return Ok((wav.mono(), wav.sample_rate));
+    }
+    let notes = arg("--render").ok_or("expected --wav <file> or --render <notes>")?;
+    let sample_rate = arg("--sample-rate").map_or(Ok(48000), |sr| sr.parse())?;
+    let mut generator = SineGenerator::with_sample_rate(sample_rate);
+    if let Some(path) = arg("--partials") {
+        generator.set_partials(&PartialTable::load(path)?);
+    }
+    for n in notes.split(',') {
+        let note: u8 = n.trim().parse()?;
+        if note > 127 {
+            return Err(format!("note {note} is out of MIDI range").into());
+        }
+        generator.note(note, 100);
+    }
+    let seconds = arg("--seconds").map_or(Ok(2.), |s| s.parse())?;
+    Ok((generator.render(seconds), sample_rate))
+}
+
+fn spectrogram(out: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let (samples, sample_rate) = source()?;
+    let defaults = SpectrogramConfig::default();
+    let config = SpectrogramConfig {
+        fft_size: arg("--fft").map_or(Ok(defaults.fft_size), |n| n.parse())?,
+        hop: arg("--hop").map_or(Ok(defaults.hop), |n| n.parse())?,
+        window: arg("--window").map_or(Ok(defaults.window), |w| w.parse())?,
+        log_freq: flag("--log"),
+        range_db: arg("--range").map_or(Ok(defaults.range_db), |db| db.parse())?,
+        height: arg("--height").map_or(Ok(defaults.height), |h| h.parse())?,
+    };
+    if !config.fft_size.is_power_of_two() {
+        return Err("--fft must be a power of two".into());
+    }
+    if config.hop == 0 {
+        return Err("--hop must be positive".into());
+    }
+    Spectrogram::new(&samples, sample_rate, config).save(out)?;
+    println!("wrote {out}");
+    Ok(())
+}
 
-    println!("interfaces: {:#?}", interfaces);
+/// Measures the partials of a sustained note and writes them as a table
+/// for `--partials`
+fn harmonics(path: &str, out: Option<&String>) -> Result<(), Box<dyn std::error::Error>> {
+    let wav = wav::Wav::read(path)?;
+    let count = arg("--harmonics").map_or(Ok(16), |n| n.parse())?;
+    let table = harmonics::analyze(&wav.mono(), wav.sample_rate, count)
+        .ok_or("no stable fundamental found")?;
+    print!("{table}");
+    if let Some(out) = out.filter(|o| !o.starts_with("--")) {
+        table.save(out)?;
+        println!("wrote {out}");
+    }
+    Ok(())
+}
+
+/// Prints onsets and tempo of `--wav` or `--render` input, and writes the
+/// onset (or with `--beats`, beat) times for the interpreter's `--onsets`
+fn onsets(out: Option<&String>) -> Result<(), Box<dyn std::error::Error>> {
+    let (samples, sample_rate) = source()?;
+    let method = arg("--method").map_or(Ok(onset::Method::SpectralFlux), |m| m.parse())?;
+    let picker = onset::PeakPicker {
+        delta: arg("--delta").map_or(Ok(0.5), |d| d.parse())?,
+        ..Default::default()
+    };
+    let rhythm = onset::analyze(&samples, sample_rate, method, &picker);
+    rhythm
+        .onsets
+        .iter()
+        .for_each(|time| println!("onset {time:8.3}s"));
+    match rhythm.bpm {
+        Some(bpm) => println!("tempo {bpm:.1} BPM, {} beats", rhythm.beats.len()),
+        None => println!("no tempo found"),
+    }
+    if let Some(out) = out.filter(|o| !o.starts_with("--")) {
+        let times = if flag("--beats") {
+            &rhythm.beats
+        } else {
+            &rhythm.onsets
+        };
+        onset::write_timestamps(out, times)?;
+        println!("wrote {out}");
+    }
+    Ok(())
+}
+
+/// Offline time-stretch (`--factor`) or pitch shift (`--semitones`) of each
+/// channel of a WAV file
+fn vocode(input: &str, out: &str, shift: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let wav = wav::Wav::read(input)?;
+    let channels = if shift {
+        let semitones: f32 = arg("--semitones")
+            .ok_or("expected --semitones <n>")?
+            .parse()?;
+        wav.channels
+            .iter()
+            .map(|c| vocoder::pitch_shift(c, semitones))
+            .collect()
+    } else {
+        let factor: f32 = arg("--factor").ok_or("expected --factor <n>")?.parse()?;
+        if !factor.is_finite() || factor <= 0. {
+            return Err("--factor must be positive".into());
+        }
+        wav.channels
+            .iter()
+            .map(|c| vocoder::time_stretch(c, factor))
+            .collect()
+    };
+    let out_wav = wav::Wav::new(channels, wav.sample_rate);
+    out_wav.write(out)?;
+    println!("wrote {out} ({:.2}s)", out_wav.duration());
+    Ok(())
+}
+
+/// Sinusoidal-plus-noise analysis of a WAV file, edited and resynthesised
+fn sms(input: &str, out: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let wav = wav::Wav::read(input)?;
+    let config = sms::SmsConfig {
+        max_peaks: arg("--peaks").map_or(Ok(60), |n| n.parse())?,
+        min_db: arg("--min-db").map_or(Ok(-70.), |db| db.parse())?,
+        ..Default::default()
+    };
+    let mut model = sms::SmsModel::analyze(&wav.mono(), wav.sample_rate, &config);
+    println!(
+        "{} partial tracks over {} frames",
+        model.tracks.len(),
+        model.frames
+    );
+
+    if let Some(other) = arg("--morph") {
+        let other = wav::Wav::read(other)?;
+        let other = sms::SmsModel::analyze(&other.mono(), other.sample_rate, &config);
+        model = model.morph(&other, arg("--amount").map_or(Ok(0.5), |a| a.parse())?)?;
+    }
+    if let Some(count) = arg("--keep") {
+        model.keep_loudest(count.parse()?);
+    }
+    if let Some(gain) = arg("--noise-gain") {
+        model.scale_noise(gain.parse()?);
+    }
+    let stretch = arg("--stretch").map_or(Ok(1.), |f| f.parse())?;
+    let transpose = arg("--transpose").map_or(Ok(0.), |st| st.parse())?;
+    let samples = if flag("--residual") {
+        model.synthesize_noise(sms::stretched_hop(stretch))
+    } else {
+        model.synthesize(stretch, vocoder::ratio(transpose), !flag("--no-noise"))
+    };
+
+    let out_wav = wav::Wav::new(vec![samples], wav.sample_rate);
+    out_wav.write(out)?;
+    println!("wrote {out} ({:.2}s)", out_wav.duration());
+    Ok(())
+}
+
+/// Transcribes `--wav` or `--render` input to notes, or with `--eval`
+/// renders a known piece with the synth (and `--partials`) and scores the
+/// transcription against it. Writes an SMF, or a note list for `.txt`.
+fn transcribe(out: Option<&String>) -> Result<(), Box<dyn std::error::Error>> {
+    let config = transcribe::TranscribeConfig {
+        max_polyphony: arg("--polyphony").map_or(Ok(6), |n| n.parse())?,
+        relative_threshold: arg("--threshold").map_or(Ok(0.15), |t| t.parse())?,
+        ..Default::default()
+    };
+    let notes = if flag("--eval") {
+        let sample_rate = arg("--sample-rate").map_or(Ok(48000), |sr| sr.parse())?;
+        let mut generator = SineGenerator::with_sample_rate(sample_rate);
+        if let Some(path) = arg("--partials") {
+            generator.set_partials(&PartialTable::load(path)?);
+        }
+        let reference = transcribe::test_piece();
+        let samples = transcribe::render(&reference, sample_rate, &mut generator);
+        let notes = transcribe::transcribe(&samples, sample_rate, &config);
+        let score = transcribe::evaluate(&reference, &notes, 0.05);
+        println!(
+            "{} reference notes, {} transcribed: precision {:.2} recall {:.2} F {:.2}, \
+             mean onset error {:.1}ms",
+            reference.len(),
+            notes.len(),
+            score.precision,
+            score.recall,
+            score.f_measure,
+            score.onset_error * 1000.
+        );
+        notes
+    } else {
+        let (samples, sample_rate) = source()?;
+        transcribe::transcribe(&samples, sample_rate, &config)
+    };
+    notes
+        .iter()
+        .for_each(|note| println!("{}", transcribe::describe(note)));
+
+    if let Some(out) = out.filter(|o| !o.starts_with("--")) {
+        if out.ends_with(".txt") {
+            std::fs::write(out, smf::note_list(&notes))?;
+        } else {
+            let bpm = arg("--bpm").map_or(Ok(120.), |b| b.parse())?;
+            let ppq = arg("--ppq").map_or(Ok(480), |p| p.parse())?;
+            smf::Smf::from_notes(&notes, bpm, ppq).save(out)?;
+        }
+        println!("wrote {out}");
+    }
+    Ok(())
+}
+
+/// Plays a Standard MIDI File through the synth, with space to play or
+/// pause, `s` to stop, `l` to loop, `+`/`-` for tempo, `,`/`.` to seek and
+/// `q` to quit. With `--out` it renders the file to a WAV instead.
+fn play(path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let file = smf::Smf::load(path)?;
+    let track = arg("--track").map(|t| t.parse()).transpose()?;
+    let sequence = file.sequence(track);
+    println!(
+        "{path}: format {}, {} tracks, {} events, {:.1}s",
+        file.format,
+        file.tracks.len(),
+        sequence.events.len(),
+        sequence.length
+    );
+    let partials = arg("--partials").map(PartialTable::load).transpose()?;
+    let setup = |player: &mut smf::SmfPlayer, generator: &mut SineGenerator| {
+        if let Some(table) = &partials {
+            generator.set_partials(table);
+        }
+        player.apply(smf::Transport::Loop(flag("--loop")));
+        player.apply(smf::Transport::TempoScale(
+            arg("--tempo").map_or(Ok(1.), |t| t.parse())?,
+        ));
+        player.apply(smf::Transport::Play);
+        Ok::<_, Box<dyn std::error::Error>>(())
+    };
+
+    if let Some(out) = arg("--out") {
+        let sample_rate = arg("--sample-rate").map_or(Ok(48000), |sr| sr.parse())?;
+        let mut generator = SineGenerator::with_sample_rate(sample_rate);
+        let mut player = smf::SmfPlayer::new(sequence, sample_rate);
+        setup(&mut player, &mut generator)?;
+        let mut parts = Parts::new(generator);
+        player.apply(smf::Transport::Loop(false));
+        let mut bus = master_bus(sample_rate)?;
+        let mut channels = [Vec::new(), Vec::new()];
+        // one pass, then a second for the releases
+        let mut tail = 0;
+        while tail < sample_rate {
+            player.tick(|bytes| {
+                if let Some(message) = MidiMessage::from_bytes(bytes) {
+                    parts.handle(&message)
+                }
+            });
+            let stereo = bus.process_frame(parts.next_frame());
+            channels[0].push(stereo[0]);
+            channels[1].push(stereo[1]);
+            if player.is_finished() {
+                tail += 1;
+            }
+        }
+        let wav = wav::Wav::new(channels.into(), sample_rate);
+        wav.write(&out)?;
+        println!("wrote {out} ({:.2}s)", wav.duration());
+        return Ok(());
+    }
+
+    let sample_rate = STREAM_CONFIG.sample_rate();
+    let mut generator = SineGenerator::default(STREAM_CONFIG.clone());
+    let mut player = smf::SmfPlayer::new(sequence, sample_rate);
+    setup(&mut player, &mut generator)?;
+    let mut parts = Parts::new(generator);
+    let player = Arc::new(Mutex::new(player));
+    let mut bus = master_bus(sample_rate)?;
+    let config = OUTPUT_DEVICE.default_output_config()?.config();
+    let channels = config.channels as usize;
+    let stream_player = player.clone();
+    let stream = OUTPUT_DEVICE.build_output_stream::<f32, _, _>(
+        &config,
+        move |data: &mut [f32], _cb_info| {
+            let mut player = stream_player.lock().unwrap();
+            data.chunks_mut(channels).for_each(|frame| {
+                player.tick(|bytes| {
+                    if let Some(message) = MidiMessage::from_bytes(bytes) {
+                        parts.handle(&message)
+                    }
+                });
+                let stereo = bus.process_frame(parts.next_frame());
+                frame
+                    .iter_mut()
+                    .enumerate()
+                    .for_each(|(idx, s)| *s = stereo[idx % 2]);
+            });
+        },
+        |e| {
+            println!("{e}");
+        },
+        None,
+    )?;
+    stream.play()?;
+
+    let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+    unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) };
+    let original = termios;
+    termios.c_lflag &= !(ICANON | ECHO);
+    unsafe { libc::tcsetattr(libc::STDIN_FILENO, TCSANOW, &termios) };
+
+    let (ktx, krx) = mpsc::channel::<char>();
+    thread::spawn(move || {
+        loop {
+            let key = unsafe { getchar() };
+            if ktx.send(char::from_u32(key as u32).unwrap_or('q')).is_err() {
+                break;
+            }
+        }
+    });
+
+    loop {
+        if let Ok(key) = krx.recv_timeout(Duration::from_millis(250)) {
+            let mut player = player.lock().unwrap();
+            let position = player.position();
+            let transport = match key {
+                ' ' if player.is_playing() => smf::Transport::Pause,
+                ' ' => smf::Transport::Play,
+                's' => smf::Transport::Stop,
+                'l' => smf::Transport::Loop(!player.is_looping()),
+                '+' => smf::Transport::TempoScale(player.tempo_scale() * 1.05),
+                '-' => smf::Transport::TempoScale(player.tempo_scale() / 1.05),
+                ',' => smf::Transport::Seek(position - 5.),
+                '.' => smf::Transport::Seek(position + 5.),
+                'q' => break,
+                _ => continue,
+            };
+            player.apply(transport);
+            let bar = match player.sequence().bar_beat(player.position()) {
+                Some((bar, beat)) => format!("bar {bar} beat {beat:.1}"),
+                None => String::new(),
+            };
+            println!(
+                "{transport:?}: {:.1}s {bar}, tempo x{:.2}{}",
+                player.position(),
+                player.tempo_scale(),
+                if player.is_looping() { ", looping" } else { "" }
+            );
+        }
+        if player.lock().unwrap().is_finished() {
+            println!("end of {path}");
+            break;
+        }
+    }
+    // let the last notes ring out
+    thread::sleep(Duration::from_millis(500));
+    unsafe { libc::tcsetattr(libc::STDIN_FILENO, TCSANOW, &original) };
+    Ok(())
+}
+
+/// Bands given to `name` as comma-separated `kind:freq[:gain_db[:q]]`
+fn eq_bands(name: &str) -> Result<Option<Vec<Band>>, String> {
+    arg(name)
+        .map(|bands| bands.split(',').map(str::parse).collect())
+        .transpose()
+}
+
+/// Microphone input feeding the tuner and beat tracker, which run while
+/// `tuning` and `tracking` are set
+fn input_stream(
+    input: &cpal::Device,
+    tuning: Arc<AtomicBool>,
+    tracking: Arc<AtomicBool>,
+) -> Result<cpal::Stream, Box<dyn std::error::Error>> {
+    let config = input.default_input_config()?;
+    let (tuner, mut input_tap) = Tuner::new(config.sample_rate());
+    let _tuner_handle = tuner.spawn(tuning);
+    let (beat_tracker, mut beat_tap) =
+        BeatTracker::new(config.sample_rate(), onset::Method::SpectralFlux);
+    let _beat_handle = beat_tracker.spawn(tracking);
+    let channels = config.channels() as usize;
+    let stream = input.build_input_stream::<f32, _, _>(
+        &config.config(),
+        move |data: &[f32], _cb_info| {
+            data.chunks(channels).for_each(|frame| {
+                let mono = frame.iter().sum::<f32>() / channels as f32;
+                let _ = input_tap.try_push(mono);
+                let _ = beat_tap.try_push(mono);
+            });
+        },
+        |e| {
+            println!("{e}");
+        },
+        None,
+    )?;
+    stream.play()?;
+    Ok(stream)
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("tuner") => {
+            return tuner(args.get(2).ok_or("usage: sound-studies tuner <file.wav>")?);
+        }
+        Some("harmonics") => {
+            return harmonics(
+                args.get(2)
+                    .ok_or("usage: sound-studies harmonics <file.wav> [out.txt]")?,
+                args.get(3),
+            );
+        }
+        Some("onsets") => return onsets(args.get(2)),
+        Some("transcribe") => return transcribe(args.get(2)),
+        Some(cmd @ ("stretch" | "shift")) => {
+            let usage = "usage: sound-studies stretch|shift <in.wav> <out.wav> \
+                         [--factor n | --semitones n]";
+            return vocode(
+                args.get(2).ok_or(usage)?,
+                args.get(3).ok_or(usage)?,
+                cmd == "shift",
+            );
+        }
+        Some("sms") => {
+            let usage = "usage: sound-studies sms <in.wav> <out.wav> [--stretch n] \
+                         [--transpose n] [--morph other.wav --amount n] [--keep n] \
+                         [--no-noise | --residual]";
+            return sms(args.get(2).ok_or(usage)?, args.get(3).ok_or(usage)?);
+        }
+        Some("midi-devices") => return midi_devices(),
+        Some("play") => {
+            return play(args.get(2).ok_or(
+                "usage: sound-studies play <file.mid> [--out out.wav] [--loop] [--tempo n] \
+                 [--track n] [--partials table.txt]",
+            )?);
+        }
+        Some("spectrogram") => {
+            return spectrogram(
+                args.get(2)
+                    .ok_or("usage: sound-studies spectrogram <out.png|out.ppm> [options]")?,
+            );
+        }
+        _ => (),
+    }
+
+    let buf_sz = STREAM_CONFIG.buffer_size();
+    if let SupportedBufferSize::Range { min, max } = buf_sz {
+        println!("buffer size range: min: {min} max: {max}");
+    }
 
     println!("\n\n\n{:#?}", OUTPUT_DEVICE.default_output_config());
 
-    let sound = Arc::new(RwLock::new(SineGenerator::default(STREAM_CONFIG.clone())));
+    let distortion = match arg("--distortion") {
+        Some(curve) => {
+            let mut curve = curve.parse::<Curve>()?;
+            if let Curve::BitCrush { bits, hold } = &mut curve {
+                *bits = arg("--bits").map_or(Ok(*bits), |b| b.parse())?;
+                *hold = arg("--hold").map_or(Ok(*hold), |h| h.parse())?;
+            }
+            let oversampling =
+                Oversampling::try_from(arg("--oversample").map_or(Ok(4), |f| f.parse())?)?;
+            Some(
+                Distortion::new(curve, oversampling)
+                    .drive(arg("--drive").map_or(Ok(0.), |db| db.parse())?),
+            )
+        }
+        None if flag("--soft-clip") => Some(Distortion::soft_clip()),
+        None => None,
+    };
+
+    let sample_rate = STREAM_CONFIG.sample_rate();
+    let mut builder = SineGenerator::default(STREAM_CONFIG.clone()).build();
+    if let Some(bands) = eq_bands("--voice-eq")? {
+        builder = builder.eq(bands
+            .into_iter()
+            .fold(ParametricEq::new(sample_rate), ParametricEq::band));
+    }
+    let mut bus_distortion = None;
+    match distortion {
+        Some(distortion) if flag("--voice-distortion") => {
+            builder = builder.voice_distortion(distortion);
+        }
+        distortion => bus_distortion = distortion,
+    }
+    let mut generator = builder.finish();
+    if let Some(path) = arg("--partials") {
+        generator.set_partials(&PartialTable::load(&path)?);
+    }
+    // one part per MIDI channel, each starting as this generator
+    let sound = Arc::new(RwLock::new(Parts::new(generator)));
     // .build()
     // .freq(A4)
     // .partial(A4, 2, 10.)
@@ -105,17 +777,61 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     // .finish();
     // let sound_clone = sound;
 
+    let mut master_bus = master_bus(sample_rate)?;
+    eq_bands("--eq")?.into_iter().flatten().for_each(|band| {
+        master_bus.eq().iter_mut().for_each(|eq| {
+            eq.add_band(band);
+        });
+    });
+    if let Some(eq) = master_bus.eq().first().filter(|eq| !eq.is_empty()) {
+        for (freq, db) in eq.magnitude_response(31.25, 16000., 10) {
+            println!("eq {freq:7.0} Hz {db:+6.1} dB");
+        }
+    }
+    master_bus.set_distortion(bus_distortion);
+    if let Some(path) = arg("--ir") {
+        let mut ir = ImpulseResponse::from_wav(&path, sample_rate)?.trim_silence(-60.);
+        if let Some(length) = arg("--ir-length") {
+            ir = ir.trim(0., Some(length.parse()?));
+        }
+        if let Some(factor) = arg("--ir-stretch") {
+            ir = ir.stretch(factor.parse()?);
+        }
+        if ir.is_empty() {
+            return Err(format!("{path} is silent").into());
+        }
+        println!("impulse response: {path} ({} samples)", ir.len());
+        let reverb = Reverb::new(&ir, 256)
+            .wet(arg("--wet").map_or(Ok(0.3), |w| w.parse())?)
+            .pre_delay(arg("--pre-delay").map_or(Ok(0.), |ms| ms.parse())?);
+        master_bus.set_reverb(Some(reverb));
+    }
+    if let Some(semitones) = arg("--shift") {
+        master_bus.set_shifter(Some(PitchShifter::new(semitones.parse()?)?));
+    }
+    let bus = Arc::new(RwLock::new(master_bus));
+
+    let (analyzer, mut tap) = Analyzer::new(sample_rate);
+    let analyzing = Arc::new(AtomicBool::new(false));
+    let _analyzer_handle = analyzer.spawn(analyzing.clone());
+
+    let config = OUTPUT_DEVICE.default_output_config()?.config();
+    let channels = config.channels as usize;
     let sound_iter = sound.clone();
+    let bus_iter = bus.clone();
     // println!("sound_iter: {sound_iter:#?}");
     let os = OUTPUT_DEVICE.build_output_stream::<f32, _, _>(
-        &OUTPUT_DEVICE.default_output_config()?.config(),
-        move |data, _cb_info| {
-            data.into_iter().for_each(|s| {
+        &config,
+        move |data: &mut [f32], _cb_info| {
+            let mut bus = bus_iter.write().unwrap();
+            data.chunks_mut(channels).for_each(|frame| {
                 let mut guard = sound_iter.write().unwrap();
-                let vol = guard.volume();
-                let next = guard.next().unwrap();
-                // println!("next: {next}");
-                *s = next * vol;
+                let stereo = bus.process_frame(guard.next_frame());
+                let _ = tap.try_push((stereo[0] + stereo[1]) * 0.5);
+                frame
+                    .iter_mut()
+                    .enumerate()
+                    .for_each(|(idx, s)| *s = stereo[idx % 2]);
             });
         },
         |e| {
@@ -126,6 +842,22 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     os.pause()?;
 
+    let tuning = Arc::new(AtomicBool::new(false));
+    let tracking = Arc::new(AtomicBool::new(false));
+    let input_stream = match INPUT_DEVICE.as_ref() {
+        Some(input) => match input_stream(input, tuning.clone(), tracking.clone()) {
+            Ok(stream) => Some(stream),
+            Err(e) => {
+                println!("can't open input ({e}), tuner and beat tracker disabled");
+                None
+            }
+        },
+        None => {
+            println!("no input device, tuner and beat tracker disabled");
+            None
+        }
+    };
+
     let mut stdin = unsafe { File::from_raw_fd(libc::STDIN_FILENO) };
 
     let mut termios: libc::termios = unsafe { std::mem::zeroed() };
@@ -134,36 +866,19 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         libc::tcgetattr(libc::STDIN_FILENO, &mut termios);
     }
 
-    let mut original_termios = termios.clone();
+    let original_termios = termios;
 
     termios.c_lflag &= !(ICANON | ECHO);
 
     unsafe {
-        let _ = libc::tcsetattr(STDERR_FILENO, TCSANOW, &mut termios);
+        let _ = libc::tcsetattr(STDERR_FILENO, TCSANOW, &termios);
     }
 
-    let (tx, rx) = mpsc::channel::<Vec<u8>>();
+    let (tx, rx) = mpsc::channel::<InputEvent>();
 
-    let thread_handle = thread::spawn(move || {
-        let keyboard = keyboard_device.open().unwrap();
-        keyboard.claim_interface(1).unwrap();
-        loop {
-            let mut buf = [0_u8; 64];
-            if let Ok(size) =
-                keyboard.read_interrupt(129_u8, &mut buf.as_mut_slice(), Duration::from_millis(100))
-            {
-                println!(
-                    "{}\t",
-                    buf.iter()
-                        .map(|b| format!("{b:02x}"))
-                        .collect::<Vec<String>>()
-                        .join(" ")
-                );
-
-                tx.send(buf[..size].to_vec()).unwrap();
-            }
-        }
-    });
+    // USB devices are opened as they're plugged in; without MIDI the synth
+    // still runs from the keyboard
+    let _midi_watcher = watch_midi(tx);
 
     let (ktx, krx) = mpsc::channel::<char>();
 
@@ -178,25 +893,126 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     });
 
+    let mut recorder = recorder()?;
+    let mut takes = 0;
+
+    // `--midi-thru` echoes what's played to the output
+    let midi_out: midi_output::SharedOutput = Arc::new(Mutex::new(None));
+    let clock = midi_output::MidiClock::new(midi_out.clone(), recorder.bpm());
+    let mut thru = flag("--midi-thru");
+    // `--midi-sync` slaves our clock to the incoming one
+    let sync = flag("--midi-sync");
+    let mut follower = ClockFollower::new();
+    let mut followed_bpm = clock.bpm();
+
+    // controller mappings from `--midi-map`, saved again after MIDI learn
+    let map_path = arg("--midi-map").unwrap_or(midi_map::DEFAULT_MAP.to_string());
+    let mut midi_map = match MidiMap::load(&map_path) {
+        Ok(map) => map,
+        Err(e) => {
+            if std::path::Path::new(&map_path).exists() {
+                println!("{map_path}: {e}");
+            }
+            MidiMap::default()
+        }
+    };
+    let save_map = |map: &MidiMap| {
+        if let Err(e) = map.save(&map_path) {
+            println!("can't write {map_path}: {e}");
+        }
+    };
+
     os.play()?;
 
     loop {
-        if let Ok(buf) = rx.try_recv() {
-            println!("buf: {buf:?}");
-
-            match buf[0] {
-                0xb => {
-                    println!("vol: {}", buf[3]);
-                    let v = buf[3];
-                    vol(sound.clone(), v);
+        // everything that came in since the last pass
+        while let Ok(event) = rx.try_recv() {
+            match event {
+                InputEvent::Arrived(device) => println!("MIDI device connected: {device}"),
+                InputEvent::Left(device) => {
+                    println!("MIDI device disconnected: {device}");
+                    let mut out = midi_out.lock().unwrap();
+                    if out.as_ref().is_some_and(|out| out.name() == device) {
+                        *out = None;
+                    }
                 }
-                0x9 => {
-                    let n = buf[2];
-                    let velocity = buf[3];
-                    println!("note: {}  velocity: {}", n, velocity);
-                    note_on(sound.clone(), n, velocity);
+                InputEvent::Output(out) => {
+                    println!("MIDI output: {}", out.name());
+                    *midi_out.lock().unwrap() = Some(out);
+                }
+                InputEvent::Midi(event, time) => {
+                    if event.message != MidiMessage::Clock {
+                        println!("cable {}: {:?}", event.cable, event.message);
+                    }
+                    if sync {
+                        match follower.handle(&event.message, time) {
+                            Some(SyncEvent::Start) => clock.start(),
+                            Some(SyncEvent::Continue) => clock.resume(),
+                            Some(SyncEvent::Stop) => clock.stop(),
+                            Some(SyncEvent::Locate(beats)) => {
+                                println!("song position: {beats} beats");
+                                midi_output::send(&midi_out, &event.message);
+                            }
+                            None => (),
+                        }
+                        if follower.is_running()
+                            && let Some(bpm) = follower.bpm()
+                            && (bpm - followed_bpm).abs() >= 0.1
+                        {
+                            followed_bpm = bpm;
+                            clock.set_bpm(bpm);
+                            println!("following {bpm:.1} BPM");
+                        }
+                    }
+                    // we send our own clock, so realtime stops here
+                    if thru && !event.message.is_realtime() {
+                        midi_output::send(&midi_out, &event.message);
+                    }
+                    recorder.record(time, &event.message.to_bytes());
+
+                    match event.message {
+                        MidiMessage::ControlChange {
+                            channel,
+                            controller,
+                            value,
+                        } => {
+                            let learning = midi_map.learning();
+                            let values = midi_map.control_change(channel, controller, value);
+                            // unmapped controllers go to the channel's part
+                            if values.is_empty() {
+                                sound.write().unwrap().handle(&event.message);
+                            }
+                            values.into_iter().for_each(|(param, value)| {
+                                println!("{param}: {value:.2}");
+                                set_param(param, value, &sound, &bus, &clock);
+                            });
+                            if let Some(param) = learning {
+                                println!(
+                                    "{param} learned: channel {} CC {controller}",
+                                    channel + 1
+                                );
+                                save_map(&midi_map);
+                            }
+                        }
+                        MidiMessage::NoteOn {
+                            channel,
+                            note,
+                            velocity,
+                        } => {
+                            println!("note: {note}  velocity: {velocity}");
+                            note_on(sound.clone(), channel, note, velocity);
+                        }
+                        MidiMessage::NoteOff { channel, note, .. } => {
+                            note_on(sound.clone(), channel, note, 0)
+                        }
+                        MidiMessage::ProgramChange { channel, program } => {
+                            sound.write().unwrap().handle(&event.message);
+                            let patch = patch::Patch::program(program);
+                            println!("part {}: program {program} ({})", channel + 1, patch.name);
+                        }
+                        _ => sound.write().unwrap().handle(&event.message),
+                    }
                 }
-                _ => (),
             }
         }
 
@@ -210,6 +1026,85 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     println!("stop");
                     os.pause().unwrap()
                 }
+                'a' => {
+                    let on = !analyzing.load(Ordering::Relaxed);
+                    analyzing.store(on, Ordering::Relaxed);
+                    println!("analyzer {}", if on { "on" } else { "off" });
+                }
+                't' if input_stream.is_some() => {
+                    let on = !tuning.load(Ordering::Relaxed);
+                    tuning.store(on, Ordering::Relaxed);
+                    println!("\ntuner {}", if on { "on" } else { "off" });
+                }
+                'b' if input_stream.is_some() => {
+                    let on = !tracking.load(Ordering::Relaxed);
+                    tracking.store(on, Ordering::Relaxed);
+                    println!("\nbeat tracker {}", if on { "on" } else { "off" });
+                }
+                'm' => {
+                    let (comp, lim) = bus.read().unwrap().gain_reduction_db();
+                    println!("gain reduction: compressor {comp:.1} dB  limiter {lim:.1} dB");
+                }
+                'r' if !recorder.is_recording() => {
+                    recorder.start(Instant::now());
+                    println!("recording");
+                }
+                'r' => {
+                    let now = Instant::now();
+                    let (bar, beat) = recorder.bar_beat(now);
+                    if recorder.is_punched_in() {
+                        recorder.punch_out(now);
+                        println!("punch out at {bar}:{beat:.2}");
+                    } else {
+                        recorder.punch_in();
+                        println!("punch in at {bar}:{beat:.2}");
+                    }
+                }
+                'w' if recorder.is_recording() => {
+                    takes += 1;
+                    save_take(&recorder.stop(Instant::now()), takes);
+                }
+                'h' => {
+                    thru = !thru;
+                    println!("MIDI thru {}", if thru { "on" } else { "off" });
+                }
+                'c' if clock.is_running() => {
+                    clock.stop();
+                    println!("MIDI clock stopped");
+                }
+                'c' => {
+                    clock.start();
+                    println!("MIDI clock started at {:.1} BPM", clock.bpm());
+                }
+                'l' => {
+                    // each press offers the next parameter, then learn is off
+                    let next = match midi_map.learning() {
+                        None => Some(Param::LEARNABLE[0]),
+                        Some(param) => Param::LEARNABLE
+                            .iter()
+                            .position(|p| *p == param)
+                            .and_then(|idx| Param::LEARNABLE.get(idx + 1))
+                            .copied(),
+                    };
+                    match next {
+                        Some(param) => {
+                            midi_map.learn(param);
+                            println!("learn {param}: move a control ('x' unbinds it)");
+                        }
+                        None => {
+                            midi_map.cancel_learn();
+                            println!("learn off");
+                        }
+                    }
+                }
+                'x' => {
+                    if let Some(param) = midi_map.learning() {
+                        midi_map.cancel_learn();
+                        midi_map.unbind(param);
+                        println!("{param} unbound");
+                        save_map(&midi_map);
+                    }
+                }
                 'q' => break,
                 _ => {
                     // input.clear();
@@ -222,9 +1117,16 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         thread::sleep(Duration::from_millis(1));
     }
 
+    if recorder.is_recording() {
+        save_take(&recorder.stop(Instant::now()), takes + 1);
+    }
+    if clock.is_running() {
+        clock.stop();
+    }
+
     stdin.flush()?;
     unsafe {
-        let _ = libc::tcsetattr(STDERR_FILENO, TCSANOW, &mut original_termios);
+        let _ = libc::tcsetattr(STDERR_FILENO, TCSANOW, &original_termios);
     }
 
     Ok(())
@@ -0,0 +1,162 @@
+use crate::{
+    convolver::Reverb,
+    distortion::Distortion,
+    dynamics::{Compressor, Limiter},
+    eq::ParametricEq,
+    vocoder::PitchShifter,
+};
+
+/// Processing applied to the stereo mix before it reaches the device.
+/// The shifter, EQ and distortion run once per side.
+pub struct MasterBus {
+    shifter: Option<[PitchShifter; 2]>,
+    eq: [ParametricEq; 2],
+    distortion: Option<[Distortion; 2]>,
+    reverb: Option<Reverb>,
+    compressor: Compressor,
+    limiter: Limiter,
+    compress: bool,
+}
+
+impl MasterBus {
+    pub fn new(sample_rate: u32) -> Self {
+        let eq = ParametricEq::new(sample_rate);
+        let compressor = Compressor::new(sample_rate);
+        let limiter = Limiter::new(sample_rate, 5., 80., -0.3);
+
+        Self {
+            shifter: None,
+            eq: [eq.clone(), eq],
+            distortion: None,
+            reverb: None,
+            compressor,
+            limiter,
+            compress: true,
+        }
+    }
+
+    pub fn set_shifter(&mut self, shifter: Option<PitchShifter>) {
+        self.shifter = shifter.map(|s| [s.clone(), s]);
+    }
+
+    /// Left and right, to be changed alike
+    pub fn eq(&mut self) -> &mut [ParametricEq] {
+        &mut self.eq
+    }
+
+    pub fn set_distortion(&mut self, distortion: Option<Distortion>) {
+        self.distortion = distortion.map(|d| [d.clone(), d]);
+    }
+
+    /// Left and right, or none
+    pub fn distortion(&mut self) -> &mut [Distortion] {
+        self.distortion.as_mut().map_or(&mut [], |d| &mut d[..])
+    }
+
+    pub fn set_reverb(&mut self, reverb: Option<Reverb>) {
+        self.reverb = reverb;
+    }
+
+    pub fn reverb(&mut self) -> Option<&mut Reverb> {
+        self.reverb.as_mut()
+    }
+
+    pub fn compressor(&mut self) -> &mut Compressor {
+        &mut self.compressor
+    }
+
+    pub fn limiter(&mut self) -> &mut Limiter {
+        &mut self.limiter
+    }
+
+    pub fn set_compress(&mut self, compress: bool) {
+        self.compress = compress;
+    }
+
+    pub fn gain_reduction_db(&self) -> (f32, f32) {
+        (
+            self.compressor.gain_reduction_db(),
+            self.limiter.gain_reduction_db(),
+        )
+    }
+
+    /// Takes a stereo mix, such as panned parts
+    pub fn process_frame(&mut self, frame: [f32; 2]) -> [f32; 2] {
+        let mut frame = frame;
+        for (ch, x) in frame.iter_mut().enumerate() {
+            if let Some(shifter) = self.shifter.as_mut() {
+                *x = shifter[ch].process(*x);
+            }
+            *x = self.eq[ch].process(*x);
+            if let Some(distortion) = self.distortion.as_mut() {
+                *x = distortion[ch].process(*x);
+            }
+        }
+        let frame = match self.reverb.as_mut() {
+            Some(reverb) => reverb.process_frame(frame),
+            None => frame,
+        };
+        self.dynamics(frame)
+    }
+
+    fn dynamics(&mut self, frame: [f32; 2]) -> [f32; 2] {
+        let frame = if self.compress {
+            let gain = self.compressor.gain(frame[0].abs().max(frame[1].abs()));
+            frame.map(|s| s * gain)
+        } else {
+            frame
+        };
+        self.limiter.process_frame(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        eq::{Band, BandKind},
+        pitch::Yin,
+    };
+    use std::f32::consts::TAU;
+
+    const SR: u32 = 48000;
+
+    fn hard_left(bus: &mut MasterBus, freq: f32, len: usize) -> (Vec<f32>, Vec<f32>) {
+        (0..len)
+            .map(|i| bus.process_frame([0.3 * (TAU * freq * i as f32 / SR as f32).sin(), 0.]))
+            .map(|[l, r]| (l, r))
+            .unzip()
+    }
+
+    fn rms(x: &[f32]) -> f32 {
+        (x.iter().map(|s| s * s).sum::<f32>() / x.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn panned_signal_is_shifted() {
+        let mut bus = MasterBus::new(SR);
+        bus.set_compress(false);
+        bus.set_shifter(Some(PitchShifter::new(12.).unwrap()));
+        let (left, right) = hard_left(&mut bus, 220., SR as usize);
+
+        let mut yin = Yin::new(SR, 50., 1000.);
+        let tail = &left[left.len() - yin.frame_len()..];
+        let pitch = yin.detect(tail).expect("pitch");
+        assert!((pitch.freq - 440.).abs() < 5., "{}", pitch.freq);
+        assert!(rms(&right) < 1e-3);
+    }
+
+    #[test]
+    fn panned_signal_is_equalised() {
+        let mut bus = MasterBus::new(SR);
+        bus.set_compress(false);
+        bus.eq().iter_mut().for_each(|eq| {
+            eq.add_band(Band::new(BandKind::HighShelf, 1000., -24., 0.707));
+        });
+        let (left, right) = hard_left(&mut bus, 4000., SR as usize / 4);
+
+        let settled = &left[left.len() / 2..];
+        assert!(rms(settled) < 0.3 * 0.707 * 0.1, "{}", rms(settled));
+        assert!(rms(&right) < 1e-3);
+    }
+}
@@ -0,0 +1,253 @@
+/// Pitch-bend range either way, in semitones
+pub const BEND_RANGE: f32 = 2.;
+
+/// A complete MIDI message. Channels are 0-15.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MidiMessage {
+    NoteOff {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    NoteOn {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    PolyPressure {
+        channel: u8,
+        note: u8,
+        pressure: u8,
+    },
+    ControlChange {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+    ProgramChange {
+        channel: u8,
+        program: u8,
+    },
+    ChannelPressure {
+        channel: u8,
+        pressure: u8,
+    },
+    /// -8192..=8191, 0 is centred
+    PitchBend {
+        channel: u8,
+        value: i16,
+    },
+    /// Payload between the 0xF0 and 0xF7
+    SysEx(Vec<u8>),
+    TimeCode(u8),
+    /// Sixteenth notes since the start of the song
+    SongPosition(u16),
+    SongSelect(u8),
+    TuneRequest,
+    Clock,
+    Start,
+    Continue,
+    Stop,
+    ActiveSensing,
+    Reset,
+}
+
+/// Length including the status byte of a message starting with `status`,
+/// `None` for SysEx, data bytes and undefined statuses
+pub fn message_len(status: u8) -> Option<usize> {
+    match status {
+        0x80..=0xbf | 0xe0..=0xef => Some(3),
+        0xc0..=0xdf => Some(2),
+        0xf1 | 0xf3 => Some(2),
+        0xf2 => Some(3),
+        0xf6 | 0xf8 | 0xfa..=0xfc | 0xfe | 0xff => Some(1),
+        _ => None,
+    }
+}
+
+impl MidiMessage {
+    /// Parses one complete message other than SysEx
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let status = *bytes.first()?;
+        if bytes.len() < message_len(status)? {
+            return None;
+        }
+        let channel = status & 0x0f;
+        let data = |idx: usize| bytes[idx] & 0x7f;
+        Some(match status {
+            0x80..=0x8f => Self::NoteOff {
+                channel,
+                note: data(1),
+                velocity: data(2),
+            },
+            0x90..=0x9f => Self::NoteOn {
+                channel,
+                note: data(1),
+                velocity: data(2),
+            },
+            0xa0..=0xaf => Self::PolyPressure {
+                channel,
+                note: data(1),
+                pressure: data(2),
+            },
+            0xb0..=0xbf => Self::ControlChange {
+                channel,
+                controller: data(1),
+                value: data(2),
+            },
+            0xc0..=0xcf => Self::ProgramChange {
+                channel,
+                program: data(1),
+            },
+            0xd0..=0xdf => Self::ChannelPressure {
+                channel,
+                pressure: data(1),
+            },
+            0xe0..=0xef => Self::PitchBend {
+                channel,
+                value: ((data(2) as i16) << 7 | data(1) as i16) - 8192,
+            },
+            0xf1 => Self::TimeCode(data(1)),
+            0xf2 => Self::SongPosition((data(2) as u16) << 7 | data(1) as u16),
+            0xf3 => Self::SongSelect(data(1)),
+            0xf6 => Self::TuneRequest,
+            0xf8 => Self::Clock,
+            0xfa => Self::Start,
+            0xfb => Self::Continue,
+            0xfc => Self::Stop,
+            0xfe => Self::ActiveSensing,
+            0xff => Self::Reset,
+            _ => return None,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Self::NoteOff {
+                channel,
+                note,
+                velocity,
+            } => vec![0x80 | channel, *note, *velocity],
+            Self::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => vec![0x90 | channel, *note, *velocity],
+            Self::PolyPressure {
+                channel,
+                note,
+                pressure,
+            } => vec![0xa0 | channel, *note, *pressure],
+            Self::ControlChange {
+                channel,
+                controller,
+                value,
+            } => vec![0xb0 | channel, *controller, *value],
+            Self::ProgramChange { channel, program } => vec![0xc0 | channel, *program],
+            Self::ChannelPressure { channel, pressure } => vec![0xd0 | channel, *pressure],
+            Self::PitchBend { channel, value } => {
+                let value = (*value + 8192).clamp(0, 0x3fff) as u16;
+                vec![0xe0 | channel, (value & 0x7f) as u8, (value >> 7) as u8]
+            }
+            Self::SysEx(payload) => {
+                let mut bytes = vec![0xf0];
+                bytes.extend(payload);
+                bytes.push(0xf7);
+                bytes
+            }
+            Self::TimeCode(value) => vec![0xf1, *value],
+            Self::SongPosition(beats) => vec![0xf2, (beats & 0x7f) as u8, (beats >> 7) as u8],
+            Self::SongSelect(song) => vec![0xf3, *song],
+            Self::TuneRequest => vec![0xf6],
+            Self::Clock => vec![0xf8],
+            Self::Start => vec![0xfa],
+            Self::Continue => vec![0xfb],
+            Self::Stop => vec![0xfc],
+            Self::ActiveSensing => vec![0xfe],
+            Self::Reset => vec![0xff],
+        }
+    }
+
+    pub fn channel(&self) -> Option<u8> {
+        match self {
+            Self::NoteOff { channel, .. }
+            | Self::NoteOn { channel, .. }
+            | Self::PolyPressure { channel, .. }
+            | Self::ControlChange { channel, .. }
+            | Self::ProgramChange { channel, .. }
+            | Self::ChannelPressure { channel, .. }
+            | Self::PitchBend { channel, .. } => Some(*channel),
+            _ => None,
+        }
+    }
+
+    pub fn is_realtime(&self) -> bool {
+        matches!(
+            self,
+            Self::Clock
+                | Self::Start
+                | Self::Continue
+                | Self::Stop
+                | Self::ActiveSensing
+                | Self::Reset
+        )
+    }
+}
+
+/// Parses a MIDI byte stream, as read from a raw MIDI port: running status,
+/// realtime bytes between any others, and SysEx of any length
+#[derive(Debug, Clone, Default)]
+pub struct MidiParser {
+    status: Option<u8>,
+    data: Vec<u8>,
+    sysex: Option<Vec<u8>>,
+}
+
+impl MidiParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, byte: u8) -> Option<MidiMessage> {
+        match byte {
+            // realtime, which may come anywhere and changes nothing
+            0xf8..=0xff => return MidiMessage::from_bytes(&[byte]),
+            0xf0 => {
+                self.status = None;
+                self.sysex = Some(Vec::new());
+                return None;
+            }
+            0xf7 => {
+                self.status = None;
+                return self.sysex.take().map(MidiMessage::SysEx);
+            }
+            0x80..=0xf6 => {
+                // any other status also ends an unterminated SysEx
+                self.sysex = None;
+                self.data.clear();
+                self.status = message_len(byte).map(|_| byte);
+            }
+            _ => match self.sysex.as_mut() {
+                Some(sysex) => sysex.push(byte),
+                None if self.status.is_some() => self.data.push(byte),
+                None => (),
+            },
+        }
+
+        let status = self.status?;
+        if self.data.len() + 1 < message_len(status)? {
+            return None;
+        }
+        let mut bytes = vec![status];
+        bytes.append(&mut self.data);
+        // system common messages cancel running status
+        if status >= 0xf0 {
+            self.status = None;
+        }
+        MidiMessage::from_bytes(&bytes)
+    }
+
+    pub fn parse(&mut self, bytes: &[u8]) -> Vec<MidiMessage> {
+        bytes.iter().filter_map(|b| self.push(*b)).collect()
+    }
+}
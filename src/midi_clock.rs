@@ -0,0 +1,157 @@
+use std::{collections::VecDeque, time::Instant};
+
+use crate::midi::MidiMessage;
+
+/// MIDI clock pulses per quarter note
+pub const PPQN: u32 = 24;
+
+/// Pulses the period is fitted over, two beats' worth
+const WINDOW: usize = 48;
+/// Fewest pulses before there is a tempo
+const MIN_PULSES: usize = 8;
+/// Weight of each new fit in the smoothed period
+const SMOOTHING: f64 = 0.05;
+/// A fit this far (as a fraction) from the smoothed period is a tempo
+/// change, followed at once rather than glided to
+const JUMP: f64 = 0.04;
+/// A gap this long in seconds means the clock went away
+const TIMEOUT: f64 = 0.5;
+
+/// A transport change from the clock source
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncEvent {
+    Start,
+    Continue,
+    Stop,
+    /// Song position pointer, in quarter notes
+    Locate(f64),
+}
+
+/// Follows an incoming MIDI clock: tempo from the pulse intervals, and the
+/// song position and transport from start, stop, continue and song position
+/// pointer. The period is a least-squares fit to the recent pulse times,
+/// which spreads their jitter over the whole window rather than over one
+/// interval, then smoothed; steps in tempo are taken as they come.
+#[derive(Debug, Clone, Default)]
+pub struct ClockFollower {
+    /// Pulse numbers and times of the recent pulses
+    times: VecDeque<(u64, Instant)>,
+    /// Pulses received, running or not
+    received: u64,
+    /// Smoothed seconds per pulse
+    period: Option<f64>,
+    running: bool,
+    /// Pulses since the start of the song
+    pulses: u64,
+}
+
+impl ClockFollower {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes a message received at `now`, returning the transport change
+    /// it makes
+    pub fn handle(&mut self, message: &MidiMessage, now: Instant) -> Option<SyncEvent> {
+        match message {
+            MidiMessage::Clock => {
+                self.pulse(now);
+                None
+            }
+            MidiMessage::Start => {
+                self.pulses = 0;
+                self.running = true;
+                Some(SyncEvent::Start)
+            }
+            MidiMessage::Continue => {
+                self.running = true;
+                Some(SyncEvent::Continue)
+            }
+            MidiMessage::Stop => {
+                self.running = false;
+                Some(SyncEvent::Stop)
+            }
+            // sixteenth notes, six pulses each
+            MidiMessage::SongPosition(position) => {
+                self.pulses = *position as u64 * 6;
+                Some(SyncEvent::Locate(self.beats()))
+            }
+            _ => None,
+        }
+    }
+
+    fn pulse(&mut self, now: Instant) {
+        if self.running {
+            self.pulses += 1;
+        }
+        self.received += 1;
+        let gap = self
+            .times
+            .back()
+            .map(|(_, last)| now.saturating_duration_since(*last));
+        if gap.is_some_and(|gap| gap.as_secs_f64() > TIMEOUT) {
+            self.times.clear();
+            self.period = None;
+        }
+        // pulses read in one batch share a time; the next distinct time
+        // still counts them, through its pulse number
+        if gap.is_some_and(|gap| gap.is_zero()) {
+            return;
+        }
+        self.times.push_back((self.received, now));
+        if self.times.len() > WINDOW {
+            self.times.pop_front();
+        }
+        if self.times.len() < MIN_PULSES {
+            return;
+        }
+        let fit = self.fit();
+        if !(fit.is_finite() && fit > 0.) {
+            return;
+        }
+        self.period = Some(match self.period {
+            Some(period) if ((fit - period) / period).abs() < JUMP => {
+                period + SMOOTHING * (fit - period)
+            }
+            Some(period) => {
+                // the older pulses were at the old tempo
+                self.times.drain(..self.times.len() - MIN_PULSES);
+                Some(self.fit())
+                    .filter(|fit| fit.is_finite() && *fit > 0.)
+                    .unwrap_or(period)
+            }
+            None => fit,
+        });
+    }
+
+    /// Slope of the pulse times against pulse number
+    fn fit(&self) -> f64 {
+        let (first_pulse, first) = self.times[0];
+        let n = self.times.len() as f64;
+        let points = self
+            .times
+            .iter()
+            .map(|(pulse, t)| ((pulse - first_pulse) as f64, (*t - first).as_secs_f64()));
+        let mean_x = points.clone().map(|(x, _)| x).sum::<f64>() / n;
+        let mean_y = points.clone().map(|(_, y)| y).sum::<f64>() / n;
+        let (xy, xx) = points.fold((0., 0.), |(xy, xx), (x, y)| {
+            let dx = x - mean_x;
+            (xy + dx * (y - mean_y), xx + dx * dx)
+        });
+        xy / xx
+    }
+
+    /// `None` until enough pulses have come in
+    pub fn bpm(&self) -> Option<f64> {
+        self.period.map(|period| 60. / (period * PPQN as f64))
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Quarter notes since the start of the song
+    pub fn beats(&self) -> f64 {
+        self.pulses as f64 / PPQN as f64
+    }
+}
@@ -0,0 +1,235 @@
+use std::{fmt, fs, path::Path, str::FromStr, time::Duration};
+
+use rusb::{Device, DeviceHandle, Direction, TransferType, UsbContext};
+
+/// Audio interface class and its MIDI Streaming subclass
+const AUDIO_CLASS: u8 = 0x01;
+const MIDI_STREAMING: u8 = 0x03;
+
+/// Read from `--midi-config`, `key = value` lines
+pub const DEFAULT_CONFIG: &str = "midi.conf";
+
+/// An IN or OUT endpoint of a MIDI Streaming interface
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MidiEndpoint {
+    pub interface: u8,
+    pub setting: u8,
+    pub address: u8,
+    /// Bulk or interrupt
+    pub transfer: TransferType,
+    pub max_packet: u16,
+}
+
+/// A USB-MIDI device found by `discover`
+#[derive(Debug, Clone)]
+pub struct MidiDevice<C: UsbContext> {
+    pub device: Device<C>,
+    pub name: String,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub endpoint: MidiEndpoint,
+    pub out_endpoint: Option<MidiEndpoint>,
+}
+
+impl<C: UsbContext> fmt::Display for MidiDevice<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} [{:04x}:{:04x}] bus {} address {}, interface {} endpoint {:#04x} ({:?})",
+            self.name,
+            self.vendor_id,
+            self.product_id,
+            self.device.bus_number(),
+            self.device.address(),
+            self.endpoint.interface,
+            self.endpoint.address,
+            self.endpoint.transfer
+        )?;
+        match self.out_endpoint {
+            Some(out) => write!(f, ", out {:#04x}", out.address),
+            None => Ok(()),
+        }
+    }
+}
+
+/// First bulk or interrupt IN endpoint of a MIDI Streaming interface
+pub fn midi_endpoint<C: UsbContext>(device: &Device<C>) -> Option<MidiEndpoint> {
+    find_endpoint(device, Direction::In)
+}
+
+/// First bulk or interrupt OUT endpoint, for sending to the device
+pub fn midi_out_endpoint<C: UsbContext>(device: &Device<C>) -> Option<MidiEndpoint> {
+    find_endpoint(device, Direction::Out)
+}
+
+fn find_endpoint<C: UsbContext>(device: &Device<C>, direction: Direction) -> Option<MidiEndpoint> {
+    let config = device
+        .active_config_descriptor()
+        .or_else(|_| device.config_descriptor(0))
+        .ok()?;
+    config.interfaces().find_map(|interface| {
+        interface
+            .descriptors()
+            .filter(|desc| {
+                desc.class_code() == AUDIO_CLASS && desc.sub_class_code() == MIDI_STREAMING
+            })
+            .find_map(|desc| {
+                desc.endpoint_descriptors()
+                    .find(|ep| {
+                        ep.direction() == direction
+                            && matches!(
+                                ep.transfer_type(),
+                                TransferType::Bulk | TransferType::Interrupt
+                            )
+                    })
+                    .map(|ep| MidiEndpoint {
+                        interface: desc.interface_number(),
+                        setting: desc.setting_number(),
+                        address: ep.address(),
+                        transfer: ep.transfer_type(),
+                        max_packet: ep.max_packet_size(),
+                    })
+            })
+    })
+}
+
+/// The device as a `MidiDevice`, if it has a MIDI Streaming interface.
+/// Devices we can't open still show up, named by their IDs.
+pub fn probe<C: UsbContext>(device: Device<C>) -> Option<MidiDevice<C>> {
+    let descriptor = device.device_descriptor().ok()?;
+    let endpoint = midi_endpoint(&device)?;
+    let out_endpoint = midi_out_endpoint(&device);
+    let (vendor_id, product_id) = (descriptor.vendor_id(), descriptor.product_id());
+    let name = device
+        .open()
+        .and_then(|handle| handle.read_product_string_ascii(&descriptor))
+        .unwrap_or_else(|_| format!("{vendor_id:04x}:{product_id:04x}"));
+    Some(MidiDevice {
+        device,
+        name,
+        vendor_id,
+        product_id,
+        endpoint,
+        out_endpoint,
+    })
+}
+
+/// Every USB-MIDI class device on `context`
+pub fn discover<C: UsbContext>(context: &C) -> rusb::Result<Vec<MidiDevice<C>>> {
+    Ok(context.devices()?.iter().filter_map(probe).collect())
+}
+
+/// How the user picks a device: a name (case-insensitive substring),
+/// `vvvv:pppp` in hex, or an index into the `discover` list
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceSelector {
+    Name(String),
+    VidPid(u16, u16),
+    Index(usize),
+}
+
+impl FromStr for DeviceSelector {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Ok(idx) = s.parse() {
+            return Ok(Self::Index(idx));
+        }
+        let ids = s.split_once(':').and_then(|(vid, pid)| {
+            Some((
+                u16::from_str_radix(vid, 16).ok()?,
+                u16::from_str_radix(pid, 16).ok()?,
+            ))
+        });
+        Ok(match ids {
+            Some((vid, pid)) => Self::VidPid(vid, pid),
+            None => Self::Name(s.to_string()),
+        })
+    }
+}
+
+impl DeviceSelector {
+    pub fn matches<C: UsbContext>(&self, idx: usize, device: &MidiDevice<C>) -> bool {
+        match self {
+            Self::Name(name) => device.name.to_lowercase().contains(&name.to_lowercase()),
+            Self::VidPid(vid, pid) => device.vendor_id == *vid && device.product_id == *pid,
+            Self::Index(i) => idx == *i,
+        }
+    }
+
+    /// For ports known only by name, like ALSA's; never matches IDs
+    pub fn matches_port(&self, idx: usize, name: &str) -> bool {
+        match self {
+            Self::Name(s) => name.to_lowercase().contains(&s.to_lowercase()),
+            Self::VidPid(..) => false,
+            Self::Index(i) => idx == *i,
+        }
+    }
+}
+
+/// Value of `key` in a `key = value` config file; `#` starts a comment
+pub fn config_value(path: impl AsRef<Path>, key: &str) -> Option<String> {
+    fs::read_to_string(path).ok()?.lines().find_map(|line| {
+        let line = line.split('#').next()?;
+        let (k, v) = line.split_once('=')?;
+        (k.trim() == key).then(|| v.trim().to_string())
+    })
+}
+
+/// An opened device with its MIDI interfaces claimed. Reading and writing
+/// may happen on different threads.
+#[derive(Debug)]
+pub struct MidiConnection<C: UsbContext> {
+    handle: DeviceHandle<C>,
+    endpoint: MidiEndpoint,
+    out_endpoint: Option<MidiEndpoint>,
+}
+
+impl<C: UsbContext> MidiDevice<C> {
+    pub fn open(&self) -> rusb::Result<MidiConnection<C>> {
+        let handle = self.device.open()?;
+        // not supported on every platform; claiming reports the real error
+        let _ = handle.set_auto_detach_kernel_driver(true);
+        handle.claim_interface(self.endpoint.interface)?;
+        if self.endpoint.setting != 0 {
+            handle.set_alternate_setting(self.endpoint.interface, self.endpoint.setting)?;
+        }
+        // the OUT endpoint is usually on the same interface
+        let out_endpoint = self.out_endpoint.filter(|out| {
+            out.interface == self.endpoint.interface
+                || handle.claim_interface(out.interface).is_ok()
+        });
+        Ok(MidiConnection {
+            handle,
+            endpoint: self.endpoint,
+            out_endpoint,
+        })
+    }
+}
+
+impl<C: UsbContext> MidiConnection<C> {
+    /// One transfer of USB-MIDI event packets
+    pub fn read(&self, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize> {
+        match self.endpoint.transfer {
+            TransferType::Interrupt => {
+                self.handle
+                    .read_interrupt(self.endpoint.address, buf, timeout)
+            }
+            _ => self.handle.read_bulk(self.endpoint.address, buf, timeout),
+        }
+    }
+
+    /// One transfer of USB-MIDI event packets to the device
+    pub fn write(&self, buf: &[u8], timeout: Duration) -> rusb::Result<usize> {
+        let out = self.out_endpoint.ok_or(rusb::Error::NotSupported)?;
+        match out.transfer {
+            TransferType::Interrupt => self.handle.write_interrupt(out.address, buf, timeout),
+            _ => self.handle.write_bulk(out.address, buf, timeout),
+        }
+    }
+
+    pub fn out_endpoint(&self) -> Option<MidiEndpoint> {
+        self.out_endpoint
+    }
+}
@@ -1,13 +1,337 @@
-use rusb::{Context, Hotplug, UsbContext};
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+        mpsc::Sender,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
 
-// pub struct MidiEventHandler;
+use rusb::{Context, Device, Hotplug, HotplugBuilder, Registration, UsbContext};
 
-// impl Hotplug<Context> for MidiEventHandler {
-//     fn device_arrived(&mut self, device: rusb::Device<Context>) {
-//         let mut ctx = device.context();
-//         let handler = device.open().unwrap();
+use crate::{
+    alsa_midi::{self, RawMidiInput},
+    midi::MidiMessage,
+    midi_device::{self, DeviceSelector},
+    midi_input::{MidiInput, UsbInput},
+    midi_output::{MidiOutput, UsbOutput},
+    usb_midi::UsbMidiEvent,
+};
 
-//     }
+/// What the MIDI input threads report to the UI
+#[derive(Debug)]
+pub enum InputEvent {
+    Arrived(String),
+    Left(String),
+    /// With the time its transfer was read
+    Midi(UsbMidiEvent, Instant),
+    /// The device picked for output has been opened
+    Output(Box<dyn MidiOutput>),
+}
 
-//     fn device_left(&mut self, device: rusb::Device<Context>) {}
-// }
+struct Reader {
+    running: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+/// Opens every USB-MIDI device (or those matching `selector`) as it is
+/// plugged in, with a read thread each. Unplugging a device stops its
+/// thread, which first sends note-offs for the notes it left held. Devices
+/// matching `output` are also written to, over the same connection.
+///
+/// Register it with `rusb::HotplugBuilder`; where hotplug isn't supported,
+/// call `device_arrived` for each device found at startup instead.
+pub struct MidiEventHandler {
+    events: Sender<InputEvent>,
+    selector: Option<DeviceSelector>,
+    output: Option<DeviceSelector>,
+    readers: HashMap<(u8, u8), Reader>,
+    /// Stopped readers not yet joined
+    retired: Vec<JoinHandle<()>>,
+}
+
+impl MidiEventHandler {
+    pub fn new(
+        events: Sender<InputEvent>,
+        selector: Option<DeviceSelector>,
+        output: Option<DeviceSelector>,
+    ) -> Self {
+        Self {
+            events,
+            selector,
+            output,
+            readers: HashMap::new(),
+            retired: Vec::new(),
+        }
+    }
+
+    /// Joins readers that have finished. Never blocks: a reader still in a
+    /// transfer would need the event loop this may be called from.
+    fn reap(&mut self) {
+        let (done, pending) = self.retired.drain(..).partition(|h| h.is_finished());
+        self.retired = pending;
+        done.into_iter().for_each(|h: JoinHandle<()>| {
+            let _ = h.join();
+        });
+    }
+}
+
+impl Hotplug<Context> for MidiEventHandler {
+    fn device_arrived(&mut self, device: Device<Context>) {
+        self.reap();
+        // descriptors are safe to read here; opening the device is left to
+        // the reader, since synchronous transfers aren't
+        if midi_device::midi_endpoint(&device).is_none() {
+            return;
+        }
+
+        let key = (device.bus_number(), device.address());
+        let running = Arc::new(AtomicBool::new(true));
+        let selectors = (self.selector.clone(), self.output.clone());
+        let events = self.events.clone();
+        let flag = running.clone();
+        let handle = thread::spawn(move || read(device, selectors, events, flag));
+        if let Some(old) = self.readers.insert(key, Reader { running, handle }) {
+            old.running.store(false, Ordering::Relaxed);
+            self.retired.push(old.handle);
+        }
+    }
+
+    fn device_left(&mut self, device: Device<Context>) {
+        if let Some(reader) = self
+            .readers
+            .remove(&(device.bus_number(), device.address()))
+        {
+            reader.running.store(false, Ordering::Relaxed);
+            self.retired.push(reader.handle);
+        }
+        self.reap();
+    }
+}
+
+impl Drop for MidiEventHandler {
+    fn drop(&mut self) {
+        self.readers.drain().for_each(|(_, reader)| {
+            reader.running.store(false, Ordering::Relaxed);
+            self.retired.push(reader.handle);
+        });
+        self.retired.drain(..).for_each(|h| {
+            let _ = h.join();
+        });
+    }
+}
+
+/// Keeps MIDI input running until dropped. Without hotplug support it
+/// holds the handler for the devices present at startup.
+pub struct Watcher {
+    _registration: Option<Registration<Context>>,
+    _handler: Option<MidiEventHandler>,
+    /// Readers of inputs opened once, like ALSA ports
+    readers: Vec<Reader>,
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        self.readers
+            .iter()
+            .for_each(|reader| reader.running.store(false, Ordering::Relaxed));
+        self.readers.drain(..).for_each(|reader| {
+            let _ = reader.handle.join();
+        });
+    }
+}
+
+/// Starts a `MidiEventHandler` on `context`, with a thread running libusb's
+/// event loop to deliver hotplug callbacks
+pub fn watch(
+    context: &Context,
+    events: Sender<InputEvent>,
+    selector: Option<DeviceSelector>,
+    output: Option<DeviceSelector>,
+) -> rusb::Result<Watcher> {
+    let mut handler = MidiEventHandler::new(events, selector, output);
+    if !rusb::has_hotplug() {
+        context
+            .devices()?
+            .iter()
+            .for_each(|device| handler.device_arrived(device));
+        return Ok(Watcher {
+            _registration: None,
+            _handler: Some(handler),
+            readers: Vec::new(),
+        });
+    }
+    let mut builder = HotplugBuilder::new();
+    builder.enumerate(true);
+    let registration = builder.register::<Context, _>(context, Box::new(handler))?;
+    let context = context.clone();
+    thread::spawn(move || {
+        loop {
+            if let Err(e) = context.handle_events(None) {
+                println!("USB event loop stopped: {e}");
+                break;
+            }
+        }
+    });
+    Ok(Watcher {
+        _registration: Some(registration),
+        _handler: None,
+        readers: Vec::new(),
+    })
+}
+
+/// Opens the ALSA raw MIDI ports (or those matching `selector`), each with a
+/// read thread, and the first port matching `output` for writing. Ports are
+/// only looked for once.
+pub fn watch_alsa(
+    events: Sender<InputEvent>,
+    selector: Option<DeviceSelector>,
+    output: Option<DeviceSelector>,
+) -> std::io::Result<Watcher> {
+    let ports = alsa_midi::ports()?;
+    if let Some(output) = output {
+        match alsa_midi::open_output(&output) {
+            Ok(port) => {
+                let _ = events.send(InputEvent::Output(Box::new(port)));
+            }
+            Err(e) => println!("{e}"),
+        }
+    }
+    let readers = ports
+        .into_iter()
+        .enumerate()
+        .filter(|(idx, port)| {
+            selector
+                .as_ref()
+                .is_none_or(|s| s.matches_port(*idx, &port.to_string()))
+        })
+        .filter_map(|(_, port)| match RawMidiInput::open(&port) {
+            Ok(input) => {
+                let _ = events.send(InputEvent::Arrived(port.to_string()));
+                let running = Arc::new(AtomicBool::new(true));
+                let (events, flag) = (events.clone(), running.clone());
+                let handle = thread::spawn(move || pump(input, &events, &flag));
+                Some(Reader { running, handle })
+            }
+            Err(e) => {
+                println!("can't open {port}: {e}");
+                None
+            }
+        })
+        .collect();
+    Ok(Watcher {
+        _registration: None,
+        _handler: None,
+        readers,
+    })
+}
+
+/// Read thread of one device, until it is unplugged or `running` clears.
+/// A device only picked for output is handed over, and its thread just
+/// waits to report it `Left`.
+fn read(
+    device: Device<Context>,
+    (selector, output): (Option<DeviceSelector>, Option<DeviceSelector>),
+    events: Sender<InputEvent>,
+    running: Arc<AtomicBool>,
+) {
+    let Some(device) = midi_device::probe(device) else {
+        return;
+    };
+    // index selectors count in the order `midi-devices` lists them now, so a
+    // replugged device keeps its place
+    let by_index = [&selector, &output]
+        .iter()
+        .any(|s| matches!(s, Some(DeviceSelector::Index(_))));
+    let index = by_index
+        .then(|| midi_device::discover(device.device.context()).ok())
+        .flatten()
+        .and_then(|devices| {
+            let key = |d: &Device<Context>| (d.bus_number(), d.address());
+            devices
+                .iter()
+                .position(|d| key(&d.device) == key(&device.device))
+        })
+        .unwrap_or(usize::MAX);
+    let is_input = selector.is_none_or(|s| s.matches(index, &device));
+    let is_output = output.is_some_and(|s| s.matches(index, &device));
+    if !is_input && !is_output {
+        return;
+    }
+    let connection = match device.open() {
+        Ok(connection) => Arc::new(connection),
+        Err(e) => {
+            println!("can't open {}: {e}", device.name);
+            return;
+        }
+    };
+    if is_output {
+        match UsbOutput::new(&device.name, connection.clone()) {
+            Some(output) => {
+                let _ = events.send(InputEvent::Output(Box::new(output)));
+            }
+            None => println!("{} has no MIDI OUT endpoint", device.name),
+        }
+    }
+    if is_input {
+        let _ = events.send(InputEvent::Arrived(device.to_string()));
+        pump(UsbInput::new(&device, connection), &events, &running);
+    } else {
+        while running.load(Ordering::Relaxed) {
+            thread::sleep(Duration::from_millis(100));
+        }
+        let _ = events.send(InputEvent::Left(device.name.clone()));
+    }
+}
+
+/// Forwards `input` until it fails or `running` clears, then sends
+/// note-offs for the notes it left held, and `Left`
+fn pump(mut input: impl MidiInput, events: &Sender<InputEvent>, running: &AtomicBool) {
+    // (cable, channel, note) of the notes down
+    let mut held = BTreeSet::new();
+    while running.load(Ordering::Relaxed) {
+        let received = match input.read(Duration::from_millis(100)) {
+            Ok(received) => received,
+            Err(e) => {
+                println!("{}: {e}", input.name());
+                break;
+            }
+        };
+        let now = Instant::now();
+        for event in received {
+            match event.message {
+                MidiMessage::NoteOn {
+                    channel,
+                    note,
+                    velocity,
+                } if velocity > 0 => {
+                    held.insert((event.cable, channel, note));
+                }
+                MidiMessage::NoteOn { channel, note, .. }
+                | MidiMessage::NoteOff { channel, note, .. } => {
+                    held.remove(&(event.cable, channel, note));
+                }
+                _ => (),
+            }
+            if events.send(InputEvent::Midi(event, now)).is_err() {
+                return;
+            }
+        }
+    }
+
+    let now = Instant::now();
+    held.into_iter().for_each(|(cable, channel, note)| {
+        let event = UsbMidiEvent {
+            cable,
+            message: MidiMessage::NoteOff {
+                channel,
+                note,
+                velocity: 0,
+            },
+        };
+        let _ = events.send(InputEvent::Midi(event, now));
+    });
+    let _ = events.send(InputEvent::Left(input.name().to_string()));
+}
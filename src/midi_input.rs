@@ -0,0 +1,51 @@
+use std::{io, sync::Arc, time::Duration};
+
+use rusb::UsbContext;
+
+use crate::{
+    midi_device::{MidiConnection, MidiDevice},
+    usb_midi::{UsbMidiDecoder, UsbMidiEvent},
+};
+
+/// A source of MIDI input. Sources without virtual cables report cable 0.
+pub trait MidiInput: Send {
+    fn name(&self) -> &str;
+
+    /// Waits up to `timeout` for input and returns the messages it completes,
+    /// none if it timed out. An error means the source is gone.
+    fn read(&mut self, timeout: Duration) -> io::Result<Vec<UsbMidiEvent>>;
+}
+
+/// The IN endpoint of a USB-MIDI device, read with libusb
+pub struct UsbInput<C: UsbContext> {
+    name: String,
+    connection: Arc<MidiConnection<C>>,
+    decoder: UsbMidiDecoder,
+    buf: Vec<u8>,
+}
+
+impl<C: UsbContext> UsbInput<C> {
+    /// Reads `device` on a connection an output may share
+    pub fn new(device: &MidiDevice<C>, connection: Arc<MidiConnection<C>>) -> Self {
+        Self {
+            name: device.name.clone(),
+            connection,
+            decoder: UsbMidiDecoder::new(),
+            buf: vec![0; (device.endpoint.max_packet as usize).max(64)],
+        }
+    }
+}
+
+impl<C: UsbContext> MidiInput for UsbInput<C> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn read(&mut self, timeout: Duration) -> io::Result<Vec<UsbMidiEvent>> {
+        match self.connection.read(&mut self.buf, timeout) {
+            Ok(size) => Ok(self.decoder.decode(&self.buf[..size])),
+            Err(rusb::Error::Timeout) => Ok(Vec::new()),
+            Err(e) => Err(io::Error::other(e)),
+        }
+    }
+}
@@ -0,0 +1,299 @@
+use std::{error::Error, fmt, fs, path::Path, str::FromStr};
+
+/// Read from `--midi-map`, one mapping per line
+pub const DEFAULT_MAP: &str = "midi.map";
+
+/// A parameter a controller can drive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Param {
+    Volume,
+    ReverbWet,
+    ReverbDry,
+    /// dB
+    DistortionDrive,
+    DistortionMix,
+    /// dB
+    CompressorThreshold,
+    CompressorRatio,
+    /// Gain in dB of a master EQ band, from 0
+    EqGain(usize),
+    /// MIDI clock BPM
+    Tempo,
+}
+
+impl Param {
+    /// The ones offered by MIDI learn, in order
+    pub const LEARNABLE: [Param; 9] = [
+        Param::Volume,
+        Param::ReverbWet,
+        Param::ReverbDry,
+        Param::DistortionDrive,
+        Param::DistortionMix,
+        Param::CompressorThreshold,
+        Param::CompressorRatio,
+        Param::EqGain(0),
+        Param::Tempo,
+    ];
+
+    /// Range a new mapping covers
+    pub fn range(&self) -> (f32, f32) {
+        match self {
+            Param::Volume | Param::ReverbWet | Param::ReverbDry | Param::DistortionMix => (0., 1.),
+            Param::DistortionDrive => (0., 40.),
+            Param::CompressorThreshold => (-60., 0.),
+            Param::CompressorRatio => (1., 20.),
+            Param::EqGain(_) => (-18., 18.),
+            Param::Tempo => (40., 240.),
+        }
+    }
+
+    /// Taper a new mapping uses
+    pub fn taper(&self) -> Taper {
+        match self {
+            Param::Volume | Param::ReverbWet | Param::ReverbDry => Taper::Squared,
+            Param::CompressorRatio | Param::Tempo => Taper::Exponential,
+            _ => Taper::Linear,
+        }
+    }
+}
+
+impl fmt::Display for Param {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Param::Volume => write!(f, "volume"),
+            Param::ReverbWet => write!(f, "reverb-wet"),
+            Param::ReverbDry => write!(f, "reverb-dry"),
+            Param::DistortionDrive => write!(f, "distortion-drive"),
+            Param::DistortionMix => write!(f, "distortion-mix"),
+            Param::CompressorThreshold => write!(f, "compressor-threshold"),
+            Param::CompressorRatio => write!(f, "compressor-ratio"),
+            Param::EqGain(band) => write!(f, "eq-gain-{}", band + 1),
+            Param::Tempo => write!(f, "tempo"),
+        }
+    }
+}
+
+impl FromStr for Param {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(band) = s.strip_prefix("eq-gain-") {
+            return match band.parse::<usize>() {
+                Ok(band) if band > 0 => Ok(Param::EqGain(band - 1)),
+                _ => Err(format!("bad EQ band in {s}")),
+            };
+        }
+        Param::LEARNABLE
+            .into_iter()
+            .find(|p| p.to_string() == s)
+            .ok_or(format!("unknown parameter {s}"))
+    }
+}
+
+/// How the controller's travel spreads over the range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Taper {
+    Linear,
+    /// Finer towards the bottom, like an audio pot
+    Squared,
+    /// Equal ratios per step, for tempos, frequencies and ratios; linear if
+    /// the range crosses zero
+    Exponential,
+}
+
+impl fmt::Display for Taper {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Taper::Linear => write!(f, "linear"),
+            Taper::Squared => write!(f, "squared"),
+            Taper::Exponential => write!(f, "exponential"),
+        }
+    }
+}
+
+impl FromStr for Taper {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "linear" => Ok(Taper::Linear),
+            "squared" => Ok(Taper::Squared),
+            "exponential" => Ok(Taper::Exponential),
+            _ => Err(format!("unknown taper {s}")),
+        }
+    }
+}
+
+/// A controller bound to a parameter
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mapping {
+    /// 0-15, `None` for any channel
+    pub channel: Option<u8>,
+    pub controller: u8,
+    pub param: Param,
+    /// Values at controller 0 and 127, unless inverted
+    pub min: f32,
+    pub max: f32,
+    pub taper: Taper,
+    pub invert: bool,
+}
+
+impl Mapping {
+    pub fn new(channel: Option<u8>, controller: u8, param: Param) -> Self {
+        let (min, max) = param.range();
+        Self {
+            channel,
+            controller,
+            param,
+            min,
+            max,
+            taper: param.taper(),
+            invert: false,
+        }
+    }
+
+    pub fn matches(&self, channel: u8, controller: u8) -> bool {
+        self.controller == controller && self.channel.is_none_or(|c| c == channel)
+    }
+
+    /// Parameter value for a controller value
+    pub fn value(&self, cc: u8) -> f32 {
+        let x = (cc.min(127) as f32) / 127.;
+        let x = if self.invert { 1. - x } else { x };
+        match self.taper {
+            Taper::Exponential if self.min * self.max > 0. => {
+                self.min * (self.max / self.min).powf(x)
+            }
+            Taper::Squared => self.min + (self.max - self.min) * x * x,
+            _ => self.min + (self.max - self.min) * x,
+        }
+    }
+}
+
+/// `param channel controller min max taper [invert]`, channel 1-16 or `*`
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let channel = self
+            .channel
+            .map_or("*".to_string(), |c| (c + 1).to_string());
+        write!(
+            f,
+            "{} {channel} {} {} {} {}",
+            self.param, self.controller, self.min, self.max, self.taper
+        )?;
+        if self.invert {
+            write!(f, " invert")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Mapping {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [param, channel, controller, rest @ ..] = fields.as_slice() else {
+            return Err(format!("expected `param channel controller ...`: {s}").into());
+        };
+        let param: Param = param.parse()?;
+        let channel = match *channel {
+            "*" => None,
+            c => match c.parse::<u8>()? {
+                c @ 1..=16 => Some(c - 1),
+                c => return Err(format!("channel {c} is not 1-16").into()),
+            },
+        };
+        let mut mapping = Mapping::new(channel, controller.parse::<u8>()?.min(127), param);
+        if let [min, max, rest @ ..] = rest {
+            mapping.min = min.parse()?;
+            mapping.max = max.parse()?;
+            if let [taper, rest @ ..] = rest {
+                mapping.taper = taper.parse()?;
+                mapping.invert = match rest {
+                    [] => false,
+                    ["invert"] => true,
+                    _ => return Err(format!("unexpected `{}`", rest.join(" ")).into()),
+                };
+            }
+        }
+        Ok(mapping)
+    }
+}
+
+/// Controller mappings, with MIDI learn: pick a parameter, move a control,
+/// and that controller and channel drive it from then on. Controllers
+/// left unmapped, CC 7 included, go to the part on their channel.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MidiMap {
+    mappings: Vec<Mapping>,
+    learning: Option<Param>,
+}
+
+impl MidiMap {
+    /// Binds the next controller moved to `param`
+    pub fn learn(&mut self, param: Param) {
+        self.learning = Some(param);
+    }
+
+    pub fn cancel_learn(&mut self) {
+        self.learning = None;
+    }
+
+    pub fn learning(&self) -> Option<Param> {
+        self.learning
+    }
+
+    /// Replaces the mapping of the same parameter, which has one controller
+    pub fn bind(&mut self, mapping: Mapping) {
+        self.unbind(mapping.param);
+        self.mappings.push(mapping);
+    }
+
+    pub fn unbind(&mut self, param: Param) {
+        self.mappings.retain(|m| m.param != param);
+    }
+
+    /// Parameter values set by a control change, after completing MIDI learn
+    /// if it was waiting. A learned mapping keeps its old range and taper.
+    pub fn control_change(&mut self, channel: u8, controller: u8, value: u8) -> Vec<(Param, f32)> {
+        if let Some(param) = self.learning.take() {
+            let mut mapping = self
+                .mappings
+                .iter()
+                .find(|m| m.param == param)
+                .cloned()
+                .unwrap_or(Mapping::new(None, controller, param));
+            mapping.channel = Some(channel);
+            mapping.controller = controller;
+            self.bind(mapping);
+        }
+        self.mappings
+            .iter()
+            .filter(|m| m.matches(channel, controller))
+            .map(|m| (m.param, m.value(value)))
+            .collect()
+    }
+
+    /// Mappings from a file, one per line; `#` starts a comment
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let mut map = Self::default();
+        for (idx, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mapping: Mapping = line.parse().map_err(|e| format!("line {}: {e}", idx + 1))?;
+            map.bind(mapping);
+        }
+        Ok(map)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let mut out = String::from("# param channel controller min max taper [invert]\n");
+        self.mappings
+            .iter()
+            .for_each(|m| out.push_str(&format!("{m}\n")));
+        fs::write(path, out)
+    }
+}
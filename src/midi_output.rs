@@ -0,0 +1,163 @@
+use std::{
+    fmt, io,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use rusb::UsbContext;
+
+use crate::{midi::MidiMessage, midi_clock::PPQN, midi_device::MidiConnection, usb_midi};
+
+/// Somewhere to send MIDI: external synths, or whatever follows our clock
+pub trait MidiOutput: Send + fmt::Debug {
+    fn name(&self) -> &str;
+
+    /// An error means the destination is gone
+    fn send(&mut self, message: &MidiMessage) -> io::Result<()>;
+}
+
+/// The OUT endpoint of a USB-MIDI device, sharing the connection its input
+/// is read from
+#[derive(Debug)]
+pub struct UsbOutput<C: UsbContext> {
+    name: String,
+    connection: Arc<MidiConnection<C>>,
+    cable: u8,
+}
+
+impl<C: UsbContext> UsbOutput<C> {
+    /// `None` if the device has no OUT endpoint
+    pub fn new(name: &str, connection: Arc<MidiConnection<C>>) -> Option<Self> {
+        connection.out_endpoint()?;
+        Some(Self {
+            name: name.to_string(),
+            connection,
+            cable: 0,
+        })
+    }
+}
+
+impl<C: UsbContext + fmt::Debug> MidiOutput for UsbOutput<C> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn send(&mut self, message: &MidiMessage) -> io::Result<()> {
+        let transfer = usb_midi::encode(self.cable, message).concat();
+        self.connection
+            .write(&transfer, Duration::from_millis(100))
+            .map(|_| ())
+            .map_err(io::Error::other)
+    }
+}
+
+/// The current output, shared by the main loop and the clock
+pub type SharedOutput = Arc<Mutex<Option<Box<dyn MidiOutput>>>>;
+
+/// Sends `message` if there is an output, which is dropped once it fails
+pub fn send(output: &SharedOutput, message: &MidiMessage) {
+    let mut output = output.lock().unwrap();
+    if let Some(Err(e)) = output.as_mut().map(|out| out.send(message)) {
+        println!("MIDI output {}: {e}", output.as_ref().unwrap().name());
+        *output = None;
+    }
+}
+
+/// Sends MIDI clock from its own thread while running, with start, stop
+/// and continue around it
+pub struct MidiClock {
+    output: SharedOutput,
+    /// Tempo as `f64` bits
+    bpm: Arc<AtomicU64>,
+    running: Arc<AtomicBool>,
+    alive: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl MidiClock {
+    pub fn new(output: SharedOutput, bpm: f64) -> Self {
+        let bpm = Arc::new(AtomicU64::new(bpm.to_bits()));
+        let running = Arc::new(AtomicBool::new(false));
+        let alive = Arc::new(AtomicBool::new(true));
+        let handle = {
+            let (output, bpm, running, alive) =
+                (output.clone(), bpm.clone(), running.clone(), alive.clone());
+            thread::spawn(move || {
+                // when the next pulse is due, while running
+                let mut next: Option<Instant> = None;
+                while alive.load(Ordering::Relaxed) {
+                    if !running.load(Ordering::Relaxed) {
+                        next = None;
+                        thread::sleep(Duration::from_millis(1));
+                        continue;
+                    }
+                    let now = Instant::now();
+                    let due = *next.get_or_insert(now);
+                    if now < due {
+                        // sleep is too coarse for the last stretch
+                        if due - now > Duration::from_millis(2) {
+                            thread::sleep(Duration::from_millis(1));
+                        } else {
+                            std::hint::spin_loop();
+                        }
+                        continue;
+                    }
+                    send(&output, &MidiMessage::Clock);
+                    let bpm = f64::from_bits(bpm.load(Ordering::Relaxed));
+                    // scheduled from the last due time so errors don't add up
+                    next = Some(due + Duration::from_secs_f64(60. / bpm / PPQN as f64));
+                }
+            })
+        };
+        Self {
+            output,
+            bpm,
+            running,
+            alive,
+            handle: Some(handle),
+        }
+    }
+
+    pub fn bpm(&self) -> f64 {
+        f64::from_bits(self.bpm.load(Ordering::Relaxed))
+    }
+
+    pub fn set_bpm(&self, bpm: f64) {
+        self.bpm
+            .store(bpm.clamp(1., 999.).to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    /// Start from the top of the song
+    pub fn start(&self) {
+        send(&self.output, &MidiMessage::Start);
+        self.running.store(true, Ordering::Relaxed);
+    }
+
+    /// Carry on from where `stop` left off
+    pub fn resume(&self) {
+        send(&self.output, &MidiMessage::Continue);
+        self.running.store(true, Ordering::Relaxed);
+    }
+
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::Relaxed);
+        send(&self.output, &MidiMessage::Stop);
+    }
+}
+
+impl Drop for MidiClock {
+    fn drop(&mut self) {
+        self.alive.store(false, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
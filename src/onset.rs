@@ -0,0 +1,434 @@
+use std::{
+    collections::VecDeque,
+    fs,
+    io::Write as _,
+    path::Path,
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use ringbuf::{HeapCons, HeapRb, traits::*};
+use rustfft::num_complex::Complex;
+
+use crate::{
+    pitch::InputTap,
+    spectrum::{Spectrum, Window},
+};
+
+const FFT_SIZE: usize = 1024;
+const HOP: usize = 512;
+/// Log compression applied to magnitudes before spectral flux
+const COMPRESSION: f32 = 100.;
+/// Tempo search range and the prior centre, in BPM
+const MIN_BPM: f32 = 40.;
+const MAX_BPM: f32 = 240.;
+const PRIOR_BPM: f32 = 120.;
+/// Detection function frames kept by the live tracker, about six seconds
+const LIVE_FRAMES: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Method {
+    /// Half-wave rectified increase in log magnitude
+    SpectralFlux,
+    /// Rectified distance from the magnitude and phase predicted by the
+    /// previous two frames (Dixon, 2006)
+    ComplexDomain,
+}
+
+impl FromStr for Method {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "flux" => Ok(Method::SpectralFlux),
+            "complex" => Ok(Method::ComplexDomain),
+            _ => Err(format!("unknown onset method: {s}")),
+        }
+    }
+}
+
+/// Turns successive frames into one detection function value each
+pub struct OnsetDetector {
+    method: Method,
+    spectrum: Spectrum,
+    mags: Vec<f32>,
+    phases: Vec<f32>,
+    prev_phases: Vec<f32>,
+}
+
+impl OnsetDetector {
+    pub fn new(method: Method) -> Self {
+        let spectrum = Spectrum::new(FFT_SIZE, Window::Hann);
+        let bins = spectrum.bins();
+        Self {
+            method,
+            spectrum,
+            mags: vec![0.; bins],
+            phases: vec![0.; bins],
+            prev_phases: vec![0.; bins],
+        }
+    }
+
+    pub fn process(&mut self, frame: &[f32]) -> f32 {
+        let bins = self.spectrum.bins() as f32;
+        let spectrum = self.spectrum.complex(frame).to_vec();
+        let value = match self.method {
+            Method::SpectralFlux => spectrum
+                .iter()
+                .zip(self.mags.iter())
+                .map(|(c, prev)| {
+                    let mag = (1. + COMPRESSION * c.norm()).ln();
+                    (mag - prev).max(0.)
+                })
+                .sum::<f32>(),
+            Method::ComplexDomain => spectrum
+                .iter()
+                .zip(self.mags.iter())
+                .zip(self.phases.iter().zip(self.prev_phases.iter()))
+                .filter(|((c, prev), _)| c.norm() >= **prev)
+                .map(|((c, prev), (phase, prev_phase))| {
+                    let predicted = Complex::from_polar(*prev, 2. * phase - prev_phase);
+                    (c - predicted).norm()
+                })
+                .sum::<f32>(),
+        };
+
+        std::mem::swap(&mut self.phases, &mut self.prev_phases);
+        spectrum.iter().enumerate().for_each(|(idx, c)| {
+            self.phases[idx] = c.arg();
+            self.mags[idx] = match self.method {
+                Method::SpectralFlux => (1. + COMPRESSION * c.norm()).ln(),
+                Method::ComplexDomain => c.norm(),
+            };
+        });
+        value / bins
+    }
+}
+
+/// Detection function of a whole recording, one value per hop. Frames are
+/// centred, so frame `n` sits at `n * hop / sample_rate` seconds.
+pub fn detection_function(samples: &[f32], method: Method) -> Vec<f32> {
+    let mut detector = OnsetDetector::new(method);
+    let mut padded = vec![0.; FFT_SIZE / 2];
+    padded.extend_from_slice(samples);
+    padded.resize(padded.len() + FFT_SIZE / 2, 0.);
+    (0..=padded.len() - FFT_SIZE)
+        .step_by(HOP)
+        .map(|start| detector.process(&padded[start..start + FFT_SIZE]))
+        .collect()
+}
+
+/// Adaptive threshold peak picking (Böck et al., 2012)
+#[derive(Debug, Clone)]
+pub struct PeakPicker {
+    /// Frames either side a peak must be the maximum of
+    pub max_before: usize,
+    pub max_after: usize,
+    /// Frames either side averaged for the threshold
+    pub avg_before: usize,
+    pub avg_after: usize,
+    /// Height above the local mean, in units of the function's deviation
+    pub delta: f32,
+    /// Minimum frames between onsets
+    pub wait: usize,
+}
+
+impl Default for PeakPicker {
+    fn default() -> Self {
+        Self {
+            max_before: 3,
+            max_after: 3,
+            avg_before: 10,
+            avg_after: 7,
+            delta: 0.5,
+            wait: 3,
+        }
+    }
+}
+
+impl PeakPicker {
+    pub fn pick(&self, odf: &[f32]) -> Vec<usize> {
+        let odf = standardise(odf);
+        let window = |idx: usize, before: usize, after: usize| {
+            &odf[idx.saturating_sub(before)..(idx + after + 1).min(odf.len())]
+        };
+        let mut onsets: Vec<usize> = Vec::new();
+        (0..odf.len()).for_each(|idx| {
+            let value = odf[idx];
+            let local_max = window(idx, self.max_before, self.max_after)
+                .iter()
+                .fold(f32::MIN, |acc, v| acc.max(*v));
+            let neighbours = window(idx, self.avg_before, self.avg_after);
+            let mean = neighbours.iter().sum::<f32>() / neighbours.len() as f32;
+            let spaced = onsets.last().is_none_or(|last| idx - last > self.wait);
+            if value >= local_max && value >= mean + self.delta && spaced {
+                onsets.push(idx);
+            }
+        });
+        onsets
+    }
+}
+
+/// Zero mean, unit deviation
+fn standardise(odf: &[f32]) -> Vec<f32> {
+    let n = odf.len().max(1) as f32;
+    let mean = odf.iter().sum::<f32>() / n;
+    let deviation = (odf.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / n).sqrt();
+    let deviation = if deviation > 0. { deviation } else { 1. };
+    odf.iter().map(|v| (v - mean) / deviation).collect()
+}
+
+/// Tempo in BPM from the autocorrelation of the detection function, weighted
+/// towards `PRIOR_BPM` to resolve octave errors (Ellis, 2007)
+pub fn tempo(odf: &[f32], frame_rate: f32) -> Option<f32> {
+    let odf = standardise(odf);
+    let min_lag = (60. * frame_rate / MAX_BPM).floor().max(1.) as usize;
+    let max_lag = ((60. * frame_rate / MIN_BPM).ceil() as usize).min(odf.len().saturating_sub(1));
+    if max_lag <= min_lag + 1 {
+        return None;
+    }
+    let acf: Vec<f32> = (0..=max_lag + 1)
+        .map(|lag| {
+            odf.iter()
+                .zip(odf[lag.min(odf.len())..].iter())
+                .map(|(a, b)| a * b)
+                .sum::<f32>()
+                / (odf.len() - lag.min(odf.len())).max(1) as f32
+        })
+        .collect();
+    let weight = |lag: f32| {
+        let octaves = (60. * frame_rate / lag / PRIOR_BPM).log2();
+        (-0.5 * octaves * octaves).exp()
+    };
+    let lag = (min_lag..=max_lag)
+        .max_by(|a, b| (acf[*a] * weight(*a as f32)).total_cmp(&(acf[*b] * weight(*b as f32))))?;
+    if acf[lag] <= 0. {
+        return None;
+    }
+
+    let (a, b, c) = (acf[lag - 1], acf[lag], acf[lag + 1]);
+    let denom = a - 2. * b + c;
+    let offset = if denom.abs() > 1e-9 {
+        (0.5 * (a - c) / denom).clamp(-0.5, 0.5)
+    } else {
+        0.
+    };
+    Some(60. * frame_rate / (lag as f32 + offset))
+}
+
+/// Beat frames by dynamic programming: each beat is rewarded for detection
+/// function strength and penalised for straying from the beat period
+/// (Ellis, 2007)
+pub fn track_beats(odf: &[f32], frame_rate: f32, bpm: f32) -> Vec<usize> {
+    const TIGHTNESS: f32 = 100.;
+    let odf = standardise(odf);
+    let period = 60. * frame_rate / bpm;
+    if odf.is_empty() || period < 2. {
+        return Vec::new();
+    }
+
+    let mut score = odf.clone();
+    let mut backlink = vec![None; odf.len()];
+    (0..odf.len()).for_each(|t| {
+        let lo = (t as f32 - 2. * period).round().max(0.) as usize;
+        let hi = (t as f32 - period / 2.).round();
+        if hi < 0. {
+            return;
+        }
+        let best = (lo..=(hi as usize).min(t.saturating_sub(1)))
+            .map(|prev| {
+                let ratio = ((t - prev) as f32 / period).ln();
+                (prev, score[prev] - TIGHTNESS * ratio * ratio)
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        if let Some((prev, value)) = best.filter(|(_, value)| *value > 0.) {
+            score[t] = odf[t] + value;
+            backlink[t] = Some(prev);
+        }
+    });
+
+    // end on the best score within the last period
+    let tail = (odf.len() as f32 - period).max(0.) as usize;
+    let Some(mut beat) = (tail..odf.len()).max_by(|a, b| score[*a].total_cmp(&score[*b])) else {
+        return Vec::new();
+    };
+    let mut beats = vec![beat];
+    while let Some(prev) = backlink[beat] {
+        beats.push(prev);
+        beat = prev;
+    }
+    beats.reverse();
+    beats
+}
+
+#[derive(Debug, Clone)]
+pub struct Rhythm {
+    /// Onset times in seconds
+    pub onsets: Vec<f32>,
+    pub bpm: Option<f32>,
+    /// Beat times in seconds
+    pub beats: Vec<f32>,
+}
+
+pub fn analyze(samples: &[f32], sample_rate: u32, method: Method, picker: &PeakPicker) -> Rhythm {
+    let odf = detection_function(samples, method);
+    let frame_rate = sample_rate as f32 / HOP as f32;
+    let seconds = |frames: Vec<usize>| -> Vec<f32> {
+        frames
+            .into_iter()
+            .map(|frame| frame as f32 / frame_rate)
+            .collect()
+    };
+    let bpm = tempo(&odf, frame_rate);
+    let beats = bpm.map_or(Vec::new(), |bpm| track_beats(&odf, frame_rate, bpm));
+    Rhythm {
+        onsets: seconds(picker.pick(&odf)),
+        bpm,
+        beats: seconds(beats),
+    }
+}
+
+/// One time in seconds per line
+pub fn write_timestamps(path: impl AsRef<Path>, times: &[f32]) -> std::io::Result<()> {
+    let mut out = std::io::BufWriter::new(fs::File::create(path)?);
+    times.iter().try_for_each(|time| writeln!(out, "{time:.4}"))
+}
+
+/// Live onset and tempo display fed from the input stream
+pub struct BeatTracker {
+    detector: OnsetDetector,
+    frame_rate: f32,
+    frame: Vec<f32>,
+    odf: VecDeque<f32>,
+    picker: PeakPicker,
+    cons: HeapCons<f32>,
+}
+
+impl BeatTracker {
+    pub fn new(sample_rate: u32, method: Method) -> (Self, InputTap) {
+        let (prod, cons) = HeapRb::<f32>::new(FFT_SIZE * 8).split();
+        let tracker = Self {
+            detector: OnsetDetector::new(method),
+            frame_rate: sample_rate as f32 / HOP as f32,
+            frame: vec![0.; FFT_SIZE],
+            odf: VecDeque::with_capacity(LIVE_FRAMES),
+            picker: PeakPicker::default(),
+            cons,
+        };
+        (tracker, prod)
+    }
+
+    /// Runs the detector over every complete hop waiting in the tap and
+    /// returns whether an onset was confirmed
+    fn drain(&mut self) -> bool {
+        let mut onset = false;
+        let mut fresh = [0.0_f32; HOP];
+        while self.cons.occupied_len() >= HOP {
+            self.cons.pop_slice(&mut fresh);
+            self.frame.drain(..HOP);
+            self.frame.extend_from_slice(&fresh);
+            if self.odf.len() == LIVE_FRAMES {
+                self.odf.pop_front();
+            }
+            self.odf.push_back(self.detector.process(&self.frame));
+            let odf = self.odf.make_contiguous();
+
+            // a peak can only be confirmed once the frames after it are in
+            let recent = odf.len().saturating_sub(self.picker.avg_before * 3);
+            let candidate = odf.len().saturating_sub(self.picker.max_after + 1);
+            onset |= candidate >= recent
+                && self
+                    .picker
+                    .pick(&odf[recent..])
+                    .contains(&(candidate - recent));
+        }
+        onset
+    }
+
+    pub fn spawn(mut self, enabled: Arc<AtomicBool>) -> JoinHandle<()> {
+        thread::spawn(move || {
+            let mut flash = 0_u8;
+            let mut ticks = 0_usize;
+            let mut bpm = None;
+            loop {
+                if self.drain() {
+                    flash = 3;
+                }
+                // re-estimate about once a second
+                ticks += 1;
+                if ticks.is_multiple_of(20) {
+                    bpm = tempo(self.odf.make_contiguous(), self.frame_rate);
+                }
+                if enabled.load(Ordering::Relaxed) {
+                    let marker = if flash > 0 { '●' } else { ' ' };
+                    match bpm {
+                        Some(bpm) => print!("\r{marker} {bpm:6.1} BPM\x1b[K"),
+                        None => print!("\r{marker}     -- BPM\x1b[K"),
+                    }
+                    let _ = std::io::Write::flush(&mut std::io::stdout());
+                }
+                flash = flash.saturating_sub(1);
+                thread::sleep(Duration::from_millis(50));
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SR: u32 = 48000;
+
+    /// Decaying 2 kHz bursts at `bpm`, starting half a second in
+    fn click_track(bpm: f32, seconds: f32) -> (Vec<f32>, Vec<f32>) {
+        let period = 60. / bpm;
+        let clicks: Vec<f32> = (0..)
+            .map(|n| 0.5 + n as f32 * period)
+            .take_while(|t| *t < seconds - 0.5)
+            .collect();
+        let mut samples = vec![0.; (seconds * SR as f32) as usize];
+        for click in &clicks {
+            let start = (click * SR as f32) as usize;
+            (0..SR as usize / 50).for_each(|i| {
+                let t = i as f32 / SR as f32;
+                samples[start + i] += (std::f32::consts::TAU * 2000. * t).sin() * (-t * 300.).exp();
+            });
+        }
+        (samples, clicks)
+    }
+
+    #[test]
+    fn click_track_tempo_and_onsets() {
+        let (samples, clicks) = click_track(100., 12.);
+        let rhythm = analyze(&samples, SR, Method::SpectralFlux, &PeakPicker::default());
+        let hop = HOP as f32 / SR as f32;
+
+        let bpm = rhythm.bpm.expect("tempo");
+        assert!((bpm - 100.).abs() <= 1., "{bpm}");
+
+        assert_eq!(rhythm.onsets.len(), clicks.len());
+        rhythm
+            .onsets
+            .iter()
+            .zip(clicks.iter())
+            .for_each(|(onset, click)| assert!((onset - click).abs() <= hop, "{onset} {click}"));
+
+        // every beat but the ends lands on a click
+        assert!(rhythm.beats.len() + 2 >= clicks.len(), "{:?}", rhythm.beats);
+        rhythm.beats[1..rhythm.beats.len() - 1]
+            .iter()
+            .for_each(|beat| {
+                let nearest = clicks
+                    .iter()
+                    .map(|click| (beat - click).abs())
+                    .fold(f32::MAX, f32::min);
+                assert!(nearest <= 2. * hop, "{beat}");
+            });
+    }
+}
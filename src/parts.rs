@@ -0,0 +1,102 @@
+use std::f32::consts::{FRAC_PI_4, SQRT_2};
+
+use crate::{
+    harmonics::PartialTable, midi::MidiMessage, patch::Patch, sine_generator::SineGenerator,
+};
+
+/// One part per MIDI channel
+pub const PARTS: usize = 16;
+
+/// An instrument on one MIDI channel, with its own patch, volume, pan and
+/// notes
+#[derive(Debug, Clone)]
+pub struct Part {
+    generator: SineGenerator,
+    /// -1 (left) to 1 (right)
+    pan: f32,
+}
+
+impl Part {
+    pub fn set_program(&mut self, program: u8) {
+        let patch = Patch::program(program);
+        self.generator.all_notes_off();
+        self.generator.set_partials(&PartialTable::from(&patch));
+    }
+
+    pub fn set_pan(&mut self, pan: f32) {
+        self.pan = pan.clamp(-1., 1.);
+    }
+
+    /// Equal-power pan, unity gain at the centre
+    fn gains(&self) -> [f32; 2] {
+        let angle = (self.pan + 1.) * FRAC_PI_4;
+        [angle.cos(), angle.sin()].map(|g| g * SQRT_2)
+    }
+}
+
+/// Sixteen parts, picked by the channel of each message, mixed to stereo.
+/// Program change switches a part's patch; CC 10 pans it, and CC 7, pitch
+/// bend and the rest go to its generator.
+#[derive(Debug, Clone)]
+pub struct Parts {
+    parts: Vec<Part>,
+    volume: f32,
+}
+
+impl Parts {
+    /// Every part starts as a copy of `template`
+    pub fn new(template: SineGenerator) -> Self {
+        let part = Part {
+            generator: template,
+            pan: 0.,
+        };
+        Self {
+            parts: vec![part; PARTS],
+            volume: 1.,
+        }
+    }
+
+    /// Part for a channel, 0-15
+    pub fn part(&mut self, channel: u8) -> &mut Part {
+        &mut self.parts[channel as usize % PARTS]
+    }
+
+    /// Master volume over every part, 0 to 1
+    pub fn set_volume(&mut self, volume: f32) {
+        self.volume = volume.clamp(0., 1.);
+    }
+
+    pub fn note(&mut self, channel: u8, note: u8, velocity: u8) {
+        self.part(channel).generator.note(note, velocity);
+    }
+
+    pub fn handle(&mut self, message: &MidiMessage) {
+        let Some(channel) = message.channel() else {
+            return;
+        };
+        let part = self.part(channel);
+        match *message {
+            MidiMessage::ProgramChange { program, .. } => part.set_program(program),
+            MidiMessage::ControlChange {
+                controller: 10,
+                value,
+                ..
+            } => part.set_pan((value as f32 - 64.) / 63.),
+            _ => part.generator.handle(message),
+        }
+    }
+
+    /// Next stereo frame of the mix
+    pub fn next_frame(&mut self) -> [f32; 2] {
+        let volume = self.volume;
+        self.parts
+            .iter_mut()
+            .filter(|part| !part.generator.is_idle())
+            .fold([0.; 2], |[l, r], part| {
+                let gain = part.generator.volume() * volume;
+                let s = part.generator.next().unwrap_or(0.) * gain;
+                let [gl, gr] = part.gains();
+                [l + s * gl, r + s * gr]
+            })
+    }
+}
@@ -0,0 +1,142 @@
+/// One sine of a patch
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Harmonic {
+    /// Frequency over the note's
+    pub ratio: f32,
+    pub amplitude: f32,
+    /// dB per second, 0 to sustain
+    pub decay: f32,
+}
+
+/// An additive instrument: the sines every note is built from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Patch {
+    pub name: &'static str,
+    pub harmonics: Vec<Harmonic>,
+}
+
+/// Family names of the General MIDI programs, eight programs each
+const FAMILIES: [&str; 16] = [
+    "piano",
+    "chromatic percussion",
+    "organ",
+    "guitar",
+    "bass",
+    "strings",
+    "ensemble",
+    "brass",
+    "reed",
+    "pipe",
+    "synth lead",
+    "synth pad",
+    "synth effects",
+    "ethnic",
+    "percussive",
+    "sound effects",
+];
+
+impl Patch {
+    /// Amplitudes are scaled to the loudness of a single sine
+    pub fn new(name: &'static str, harmonics: Vec<Harmonic>) -> Self {
+        let power: f32 = harmonics.iter().map(|h| h.amplitude * h.amplitude).sum();
+        let scale = if power > 0. { power.sqrt().recip() } else { 1. };
+        let harmonics = harmonics
+            .into_iter()
+            .map(|h| Harmonic {
+                amplitude: h.amplitude * scale,
+                ..h
+            })
+            .collect();
+        Self { name, harmonics }
+    }
+
+    /// What a part plays before any program change
+    #[allow(dead_code)] // the interpreter's parts; ours start from the CLI's sound
+    pub fn sine() -> Self {
+        Self::new("sine", series(1, |_| 1., |_| 0.))
+    }
+
+    /// A patch for a General MIDI program, one per instrument family
+    pub fn program(program: u8) -> Self {
+        let family = (program as usize / 8) % FAMILIES.len();
+        let name = FAMILIES[family];
+        let harmonics = match family {
+            // struck and plucked strings: bright, upper partials dying first
+            0 => series(10, |n| n.powf(-1.5), |n| -6. - 3. * n),
+            3 | 13 => series(12, |n| n.recip(), |n| -10. - 4. * n),
+            4 => series(4, |n| 0.5_f32.powf(n - 1.), |n| -4. * n),
+            // bell-like, inharmonic
+            1 | 14 => inharmonic(&[
+                (1., 1., -8.),
+                (2.76, 0.6, -14.),
+                (5.4, 0.4, -20.),
+                (8.93, 0.25, -28.),
+            ]),
+            // drawbars: sub octave up to the fourth octave
+            2 => inharmonic(&[
+                (0.5, 0.6, 0.),
+                (1., 1., 0.),
+                (2., 0.8, 0.),
+                (3., 0.5, 0.),
+                (4., 0.5, 0.),
+                (6., 0.3, 0.),
+                (8., 0.3, 0.),
+            ]),
+            // bowed and blown: sawtooth-like
+            5 | 6 => series(12, |n| n.recip(), |_| 0.),
+            7 => series(10, |n| n.powf(-0.7), |_| 0.),
+            // reeds: odd harmonics, like a clarinet
+            8 => series(
+                9,
+                |n| if n % 2. == 1. { n.recip() } else { 0.1 / n },
+                |_| 0.,
+            ),
+            9 => series(3, |n| 0.2_f32.powf(n - 1.), |_| 0.),
+            // square wave
+            10 => series(11, |n| if n % 2. == 1. { n.recip() } else { 0. }, |_| 0.),
+            // two detuned saws
+            11 | 12 => series(8, |n| n.recip(), |_| 0.)
+                .into_iter()
+                .chain(
+                    series(8, |n| 0.7 / n, |_| 0.)
+                        .into_iter()
+                        .map(|h| Harmonic {
+                            ratio: h.ratio * 1.004,
+                            ..h
+                        }),
+                )
+                .collect(),
+            _ => series(1, |_| 1., |_| 0.),
+        };
+        Self::new(name, harmonics)
+    }
+}
+
+/// Harmonics 1 to `count`, with amplitudes and decays by harmonic number
+fn series(
+    count: usize,
+    amplitude: impl Fn(f32) -> f32,
+    decay: impl Fn(f32) -> f32,
+) -> Vec<Harmonic> {
+    (1..=count)
+        .map(|n| n as f32)
+        .map(|n| Harmonic {
+            ratio: n,
+            amplitude: amplitude(n),
+            decay: decay(n),
+        })
+        .filter(|h| h.amplitude > 0.)
+        .collect()
+}
+
+/// (ratio, amplitude, decay) triples
+fn inharmonic(partials: &[(f32, f32, f32)]) -> Vec<Harmonic> {
+    partials
+        .iter()
+        .map(|&(ratio, amplitude, decay)| Harmonic {
+            ratio,
+            amplitude,
+            decay,
+        })
+        .collect()
+}
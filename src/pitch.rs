@@ -0,0 +1,237 @@
+use std::{
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use ringbuf::{HeapCons, HeapProd, HeapRb, traits::*};
+
+use crate::{
+    sine_generator::{freq_to_note, note_name},
+    wav::Wav,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Pitch {
+    pub freq: f32,
+    /// 1 - the normalised difference at the chosen lag, 1.0 for a pure tone
+    pub clarity: f32,
+}
+
+impl Pitch {
+    /// Nearest equal-tempered MIDI note and the deviation from it in cents
+    pub fn nearest(&self) -> (u8, f32) {
+        let n = freq_to_note(self.freq);
+        let nearest = n.round().clamp(0., 127.);
+        (nearest as u8, (n - nearest) * 100.)
+    }
+
+    /// One-line tuner readout with a ±50 cent meter
+    pub fn readout(&self) -> String {
+        let (n, cents) = self.nearest();
+        let pos = ((cents + 50.) / 100. * 20.).round().clamp(0., 20.) as usize;
+        let meter: String = (0..=20)
+            .map(|idx| match idx {
+                _ if idx == pos => '●',
+                10 => '|',
+                _ => '-',
+            })
+            .collect();
+        format!(
+            "{:<4} {:8.2} Hz {cents:+6.1} cents  [{meter}]",
+            note_name(n),
+            self.freq
+        )
+    }
+}
+
+/// YIN fundamental frequency estimator (de Cheveigné & Kawahara, 2002)
+pub struct Yin {
+    sample_rate: u32,
+    threshold: f32,
+    min_lag: usize,
+    max_lag: usize,
+    /// Analysis frame, twice the longest period plus the parabola's margin
+    frame: usize,
+    diff: Vec<f32>,
+}
+
+impl Yin {
+    pub fn new(sample_rate: u32, min_freq: f32, max_freq: f32) -> Self {
+        let max_lag = (sample_rate as f32 / min_freq).ceil() as usize;
+        let min_lag = ((sample_rate as f32 / max_freq) as usize).max(2);
+        let frame = (2 * (max_lag + 2)).next_power_of_two();
+        Self {
+            sample_rate,
+            threshold: 0.15,
+            min_lag,
+            max_lag,
+            frame,
+            diff: vec![0.; frame / 2],
+        }
+    }
+
+    pub fn frame_len(&self) -> usize {
+        self.frame
+    }
+
+    pub fn detect(&mut self, frame: &[f32]) -> Option<Pitch> {
+        let w = (frame.len() / 2).min(self.frame / 2);
+        let max_lag = self.max_lag.min(w.saturating_sub(1));
+        if max_lag <= self.min_lag {
+            return None;
+        }
+
+        // squared difference function
+        self.diff[0] = 0.;
+        (1..=max_lag).for_each(|tau| {
+            self.diff[tau] = (0..w)
+                .map(|j| {
+                    let d = frame[j] - frame[j + tau];
+                    d * d
+                })
+                .sum();
+        });
+
+        // cumulative mean normalised difference
+        let mut running = 0.;
+        (1..=max_lag).for_each(|tau| {
+            running += self.diff[tau];
+            self.diff[tau] = if running > 0. {
+                self.diff[tau] * tau as f32 / running
+            } else {
+                1.
+            };
+        });
+        self.diff[0] = 1.;
+
+        let d = &self.diff[..=max_lag];
+        let mut tau = (self.min_lag..max_lag).find(|t| d[*t] < self.threshold)?;
+        while tau + 1 < max_lag && d[tau + 1] < d[tau] {
+            tau += 1;
+        }
+
+        let (a, b, c) = (d[tau - 1], d[tau], d[tau + 1]);
+        let denom = a - 2. * b + c;
+        let offset = if denom.abs() > 1e-9 {
+            (0.5 * (a - c) / denom).clamp(-0.5, 0.5)
+        } else {
+            0.
+        };
+
+        Some(Pitch {
+            freq: self.sample_rate as f32 / (tau as f32 + offset),
+            clarity: 1. - b,
+        })
+    }
+}
+
+/// Producer side of the input tap, written by the input stream callback
+pub type InputTap = HeapProd<f32>;
+
+/// Live tuner fed from the input stream
+pub struct Tuner {
+    yin: Yin,
+    history: Vec<f32>,
+    cons: HeapCons<f32>,
+}
+
+impl Tuner {
+    pub fn new(sample_rate: u32) -> (Self, InputTap) {
+        let yin = Yin::new(sample_rate, 30., 2000.);
+        let (prod, cons) = HeapRb::<f32>::new(yin.frame_len() * 4).split();
+        let tuner = Self {
+            history: vec![0.; yin.frame_len()],
+            yin,
+            cons,
+        };
+        (tuner, prod)
+    }
+
+    fn drain(&mut self) {
+        let mut fresh = [0.0_f32; 1024];
+        loop {
+            let n = self.cons.pop_slice(&mut fresh);
+            if n == 0 {
+                break;
+            }
+            self.history.drain(..n);
+            self.history.extend_from_slice(&fresh[..n]);
+        }
+    }
+
+    pub fn spawn(mut self, enabled: Arc<AtomicBool>) -> JoinHandle<()> {
+        thread::spawn(move || {
+            loop {
+                self.drain();
+                if enabled.load(Ordering::Relaxed) {
+                    match self.yin.detect(&self.history) {
+                        Some(pitch) if pitch.clarity > 0.8 => {
+                            print!("\r{}\x1b[K", pitch.readout())
+                        }
+                        _ => print!("\r--\x1b[K"),
+                    }
+                    let _ = std::io::Write::flush(&mut std::io::stdout());
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        })
+    }
+}
+
+/// Pitch track of a WAV file, one reading per `hop` samples. Frames near
+/// the end are shorter, so the last readings only find higher notes.
+pub fn track_wav(
+    path: impl AsRef<Path>,
+    hop: usize,
+) -> Result<Vec<(f32, Option<Pitch>)>, hound::Error> {
+    let wav = Wav::read(path)?;
+    let samples = wav.mono();
+    let mut yin = Yin::new(wav.sample_rate, 30., 2000.);
+    let frame = yin.frame_len();
+    Ok((0..samples.len())
+        .step_by(hop.max(1))
+        .map(|start| {
+            let time = start as f32 / wav.sample_rate as f32;
+            let end = (start + frame).min(samples.len());
+            (time, yin.detect(&samples[start..end]))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::TAU;
+
+    #[test]
+    fn track_wav_follows_notes() {
+        let sr = 48000;
+        // a second each of 40 Hz and 440 Hz
+        let samples: Vec<f32> = [40., 440.]
+            .iter()
+            .flat_map(|freq| (0..sr).map(move |i| 0.5 * (TAU * freq * i as f32 / sr as f32).sin()))
+            .collect();
+        let path = std::env::temp_dir().join(format!("track-wav-{}.wav", std::process::id()));
+        Wav::new(vec![samples.clone()], sr).write(&path).unwrap();
+        let hop = 4000;
+        let track = track_wav(&path, hop).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(track.len(), samples.len().div_ceil(hop));
+        let reading = |time: f32| {
+            let (_, pitch) = track.iter().find(|(t, _)| *t >= time).unwrap();
+            pitch.expect("pitch").freq
+        };
+        assert!((reading(0.3) - 40.).abs() < 0.5, "{}", reading(0.3));
+        assert!((reading(1.5) - 440.).abs() < 1., "{}", reading(1.5));
+        // the final, shortened frame is still analysed
+        let (last, pitch) = track.last().unwrap();
+        assert!(*last >= (samples.len() - hop) as f32 / sr as f32, "{last}");
+        assert!((pitch.expect("pitch").freq - 440.).abs() < 1.);
+    }
+}
@@ -1,22 +1,26 @@
-use std::{
-    f32,
-    ops::Add,
-    sync::{Arc, LazyLock, Mutex, MutexGuard},
-};
+use std::{f32, sync::LazyLock};
 
 use bit_set::BitSet;
 use cpal::{
     Host, SupportedStreamConfig,
     traits::{DeviceTrait, HostTrait},
 };
-use ndarray::ShapeBuilder;
 use std::f32::consts::PI;
 
-static HOST: LazyLock<Host> = std::sync::LazyLock::new(|| cpal::default_host());
+use crate::{
+    distortion::Distortion,
+    eq::ParametricEq,
+    harmonics::{Partial, PartialTable},
+    midi::{BEND_RANGE, MidiMessage},
+};
+
+static HOST: LazyLock<Host> = std::sync::LazyLock::new(cpal::default_host);
 pub static OUTPUT_DEVICE: LazyLock<cpal::Device> =
     std::sync::LazyLock::new(|| HOST.default_output_device().unwrap());
 pub static STREAM_CONFIG: LazyLock<SupportedStreamConfig> =
     std::sync::LazyLock::new(|| OUTPUT_DEVICE.default_output_config().unwrap());
+pub static INPUT_DEVICE: LazyLock<Option<cpal::Device>> =
+    std::sync::LazyLock::new(|| HOST.default_input_device());
 pub static MIDI: LazyLock<Vec<Vec<f32>>> =
     std::sync::LazyLock::new(|| notes(STREAM_CONFIG.sample_rate()));
 
@@ -26,45 +30,19 @@ pub struct SineGenerator {
     velocities: Vec<f32>,
     phases: Vec<Vec<f32>>,
     sample_rate: u32,
-    channels: u16,
     delta_angles: Vec<Vec<f32>>,
+    // per-partial level, per-sample decay factor and current envelope
+    levels: Vec<Vec<f32>>,
+    decays: Vec<Vec<f32>>,
+    envelopes: Vec<Vec<f32>>,
     volume: f32,
+    /// Frequency ratio of the pitch bend
+    bend: f32,
+    eq: Option<ParametricEq>,
+    voice_distortion: Vec<Distortion>,
 }
 
 impl SineGenerator {
-    pub fn new(frequencies: Vec<Vec<f32>>, config: SupportedStreamConfig) -> Self {
-        let mut phases = Vec::<Vec<f32>>::new();
-        phases.resize(frequencies.len(), Vec::<f32>::new());
-        let sample_rate = config.sample_rate();
-        let channels = config.channels();
-        let delta_angles: Vec<Vec<f32>> = MIDI.clone();
-        let mut velocities = Vec::<f32>::new();
-        velocities.resize(154, 0.0);
-
-        // frequencies
-        //   .iter()
-        //   .map(|freqs| {
-        //       freqs
-        //           .into_iter()
-        //           .map(|freq| 2. * PI * *freq / sample_rate as f32)
-        //           .collect::<Vec<f32>>()
-        //   })
-        //   .collect();
-
-        let volume = 0.5;
-        let note_mask = BitSet::new();
-
-        Self {
-            note_mask,
-            velocities,
-            phases,
-            sample_rate,
-            channels,
-            delta_angles,
-            volume,
-        }
-    }
-
     // pub fn freq(&mut self, freq: f32) {
     //     self.phases.push(0.0);
     //     self.delta_angles
@@ -77,7 +55,9 @@ impl SineGenerator {
         self.velocities[idx] = velocity as f32 / 127. * self.volume();
         // println!("freq: {freq}");
         if velocity > 0 {
-            let _ = self.note_mask.insert(idx);
+            if self.note_mask.insert(idx) {
+                self.envelopes[idx].clone_from(&self.levels[idx]);
+            }
         } else {
             let _ = self.note_mask.remove(idx);
         }
@@ -91,6 +71,79 @@ impl SineGenerator {
         self.volume
     }
 
+    pub fn set_pitch_bend(&mut self, semitones: f32) {
+        self.bend = 2.0_f32.powf(semitones / 12.);
+    }
+
+    pub fn all_notes_off(&mut self) {
+        self.note_mask.clear();
+    }
+
+    /// No notes held
+    pub fn is_idle(&self) -> bool {
+        self.note_mask.is_empty()
+    }
+
+    /// Plays a channel message: notes, volume (CC 7), all notes off (CC 120
+    /// and 123) and pitch bend
+    pub fn handle(&mut self, message: &MidiMessage) {
+        match *message {
+            MidiMessage::NoteOn { note, velocity, .. } => self.note(note, velocity),
+            MidiMessage::NoteOff { note, .. } => self.note(note, 0),
+            MidiMessage::ControlChange {
+                controller, value, ..
+            } => match controller {
+                7 => self.update_volume(value),
+                120 | 123 => self.all_notes_off(),
+                _ => (),
+            },
+            MidiMessage::PitchBend { value, .. } => {
+                self.set_pitch_bend(value as f32 / 8192. * BEND_RANGE)
+            }
+            _ => (),
+        }
+    }
+
+    pub fn set_eq(&mut self, eq: Option<ParametricEq>) {
+        self.eq = eq;
+    }
+
+    /// Replaces every note's partials with `table`, scaled to the note's
+    /// fundamental. Partials above Nyquist are dropped.
+    pub fn set_partials(&mut self, table: &PartialTable) {
+        let nyquist = self.sample_rate as f32 / 2.;
+        for idx in 1..self.delta_angles.len() {
+            let freq = note(idx as f32);
+            let partials: Vec<&Partial> = table
+                .partials
+                .iter()
+                .filter(|p| freq * p.ratio < nyquist)
+                .collect();
+            self.delta_angles[idx] = partials
+                .iter()
+                .map(|p| delta(freq * p.ratio, self.sample_rate))
+                .collect();
+            self.levels[idx] = partials.iter().map(|p| p.amplitude).collect();
+            self.decays[idx] = partials
+                .iter()
+                .map(|p| 10.0_f32.powf(p.decay / 20. / self.sample_rate as f32))
+                .collect();
+            self.envelopes[idx] = self.levels[idx].clone();
+            if let Some(phases) = self.phases.get_mut(idx) {
+                phases.resize(partials.len(), 0.0);
+            }
+        }
+    }
+
+    /// Shapes every note separately, before the instrument EQ
+    pub fn set_voice_distortion(&mut self, distortion: Option<Distortion>) {
+        self.voice_distortion.clear();
+        if let Some(distortion) = distortion {
+            self.voice_distortion
+                .resize(self.delta_angles.len(), distortion);
+        }
+    }
+
     // pub fn partial(&mut self, n: u8, freq: f32, partial: usize, amplitude: f32) {
     //     let idx = n as usize;
     //     let mut ks = Vec::<f32>::new();
@@ -110,6 +163,37 @@ impl SineGenerator {
         SineGeneratorBuilder(self)
     }
 
+    /// A generator for offline rendering, independent of the output device
+    pub fn with_sample_rate(sample_rate: u32) -> Self {
+        let delta_angles = notes(sample_rate);
+        let phases = delta_angles
+            .iter()
+            .map(|angles| vec![0.0; angles.len()])
+            .collect();
+
+        Self {
+            note_mask: BitSet::new(),
+            velocities: vec![0.0; 154],
+            phases,
+            sample_rate,
+            levels: ones_like(&delta_angles),
+            decays: ones_like(&delta_angles),
+            envelopes: ones_like(&delta_angles),
+            delta_angles,
+            volume: 0.5,
+            bend: 1.,
+            eq: None,
+            voice_distortion: Vec::new(),
+        }
+    }
+
+    /// Renders `seconds` of the currently held notes, scaled by the volume
+    pub fn render(&mut self, seconds: f32) -> Vec<f32> {
+        let len = (seconds * self.sample_rate as f32) as usize;
+        let volume = self.volume;
+        self.by_ref().take(len).map(|s| s * volume).collect()
+    }
+
     pub fn default(config: SupportedStreamConfig) -> Self {
         let mut frequencies = Vec::<Vec<f32>>::new();
         frequencies.resize(153, Vec::<f32>::new());
@@ -120,7 +204,6 @@ impl SineGenerator {
             phase.resize(MIDI[idx].len(), 0.0);
         });
         let sample_rate = config.sample_rate();
-        let channels = config.channels();
         let delta_angles = MIDI.clone();
         // delta_angles.resize(153, Vec::<f32>::new());
         // delta_angles
@@ -140,9 +223,14 @@ impl SineGenerator {
             velocities,
             phases,
             sample_rate,
-            channels,
+            levels: ones_like(&delta_angles),
+            decays: ones_like(&delta_angles),
+            envelopes: ones_like(&delta_angles),
             delta_angles,
             volume,
+            bend: 1.,
+            eq: None,
+            voice_distortion: Vec::new(),
         }
     }
 }
@@ -157,22 +245,38 @@ impl Iterator for SineGenerator {
                 let phase = self.phases[idx].iter_mut();
                 let delta_angles = self.delta_angles[idx].iter_mut();
                 let velocity = self.velocities[idx];
+                let bend = self.bend;
+                let envelopes = self.envelopes[idx].iter_mut().zip(self.decays[idx].iter());
 
                 let next_phase = phase.zip(delta_angles).scan(0.0, |_state, (p, a)| {
-                    *p += *a;
+                    *p += *a * bend;
                     if *p > 2. * PI {
                         *p -= 2. * PI;
                     }
                     Some(p)
                 });
 
-                next_phase.fold(0.0, |acc, p| acc + f32::sin(*p) * velocity)
+                let voice = next_phase
+                    .zip(envelopes)
+                    .fold(0.0, |acc, (p, (env, decay))| {
+                        let s = f32::sin(*p) * *env;
+                        // flush to zero before the envelope goes denormal
+                        *env = if *env > 1e-6 { *env * decay } else { 0. };
+                        acc + s * velocity
+                    });
+                match self.voice_distortion.get_mut(idx) {
+                    Some(distortion) => distortion.process(voice),
+                    None => voice,
+                }
             })
             .sum();
         if sin > 0. {
             // println!("sin: {sin}");
         }
-        Some(sin)
+        match self.eq.as_mut() {
+            Some(eq) => Some(eq.process(sin)),
+            None => Some(sin),
+        }
     }
 }
 
@@ -199,35 +303,43 @@ impl SineGeneratorBuilder {
     //     self
     // }
 
+    pub fn eq(mut self, eq: ParametricEq) -> Self {
+        self.0.set_eq(Some(eq));
+        self
+    }
+
+    pub fn voice_distortion(mut self, distortion: Distortion) -> Self {
+        self.0.set_voice_distortion(Some(distortion));
+        self
+    }
+
     pub fn finish(self) -> SineGenerator {
         self.0
     }
 }
 
+fn ones_like(v: &[Vec<f32>]) -> Vec<Vec<f32>> {
+    v.iter().map(|inner| vec![1.0; inner.len()]).collect()
+}
+
 pub fn note(n: f32) -> f32 {
     440. * 2.0_f32.powf((n - 69.) / 12.)
 }
 
-/// Delta angle for partial
-pub fn partial(freq: f32, partial: usize, sample_rate: u32, amplitude: f32) -> f32 {
-    let mut ks = Vec::<f32>::new();
-    ks.resize(partial, 0.0);
-    let t = 2. * PI * freq / sample_rate as f32;
-    let f: f32 = ks
-        .iter()
-        .enumerate()
-        .map(|(k, _)| amplitude * f32::cos(2. * PI * k as f32 * freq * t + amplitude.abs()))
-        .sum();
-
-    f
+/// Inverse of `note`: the fractional MIDI note number of `freq`
+pub fn freq_to_note(freq: f32) -> f32 {
+    69. + 12. * (freq / 440.).log2()
 }
 
-pub fn delta(freq: f32, sample_rate: u32) -> f32 {
-    2. * PI * freq / sample_rate as f32
+pub fn note_name(n: u8) -> String {
+    const NAMES: [&str; 12] = [
+        "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+    ];
+    format!("{}{}", NAMES[n as usize % 12], n as i32 / 12 - 1)
 }
 
-pub fn partial_delta(freq: f32, part: usize, sample_rate: u32, amplitude: f32) -> f32 {
-    delta(partial(freq, part, sample_rate, amplitude), sample_rate)
+pub fn delta(freq: f32, sample_rate: u32) -> f32 {
+    2. * PI * freq / sample_rate as f32
 }
 
 pub fn notes(sample_rate: u32) -> Vec<Vec<f32>> {
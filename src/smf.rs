@@ -0,0 +1,974 @@
+use std::{error::Error, fmt::Write as _, fs, path::Path, time::Instant};
+
+/// Microseconds per quarter note at 120 BPM, the SMF default
+pub const DEFAULT_TEMPO: u32 = 500_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Note {
+    pub pitch: u8,
+    pub velocity: u8,
+    /// Seconds
+    pub start: f32,
+    pub end: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Division {
+    /// Ticks per quarter note
+    Ppq(u16),
+    /// Frames per second (24, 25, 29 or 30) and ticks per frame
+    Smpte { fps: u8, ticks_per_frame: u8 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EventKind {
+    /// Channel message: status byte then data bytes
+    Midi(Vec<u8>),
+    /// Meta event type and payload
+    Meta(u8, Vec<u8>),
+    /// System exclusive payload after the 0xF0, usually ending in 0xF7
+    SysEx(Vec<u8>),
+    /// Bytes sent as they are, from an 0xF7 escape event
+    Escape(Vec<u8>),
+}
+
+/// An event at an absolute tick
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackEvent {
+    pub tick: u32,
+    pub kind: EventKind,
+}
+
+impl TrackEvent {
+    pub fn midi(tick: u32, bytes: &[u8]) -> Self {
+        Self {
+            tick,
+            kind: EventKind::Midi(bytes.to_vec()),
+        }
+    }
+
+    pub fn tempo(tick: u32, micros_per_quarter: u32) -> Self {
+        Self {
+            tick,
+            kind: EventKind::Meta(0x51, micros_per_quarter.to_be_bytes()[1..].to_vec()),
+        }
+    }
+
+    /// `denominator` is a power of two, e.g. 4 for x/4
+    pub fn time_signature(tick: u32, numerator: u8, denominator: u8) -> Self {
+        Self {
+            tick,
+            kind: EventKind::Meta(
+                0x58,
+                vec![numerator, denominator.max(1).ilog2() as u8, 24, 8],
+            ),
+        }
+    }
+
+    pub fn track_name(tick: u32, name: &str) -> Self {
+        Self {
+            tick,
+            kind: EventKind::Meta(0x03, name.as_bytes().to_vec()),
+        }
+    }
+}
+
+/// A Standard MIDI File
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Smf {
+    pub format: u16,
+    pub division: Division,
+    pub tracks: Vec<Vec<TrackEvent>>,
+}
+
+fn write_vlq(out: &mut Vec<u8>, mut value: u32) {
+    let mut bytes = vec![(value & 0x7f) as u8];
+    value >>= 7;
+    while value > 0 {
+        bytes.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    out.extend(bytes.iter().rev());
+}
+
+fn read_vlq(data: &[u8], pos: &mut usize) -> Result<u32, Box<dyn Error>> {
+    let mut value = 0_u32;
+    for _ in 0..4 {
+        let byte = *data.get(*pos).ok_or("truncated variable-length quantity")?;
+        *pos += 1;
+        value = value << 7 | (byte & 0x7f) as u32;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err("variable-length quantity longer than 4 bytes".into())
+}
+
+fn take<'a>(data: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8], Box<dyn Error>> {
+    let bytes = data
+        .get(*pos..*pos + len)
+        .ok_or("event runs past the end of its track")?;
+    *pos += len;
+    Ok(bytes)
+}
+
+/// Events of one `MTrk` chunk, with absolute ticks
+fn parse_track(data: &[u8]) -> Result<Vec<TrackEvent>, Box<dyn Error>> {
+    let mut events = Vec::new();
+    let mut pos = 0;
+    let mut tick = 0_u32;
+    let mut running: Option<u8> = None;
+    while pos < data.len() {
+        tick = tick
+            .checked_add(read_vlq(data, &mut pos)?)
+            .ok_or("track longer than 2^32 ticks")?;
+        let first = *data.get(pos).ok_or("truncated event")?;
+        let kind = match first {
+            0xff => {
+                let kind = *data.get(pos + 1).ok_or("truncated meta event")?;
+                pos += 2;
+                let len = read_vlq(data, &mut pos)? as usize;
+                running = None;
+                EventKind::Meta(kind, take(data, &mut pos, len)?.to_vec())
+            }
+            0xf0 | 0xf7 => {
+                pos += 1;
+                let len = read_vlq(data, &mut pos)? as usize;
+                let payload = take(data, &mut pos, len)?.to_vec();
+                running = None;
+                if first == 0xf0 {
+                    EventKind::SysEx(payload)
+                } else {
+                    EventKind::Escape(payload)
+                }
+            }
+            _ => {
+                // running status: data bytes reuse the last channel status
+                let status = if first & 0x80 != 0 {
+                    pos += 1;
+                    first
+                } else {
+                    running.ok_or("data byte without a running status")?
+                };
+                if !(0x80..0xf0).contains(&status) {
+                    return Err(format!("unexpected status {status:#04x} in track").into());
+                }
+                running = Some(status);
+                let len = if matches!(status & 0xf0, 0xc0 | 0xd0) {
+                    1
+                } else {
+                    2
+                };
+                let mut bytes = vec![status];
+                bytes.extend(take(data, &mut pos, len)?);
+                EventKind::Midi(bytes)
+            }
+        };
+        let end = kind == EventKind::Meta(0x2f, Vec::new());
+        events.push(TrackEvent { tick, kind });
+        if end {
+            break;
+        }
+    }
+    Ok(events)
+}
+
+/// Chunk ID and data
+type Chunk<'a> = (&'a [u8], &'a [u8]);
+
+impl Smf {
+    /// Parses format 0, 1 or 2; unknown chunks are skipped
+    pub fn parse(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let mut pos = 0;
+        let mut chunk = || -> Result<Option<Chunk>, Box<dyn Error>> {
+            if pos + 8 > bytes.len() {
+                return Ok(None);
+            }
+            let id = &bytes[pos..pos + 4];
+            let len = u32::from_be_bytes(bytes[pos + 4..pos + 8].try_into()?) as usize;
+            let data = bytes
+                .get(pos + 8..pos + 8 + len)
+                .ok_or("chunk runs past the end of the file")?;
+            pos += 8 + len;
+            Ok(Some((id, data)))
+        };
+
+        let (id, header) = chunk()?.ok_or("file too short")?;
+        if id != b"MThd" || header.len() < 6 {
+            return Err("not a Standard MIDI File".into());
+        }
+        let format = u16::from_be_bytes([header[0], header[1]]);
+        let count = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let division = if header[4] & 0x80 != 0 {
+            Division::Smpte {
+                fps: (header[4] as i8).unsigned_abs(),
+                ticks_per_frame: header[5],
+            }
+        } else {
+            Division::Ppq(u16::from_be_bytes([header[4], header[5]]))
+        };
+        if format > 2 {
+            return Err(format!("unsupported SMF format {format}").into());
+        }
+
+        let mut tracks = Vec::with_capacity(count);
+        while let Some((id, data)) = chunk()? {
+            if id == b"MTrk" {
+                tracks.push(parse_track(data)?);
+            }
+        }
+        Ok(Self {
+            format,
+            division,
+            tracks,
+        })
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        Self::parse(&fs::read(path)?)
+    }
+
+    /// Tracks that play together: all of them, except in format 2 where
+    /// each is its own song and `track` picks one (the first by default)
+    fn playing(&self, track: Option<usize>) -> Vec<&[TrackEvent]> {
+        match (self.format, track) {
+            (_, Some(track)) => self.tracks.get(track).into_iter().map(|t| &t[..]).collect(),
+            (2, None) => self.tracks.first().into_iter().map(|t| &t[..]).collect(),
+            _ => self.tracks.iter().map(|t| &t[..]).collect(),
+        }
+    }
+
+    /// Tempo changes of the tracks that play. In formats 0 and 1 they can
+    /// sit in any track and apply to all.
+    pub fn tempo_map(&self, track: Option<usize>) -> TempoMap {
+        let tracks = match (self.format, track) {
+            (2, _) => self.playing(track),
+            _ => self.playing(None),
+        };
+        let mut changes: Vec<(u32, u32)> = tracks
+            .iter()
+            .flat_map(|t| t.iter())
+            .filter_map(|e| match &e.kind {
+                EventKind::Meta(0x51, data) if data.len() == 3 => {
+                    Some((e.tick, u32::from_be_bytes([0, data[0], data[1], data[2]])))
+                }
+                _ => None,
+            })
+            .collect();
+        changes.sort_by_key(|(tick, _)| *tick);
+        TempoMap::new(self.division, &changes)
+    }
+
+    pub fn time_signatures(&self, track: Option<usize>) -> Vec<TimeSignature> {
+        let mut signatures: Vec<TimeSignature> = self
+            .playing(track)
+            .iter()
+            .flat_map(|t| t.iter())
+            .filter_map(|e| match &e.kind {
+                EventKind::Meta(0x58, data) if data.len() >= 2 => Some(TimeSignature {
+                    tick: e.tick,
+                    numerator: data[0],
+                    denominator: 1 << data[1].min(7),
+                }),
+                _ => None,
+            })
+            .collect();
+        signatures.sort_by_key(|s| s.tick);
+        signatures
+    }
+
+    /// Channel messages of the playing tracks in seconds, ready for
+    /// `SmfPlayer`
+    pub fn sequence(&self, track: Option<usize>) -> Sequence {
+        let tempo = self.tempo_map(track);
+        let tracks = self.playing(track);
+        let mut events: Vec<(f64, Vec<u8>)> = tracks
+            .iter()
+            .flat_map(|t| t.iter())
+            .filter_map(|e| match &e.kind {
+                EventKind::Midi(bytes) => Some((tempo.seconds(e.tick), bytes.clone())),
+                _ => None,
+            })
+            .collect();
+        events.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let last_tick = tracks
+            .iter()
+            .filter_map(|t| t.last().map(|e| e.tick))
+            .max()
+            .unwrap_or(0);
+        Sequence {
+            events,
+            length: tempo.seconds(last_tick),
+            time_signatures: self.time_signatures(track),
+            tempo,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend(b"MThd");
+        out.extend(6_u32.to_be_bytes());
+        out.extend(self.format.to_be_bytes());
+        out.extend((self.tracks.len() as u16).to_be_bytes());
+        match self.division {
+            Division::Ppq(ppq) => out.extend((ppq & 0x7fff).to_be_bytes()),
+            Division::Smpte {
+                fps,
+                ticks_per_frame,
+            } => out.extend([(-(fps as i8)) as u8, ticks_per_frame]),
+        }
+
+        self.tracks.iter().for_each(|events| {
+            let mut events = events.clone();
+            // stable, so simultaneous events keep their order
+            events.sort_by_key(|e| e.tick);
+            // a parsed end-of-track only sets the track's length
+            let end = events.last().map_or(0, |e| e.tick);
+            events.retain(|e| e.kind != EventKind::Meta(0x2f, Vec::new()));
+            let mut data = Vec::new();
+            let mut last = 0;
+            events.iter().for_each(|event| {
+                write_vlq(&mut data, event.tick - last);
+                last = event.tick;
+                match &event.kind {
+                    EventKind::Midi(bytes) => data.extend(bytes),
+                    EventKind::Meta(kind, payload) => {
+                        data.extend([0xff, *kind]);
+                        write_vlq(&mut data, payload.len() as u32);
+                        data.extend(payload);
+                    }
+                    EventKind::SysEx(payload) => {
+                        data.push(0xf0);
+                        write_vlq(&mut data, payload.len() as u32);
+                        data.extend(payload);
+                    }
+                    EventKind::Escape(payload) => {
+                        data.push(0xf7);
+                        write_vlq(&mut data, payload.len() as u32);
+                        data.extend(payload);
+                    }
+                }
+            });
+            write_vlq(&mut data, end - last);
+            data.extend([0xff, 0x2f, 0x00]);
+
+            out.extend(b"MTrk");
+            out.extend((data.len() as u32).to_be_bytes());
+            out.extend(data);
+        });
+        out
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+
+    /// Format 0 file of `notes` on channel 1 at a fixed tempo
+    pub fn from_notes(notes: &[Note], bpm: f32, ppq: u16) -> Self {
+        let ticks_per_second = ppq as f32 * bpm / 60.;
+        let tick = |seconds: f32| (seconds * ticks_per_second).round().max(0.) as u32;
+        let mut events = vec![TrackEvent::tempo(0, (60_000_000. / bpm) as u32)];
+        // note-offs first at equal ticks so repeated notes retrigger
+        let mut offs: Vec<TrackEvent> = notes
+            .iter()
+            .map(|n| TrackEvent::midi(tick(n.end), &[0x80, n.pitch, 0]))
+            .collect();
+        offs.extend(
+            notes
+                .iter()
+                .map(|n| TrackEvent::midi(tick(n.start), &[0x90, n.pitch, n.velocity.max(1)])),
+        );
+        offs.sort_by_key(|e| e.tick);
+        events.extend(offs);
+        Self {
+            format: 0,
+            division: Division::Ppq(ppq),
+            tracks: vec![events],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeSignature {
+    pub tick: u32,
+    pub numerator: u8,
+    pub denominator: u8,
+}
+
+/// Converts between ticks and seconds
+#[derive(Debug, Clone, PartialEq)]
+pub struct TempoMap {
+    division: Division,
+    /// Tick, seconds at that tick and microseconds per quarter from there
+    changes: Vec<(u32, f64, u32)>,
+}
+
+impl TempoMap {
+    /// `changes` are (tick, microseconds per quarter), sorted by tick
+    pub fn new(division: Division, changes: &[(u32, u32)]) -> Self {
+        let mut map = Self {
+            division,
+            changes: vec![(0, 0., DEFAULT_TEMPO)],
+        };
+        changes.iter().for_each(|(tick, tempo)| {
+            let seconds = map.seconds(*tick);
+            if map.changes.last().is_some_and(|c| c.0 == *tick) {
+                map.changes.pop();
+            }
+            map.changes.push((*tick, seconds, *tempo));
+        });
+        map
+    }
+
+    fn seconds_per_tick(&self, tempo: u32) -> f64 {
+        match self.division {
+            Division::Ppq(ppq) => tempo as f64 / 1e6 / ppq.max(1) as f64,
+            Division::Smpte {
+                fps,
+                ticks_per_frame,
+            } => {
+                // 29 means 30 drop-frame, 29.97 frames a second
+                let fps = if fps == 29 { 29.97 } else { fps as f64 };
+                1. / (fps * ticks_per_frame.max(1) as f64)
+            }
+        }
+    }
+
+    pub fn seconds(&self, tick: u32) -> f64 {
+        let idx = self.changes.partition_point(|c| c.0 <= tick) - 1;
+        let (start, seconds, tempo) = self.changes[idx];
+        seconds + (tick - start) as f64 * self.seconds_per_tick(tempo)
+    }
+
+    pub fn ticks(&self, seconds: f64) -> f64 {
+        let idx = self.changes.partition_point(|c| c.1 <= seconds).max(1) - 1;
+        let (start, at, tempo) = self.changes[idx];
+        start as f64 + (seconds - at) / self.seconds_per_tick(tempo)
+    }
+
+    /// Quarter notes per minute at `tick`, `None` with SMPTE timing
+    #[allow(dead_code)] // for the interpreter, which follows the file's tempo
+    pub fn bpm(&self, tick: u32) -> Option<f64> {
+        let idx = self.changes.partition_point(|c| c.0 <= tick) - 1;
+        matches!(self.division, Division::Ppq(_)).then(|| 60e6 / self.changes[idx].2 as f64)
+    }
+
+    /// Seconds at a position in quarter notes, `None` with SMPTE timing
+    #[allow(dead_code)] // for the interpreter, which locates by song position
+    pub fn quarters_seconds(&self, quarters: f64) -> Option<f64> {
+        let Division::Ppq(ppq) = self.division else {
+            return None;
+        };
+        let ticks = (quarters.max(0.) * ppq as f64).round();
+        Some(self.seconds(ticks.min(u32::MAX as f64) as u32))
+    }
+}
+
+/// What `SmfPlayer` plays: channel messages with their times in seconds
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sequence {
+    pub events: Vec<(f64, Vec<u8>)>,
+    /// Seconds to the end of the longest track
+    pub length: f64,
+    pub tempo: TempoMap,
+    pub time_signatures: Vec<TimeSignature>,
+}
+
+impl Sequence {
+    /// Bar and beat, both from 1, at `seconds`; `None` with SMPTE timing
+    pub fn bar_beat(&self, seconds: f64) -> Option<(u32, f64)> {
+        let Division::Ppq(ppq) = self.tempo.division else {
+            return None;
+        };
+        let tick = self.tempo.ticks(seconds).max(0.);
+        let mut signatures = self.time_signatures.clone();
+        if signatures.first().is_none_or(|s| s.tick > 0) {
+            signatures.insert(
+                0,
+                TimeSignature {
+                    tick: 0,
+                    numerator: 4,
+                    denominator: 4,
+                },
+            );
+        }
+        let mut bar = 0.;
+        for (idx, sig) in signatures.iter().enumerate() {
+            let beat_ticks = ppq as f64 * 4. / sig.denominator.max(1) as f64;
+            let bar_ticks = beat_ticks * sig.numerator.max(1) as f64;
+            let end = signatures.get(idx + 1).map_or(f64::MAX, |s| s.tick as f64);
+            if tick < end {
+                let bars = (tick - sig.tick as f64) / bar_ticks;
+                let whole = bars.floor();
+                let beat = (bars - whole) * sig.numerator.max(1) as f64;
+                return Some(((bar + whole) as u32 + 1, beat + 1.));
+            }
+            // a change mid-bar starts a new bar
+            bar += ((end - sig.tick as f64) / bar_ticks).ceil();
+        }
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transport {
+    Play,
+    Pause,
+    /// Pause and rewind
+    Stop,
+    /// Seconds into the sequence
+    Seek(f64),
+    Loop(bool),
+    /// Playback speed, 1.0 is the file's tempo
+    TempoScale(f64),
+}
+
+/// Slowest and fastest `Transport::TempoScale`
+pub const MIN_TEMPO_SCALE: f64 = 0.05;
+pub const MAX_TEMPO_SCALE: f64 = 20.;
+
+/// Plays a `Sequence` one sample at a time, so events land on the sample
+/// they're due. Stopping, seeking and looping release held notes.
+#[derive(Debug, Clone)]
+pub struct SmfPlayer {
+    sequence: Sequence,
+    sample_rate: u32,
+    position: f64,
+    next: usize,
+    playing: bool,
+    looping: bool,
+    tempo_scale: f64,
+    /// (status, note) of the notes on
+    held: Vec<(u8, u8)>,
+    /// Note-offs to send on the next tick
+    pending: Vec<[u8; 3]>,
+}
+
+impl SmfPlayer {
+    pub fn new(sequence: Sequence, sample_rate: u32) -> Self {
+        Self {
+            sequence,
+            sample_rate,
+            position: 0.,
+            next: 0,
+            playing: false,
+            looping: false,
+            tempo_scale: 1.,
+            held: Vec::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn sequence(&self) -> &Sequence {
+        &self.sequence
+    }
+
+    /// Seconds into the sequence, at the file's tempo
+    pub fn position(&self) -> f64 {
+        self.position
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    pub fn is_looping(&self) -> bool {
+        self.looping
+    }
+
+    pub fn tempo_scale(&self) -> f64 {
+        self.tempo_scale
+    }
+
+    /// Stopped at the end, not looping
+    pub fn is_finished(&self) -> bool {
+        !self.playing && self.position >= self.sequence.length
+    }
+
+    fn release(&mut self) {
+        let offs = self
+            .held
+            .drain(..)
+            .map(|(status, note)| [0x80 | (status & 0x0f), note, 0]);
+        self.pending.extend(offs);
+    }
+
+    fn seek(&mut self, seconds: f64) {
+        self.release();
+        self.position = seconds.clamp(0., self.sequence.length);
+        self.next = self
+            .sequence
+            .events
+            .partition_point(|(time, _)| *time < self.position);
+    }
+
+    pub fn apply(&mut self, transport: Transport) {
+        match transport {
+            Transport::Play => {
+                if self.is_finished() {
+                    self.seek(0.);
+                }
+                self.playing = true;
+            }
+            Transport::Pause => {
+                self.playing = false;
+                self.release();
+            }
+            Transport::Stop => {
+                self.playing = false;
+                self.seek(0.);
+            }
+            Transport::Seek(seconds) => self.seek(seconds),
+            Transport::Loop(on) => self.looping = on,
+            Transport::TempoScale(scale) => {
+                self.tempo_scale = scale.clamp(MIN_TEMPO_SCALE, MAX_TEMPO_SCALE)
+            }
+        }
+    }
+
+    /// Sends the events due by `until`
+    fn fire(&mut self, until: f64, f: &mut impl FnMut(&[u8])) {
+        while let Some((time, bytes)) = self.sequence.events.get(self.next) {
+            if *time > until {
+                break;
+            }
+            let (status, note) = (bytes[0], bytes.get(1).copied().unwrap_or(0));
+            let velocity = bytes.get(2).copied().unwrap_or(0);
+            match status & 0xf0 {
+                0x90 if velocity > 0 => self.held.push((status, note)),
+                0x80 | 0x90 => self
+                    .held
+                    .retain(|h| (h.0 & 0x0f, h.1) != (status & 0x0f, note)),
+                _ => (),
+            }
+            f(bytes);
+            self.next += 1;
+        }
+    }
+
+    /// Advances one sample, calling `f` with each message due
+    pub fn tick(&mut self, mut f: impl FnMut(&[u8])) {
+        self.pending.drain(..).for_each(|off| f(&off));
+        if !self.playing {
+            return;
+        }
+        self.fire(self.position, &mut f);
+        self.position += self.tempo_scale / self.sample_rate as f64;
+        if self.position >= self.sequence.length {
+            // the events right at the end play before the wrap or stop
+            self.fire(self.sequence.length, &mut f);
+            if self.looping && self.sequence.length > 0. {
+                self.seek(self.position - self.sequence.length);
+                // including those at the very start
+                self.next = 0;
+            } else {
+                self.playing = false;
+                self.release();
+                self.position = self.sequence.length;
+            }
+        }
+    }
+}
+
+/// Records live MIDI into a format-1 file: a tempo track with the tempo and
+/// time signature, then the performance. The clock runs from `start`; events
+/// count while punched in and, if a punch range is set, inside it. Punching
+/// out ends the notes still held.
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    bpm: f64,
+    numerator: u8,
+    denominator: u8,
+    ppq: u16,
+    started: Option<Instant>,
+    punched_in: bool,
+    /// Seconds
+    punch_range: Option<(f64, f64)>,
+    events: Vec<(f64, Vec<u8>)>,
+    /// (status, note) of the notes on
+    held: Vec<(u8, u8)>,
+}
+
+impl Recorder {
+    /// `denominator` is a power of two, e.g. 4 for x/4
+    pub fn new(bpm: f64, numerator: u8, denominator: u8, ppq: u16) -> Self {
+        Self {
+            bpm: bpm.max(1.),
+            numerator: numerator.max(1),
+            denominator: denominator.max(1),
+            ppq,
+            started: None,
+            punched_in: false,
+            punch_range: None,
+            events: Vec::new(),
+            held: Vec::new(),
+        }
+    }
+
+    pub fn bpm(&self) -> f64 {
+        self.bpm
+    }
+
+    pub fn bar_seconds(&self) -> f64 {
+        self.numerator as f64 * 4. / self.denominator as f64 * 60. / self.bpm
+    }
+
+    /// Records only between two bars, counted from 1; the end bar is excluded
+    pub fn set_punch_bars(&mut self, range: Option<(f64, f64)>) {
+        self.punch_range = range.map(|(from, to)| {
+            (
+                (from - 1.) * self.bar_seconds(),
+                (to - 1.) * self.bar_seconds(),
+            )
+        });
+    }
+
+    /// Starts a new take, punched in
+    pub fn start(&mut self, now: Instant) {
+        self.started = Some(now);
+        self.punched_in = true;
+        self.events.clear();
+        self.held.clear();
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.started.is_some()
+    }
+
+    pub fn is_punched_in(&self) -> bool {
+        self.punched_in
+    }
+
+    /// Seconds into the take
+    pub fn position(&self, now: Instant) -> f64 {
+        self.started.map_or(0., |start| {
+            now.saturating_duration_since(start).as_secs_f64()
+        })
+    }
+
+    /// Bar and beat, both from 1
+    pub fn bar_beat(&self, now: Instant) -> (u32, f64) {
+        let bars = self.position(now) / self.bar_seconds();
+        let beat = bars.fract() * self.numerator as f64;
+        (bars as u32 + 1, beat + 1.)
+    }
+
+    pub fn punch_in(&mut self) {
+        self.punched_in = self.is_recording();
+    }
+
+    pub fn punch_out(&mut self, now: Instant) {
+        let end = self.punch_range.map_or(f64::MAX, |(_, end)| end);
+        self.release(self.position(now).min(end));
+        self.punched_in = false;
+    }
+
+    fn release(&mut self, time: f64) {
+        let offs: Vec<_> = self
+            .held
+            .drain(..)
+            .map(|(status, note)| (time, vec![0x80 | (status & 0x0f), note, 0]))
+            .collect();
+        self.events.extend(offs);
+    }
+
+    /// Takes a complete channel or SysEx message received at `now`
+    pub fn record(&mut self, now: Instant, bytes: &[u8]) {
+        let Some(&status) = bytes.first() else {
+            return;
+        };
+        if !self.is_recording() || !(0x80..=0xf0).contains(&status) {
+            return;
+        }
+        let time = self.position(now);
+        let (start, end) = self.punch_range.unwrap_or((0., f64::MAX));
+        if self.punched_in && time >= end {
+            self.release(end);
+        }
+        let note = bytes.get(1).copied().unwrap_or(0);
+        let velocity = bytes.get(2).copied().unwrap_or(0);
+        let key = (status & 0x0f, note);
+        let is_off = status & 0xf0 == 0x80 || (status & 0xf0 == 0x90 && velocity == 0);
+        if is_off {
+            // a note started in the punch ends even after it
+            if let Some(idx) = self.held.iter().position(|h| (h.0 & 0x0f, h.1) == key) {
+                self.held.remove(idx);
+                self.events.push((time.min(end), bytes.to_vec()));
+            }
+            return;
+        }
+        if !self.punched_in || time < start || time >= end {
+            return;
+        }
+        if status & 0xf0 == 0x90 {
+            self.held.push((status, note));
+        }
+        self.events.push((time, bytes.to_vec()));
+    }
+
+    /// Ends the take, closing any held notes, and returns it
+    pub fn stop(&mut self, now: Instant) -> Smf {
+        self.punch_out(now);
+        self.started = None;
+        let ticks_per_second = self.ppq as f64 * self.bpm / 60.;
+        let mut conductor = vec![
+            TrackEvent::track_name(0, "tempo"),
+            TrackEvent::tempo(0, (60_000_000. / self.bpm).round() as u32),
+            TrackEvent::time_signature(0, self.numerator, self.denominator),
+        ];
+        let mut performance = vec![TrackEvent::track_name(0, "performance")];
+        performance.extend(self.events.drain(..).map(|(time, bytes)| {
+            let tick = (time * ticks_per_second).round() as u32;
+            match bytes[0] {
+                0xf0 => TrackEvent {
+                    tick,
+                    kind: EventKind::SysEx(bytes[1..].to_vec()),
+                },
+                _ => TrackEvent::midi(tick, &bytes),
+            }
+        }));
+        let end = performance.last().map_or(0, |e| e.tick);
+        conductor.push(TrackEvent {
+            tick: end,
+            kind: EventKind::Meta(0x2f, Vec::new()),
+        });
+        Smf {
+            format: 1,
+            division: Division::Ppq(self.ppq),
+            tracks: vec![conductor, performance],
+        }
+    }
+}
+
+/// Onsets closer than this count as one chord in `note_list`
+const CHORD_SECONDS: f32 = 0.03;
+
+/// `PITCHES`/`DURATIONS` lists for the interpreter, which plays one note
+/// after another: the highest note of each chord, lasting until the next
+pub fn note_list(notes: &[Note]) -> String {
+    let mut notes = notes.to_vec();
+    notes.sort_by(|a, b| a.start.total_cmp(&b.start));
+    let mut melody: Vec<Note> = Vec::new();
+    notes.into_iter().for_each(|note| match melody.last_mut() {
+        Some(last) if note.start - last.start < CHORD_SECONDS => {
+            if note.pitch > last.pitch {
+                *last = note;
+            }
+        }
+        _ => melody.push(note),
+    });
+
+    let mut out = String::from("PITCHES");
+    melody.iter().for_each(|n| {
+        let _ = write!(out, " {}", n.pitch);
+    });
+    out.push_str("\nDURATIONS");
+    melody.iter().enumerate().for_each(|(idx, n)| {
+        let end = melody.get(idx + 1).map_or(n.end, |next| next.start);
+        let _ = write!(out, " {:.4}", end - n.start);
+    });
+    out.push('\n');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A format 0 file of one track with `data` as its events
+    fn file(division: [u8; 2], data: &[u8]) -> Vec<u8> {
+        let mut bytes = b"MThd\0\0\0\x06\0\0\0\x01".to_vec();
+        bytes.extend(division);
+        bytes.extend(b"MTrk");
+        bytes.extend((data.len() as u32).to_be_bytes());
+        bytes.extend(data);
+        bytes
+    }
+
+    #[test]
+    fn running_status() {
+        let smf = Smf::parse(&file(
+            [0, 96],
+            &[
+                0, 0x90, 60, 100, 10, 64, 100, 0x81, 0, 60, 0, 0, 0xff, 0x2f, 0,
+            ],
+        ))
+        .unwrap();
+        assert_eq!(smf.division, Division::Ppq(96));
+        assert_eq!(
+            smf.tracks[0][..3],
+            [
+                TrackEvent::midi(0, &[0x90, 60, 100]),
+                TrackEvent::midi(10, &[0x90, 64, 100]),
+                TrackEvent::midi(138, &[0x90, 60, 0]),
+            ]
+        );
+    }
+
+    #[test]
+    fn data_byte_without_status() {
+        assert!(Smf::parse(&file([0, 96], &[0, 60, 100])).is_err());
+    }
+
+    #[test]
+    fn truncated_tracks() {
+        // after a delta time, inside a VLQ, inside a message and a meta event
+        for data in [&[0][..], &[0x81], &[0, 0x90, 60], &[0, 0xff, 0x51, 3, 7]] {
+            assert!(Smf::parse(&file([0, 96], data)).is_err(), "{data:?}");
+        }
+    }
+
+    #[test]
+    fn tick_overflow() {
+        // seventeen empty text events, each 2^28 - 1 ticks after the last
+        let data = [0xff, 0xff, 0xff, 0x7f, 0xff, 0x01, 0].repeat(17);
+        assert!(Smf::parse(&file([0, 96], &data)).is_err());
+    }
+
+    #[test]
+    fn smpte_division() {
+        // 25 fps, 40 ticks per frame: a millisecond per tick
+        let smf = Smf::parse(&file([-25_i8 as u8, 40], &[0, 0xff, 0x2f, 0])).unwrap();
+        assert_eq!(
+            smf.division,
+            Division::Smpte {
+                fps: 25,
+                ticks_per_frame: 40
+            }
+        );
+        assert!((smf.tempo_map(None).seconds(1000) - 1.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn round_trip() {
+        let smf = Smf {
+            format: 1,
+            division: Division::Ppq(480),
+            tracks: vec![
+                vec![
+                    TrackEvent::track_name(0, "tempo"),
+                    TrackEvent::tempo(0, 400_000),
+                    TrackEvent::time_signature(0, 3, 4),
+                    TrackEvent {
+                        tick: 1920,
+                        kind: EventKind::Meta(0x2f, Vec::new()),
+                    },
+                ],
+                vec![
+                    TrackEvent::midi(0, &[0xc1, 40]),
+                    TrackEvent::midi(0, &[0x91, 60, 90]),
+                    TrackEvent {
+                        tick: 100,
+                        kind: EventKind::SysEx(vec![0x7e, 0x7f, 0x09, 0x01, 0xf7]),
+                    },
+                    TrackEvent::midi(300_000, &[0x81, 60, 0]),
+                    TrackEvent {
+                        tick: 300_000,
+                        kind: EventKind::Meta(0x2f, Vec::new()),
+                    },
+                ],
+            ],
+        };
+        assert_eq!(Smf::parse(&smf.to_bytes()).unwrap(), smf);
+    }
+}
@@ -0,0 +1,472 @@
+use std::{f32::consts::PI, sync::Arc};
+
+use rustfft::{Fft, FftPlanner, num_complex::Complex};
+
+use crate::spectrum::{Spectrum, Window, parabolic_peak, peaks};
+
+const FFT_SIZE: usize = 4096;
+const HOP: usize = 256;
+/// Residual spectral envelope resolution
+const NOISE_BANDS: usize = 40;
+const NOISE_LO: f32 = 30.;
+
+#[derive(Debug, Clone)]
+pub struct SmsConfig {
+    /// Most peaks taken from each frame
+    pub max_peaks: usize,
+    /// Peaks quieter than this are ignored
+    pub min_db: f32,
+    /// Largest jump a track may make between frames, in cents
+    pub max_jump: f32,
+    /// Shorter tracks are dropped as noise, in frames
+    pub min_len: usize,
+}
+
+impl Default for SmsConfig {
+    fn default() -> Self {
+        Self {
+            max_peaks: 60,
+            min_db: -70.,
+            max_jump: 30.,
+            min_len: 20,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Peak {
+    pub freq: f32,
+    pub amp: f32,
+    pub phase: f32,
+}
+
+/// One partial, a peak per frame from `start`
+#[derive(Debug, Clone)]
+pub struct Track {
+    pub start: usize,
+    pub points: Vec<Peak>,
+}
+
+impl Track {
+    pub fn at(&self, frame: usize) -> Option<&Peak> {
+        frame
+            .checked_sub(self.start)
+            .and_then(|idx| self.points.get(idx))
+    }
+
+    /// Amplitude-weighted mean frequency
+    pub fn mean_freq(&self) -> f32 {
+        let (sum, weight) = self
+            .points
+            .iter()
+            .fold((0., 0.), |(s, w), p| (s + p.freq * p.amp, w + p.amp));
+        if weight > 0. { sum / weight } else { 0. }
+    }
+}
+
+/// Sinusoidal tracks plus a per-frame noise envelope of what they leave out
+/// (Serra & Smith, 1990)
+#[derive(Debug, Clone)]
+pub struct SmsModel {
+    pub sample_rate: u32,
+    pub frames: usize,
+    pub tracks: Vec<Track>,
+    /// Residual magnitude per noise band, per frame
+    pub noise: Vec<[f32; NOISE_BANDS]>,
+}
+
+/// Edges of the log-spaced noise bands, in bins
+fn band_edges(sample_rate: u32) -> [usize; NOISE_BANDS + 1] {
+    let nyquist = sample_rate as f32 / 2.;
+    std::array::from_fn(|band| {
+        let freq = NOISE_LO * (nyquist / NOISE_LO).powf(band as f32 / NOISE_BANDS as f32);
+        ((freq * FFT_SIZE as f32 / sample_rate as f32) as usize).min(FFT_SIZE / 2 + 1)
+    })
+}
+
+/// Analysis hop scaled by `stretch`, at least one sample
+pub fn stretched_hop(stretch: f32) -> usize {
+    ((HOP as f32 * stretch).round() as usize).max(1)
+}
+
+/// Peaks of one frame, loudest first. `gain` scales magnitudes to sine
+/// amplitudes.
+fn frame_peaks(
+    spectrum: &mut Spectrum,
+    frame: &[f32],
+    sr: u32,
+    gain: f32,
+    config: &SmsConfig,
+) -> Vec<Peak> {
+    let bins = spectrum.complex(frame).to_vec();
+    let mags: Vec<f32> = bins.iter().map(|c| c.norm() * gain).collect();
+    peaks(&mags, config.max_peaks, config.min_db)
+        .into_iter()
+        .map(|bin| {
+            let (fbin, amp) = parabolic_peak(&mags, bin);
+            let freq = fbin * sr as f32 / FFT_SIZE as f32;
+            // the window's linear phase puts the FFT phase at the frame
+            // start; correct for the bin offset, advance to the centre and
+            // turn the cosine phase into a sine phase for synthesis
+            let (w, wk) = (2. * PI * fbin, 2. * PI * bin as f32);
+            let n = FFT_SIZE as f32;
+            let phase = bins[bin].arg() + (wk - w) / n * (n - 1.) / 2. + w / 2. + PI / 2.;
+            Peak {
+                freq,
+                amp,
+                phase: phase.rem_euclid(2. * PI),
+            }
+        })
+        .collect()
+}
+
+impl SmsModel {
+    pub fn analyze(samples: &[f32], sample_rate: u32, config: &SmsConfig) -> Self {
+        let mut spectrum = Spectrum::new(FFT_SIZE, Window::Blackman);
+        let gain = 2. / Window::Blackman.coeffs(FFT_SIZE).iter().sum::<f32>();
+        let mut padded = vec![0.; FFT_SIZE / 2];
+        padded.extend_from_slice(samples);
+        padded.resize(padded.len() + FFT_SIZE / 2, 0.);
+        let frames = samples.len() / HOP + 1;
+
+        let mut done: Vec<Track> = Vec::new();
+        let mut active: Vec<Track> = Vec::new();
+        (0..frames).for_each(|frame| {
+            let start = frame * HOP;
+            let found = frame_peaks(
+                &mut spectrum,
+                &padded[start..(start + FFT_SIZE).min(padded.len())],
+                sample_rate,
+                gain,
+                config,
+            );
+            let mut claimed = vec![false; found.len()];
+
+            // loudest tracks pick first, each taking the nearest free peak
+            active.sort_by(|a, b| {
+                let (a, b) = (a.points.last().unwrap(), b.points.last().unwrap());
+                b.amp.total_cmp(&a.amp)
+            });
+            let mut continued = Vec::new();
+            active.drain(..).for_each(|mut track| {
+                let last = *track.points.last().unwrap();
+                let nearest = found
+                    .iter()
+                    .enumerate()
+                    .filter(|(idx, _)| !claimed[*idx])
+                    .map(|(idx, p)| (idx, (1200. * (p.freq / last.freq).log2()).abs()))
+                    .filter(|(_, cents)| *cents <= config.max_jump)
+                    .min_by(|a, b| a.1.total_cmp(&b.1));
+                match nearest {
+                    Some((idx, _)) => {
+                        claimed[idx] = true;
+                        track.points.push(found[idx]);
+                        continued.push(track);
+                    }
+                    None => done.push(track),
+                }
+            });
+            active = continued;
+            found
+                .iter()
+                .zip(claimed)
+                .filter(|(_, claimed)| !claimed)
+                .for_each(|(peak, _)| {
+                    active.push(Track {
+                        start: frame,
+                        points: vec![*peak],
+                    })
+                });
+        });
+        done.extend(active);
+        done.retain(|track| track.points.len() >= config.min_len);
+        done.sort_by_key(|track| track.start);
+
+        let mut model = Self {
+            sample_rate,
+            frames,
+            tracks: done,
+            noise: Vec::new(),
+        };
+        model.noise = model.residual_envelope(samples);
+        model
+    }
+
+    /// Band magnitudes of the input minus the resynthesised partials
+    fn residual_envelope(&self, samples: &[f32]) -> Vec<[f32; NOISE_BANDS]> {
+        let sines = self.synthesize_sines(HOP, 1.);
+        let residual: Vec<f32> = samples
+            .iter()
+            .zip(sines.iter().chain(std::iter::repeat(&0.)))
+            .map(|(x, s)| x - s)
+            .collect();
+
+        let edges = band_edges(self.sample_rate);
+        let mut spectrum = Spectrum::new(FFT_SIZE, Window::Hann);
+        let mut padded = vec![0.; FFT_SIZE / 2];
+        padded.extend_from_slice(&residual);
+        padded.resize(padded.len() + FFT_SIZE / 2, 0.);
+        (0..self.frames)
+            .map(|frame| {
+                let start = frame * HOP;
+                let bins = spectrum.complex(&padded[start..(start + FFT_SIZE).min(padded.len())]);
+                std::array::from_fn(|band| {
+                    let (lo, hi) = (edges[band], edges[band + 1].max(edges[band] + 1));
+                    let power: f32 = bins[lo..hi.min(bins.len())]
+                        .iter()
+                        .map(|c| c.norm_sqr())
+                        .sum();
+                    (power / (hi - lo) as f32).sqrt()
+                })
+            })
+            .collect()
+    }
+
+    /// Oscillator bank, one per track. At the analysis hop and pitch the
+    /// phase follows the measured phases with cubic interpolation (McAulay &
+    /// Quatieri, 1986) so the result can be subtracted from the input;
+    /// otherwise each oscillator is a free-running phase accumulator, as in
+    /// `SineGenerator`. Frequency and amplitude ramp across each hop.
+    pub fn synthesize_sines(&self, hop: usize, transpose: f32) -> Vec<f32> {
+        let exact = hop == HOP && transpose == 1.;
+        let len = self.frames * hop;
+        let mut out = vec![0.; len];
+        let sr = self.sample_rate as f32;
+        let omega = |freq: f32| 2. * PI * freq / sr;
+        let t = hop as f32;
+
+        self.tracks.iter().for_each(|track| {
+            // fade in over the hop before the first point and out after the last
+            let (head, tail) = (track.points[0], *track.points.last().unwrap());
+            let first = Peak {
+                amp: 0.,
+                phase: head.phase - omega(head.freq) * t,
+                ..head
+            };
+            let last = Peak {
+                amp: 0.,
+                phase: tail.phase + omega(tail.freq) * t,
+                ..tail
+            };
+            let points: Vec<Peak> = std::iter::once(first)
+                .chain(track.points.iter().copied())
+                .chain(std::iter::once(last))
+                .collect();
+
+            let mut phase = first.phase;
+            points.windows(2).enumerate().for_each(|(segment, pair)| {
+                let (a, b) = (pair[0], pair[1]);
+                let start = (track.start + segment) as isize * hop as isize - hop as isize;
+                let (w0, w1) = (omega(a.freq), omega(b.freq));
+                // cubic phase coefficients, unwrapping to the smoothest path
+                let m = ((a.phase + w0 * t - b.phase + (w1 - w0) * t / 2.) / (2. * PI)).round();
+                let err = b.phase - a.phase - w0 * t + 2. * PI * m;
+                let alpha = 3. / (t * t) * err - (w1 - w0) / t;
+                let beta = -2. / (t * t * t) * err + (w1 - w0) / (t * t);
+
+                (0..hop).for_each(|idx| {
+                    let pos = start + idx as isize;
+                    let n = idx as f32;
+                    let amp = a.amp + (b.amp - a.amp) * n / t;
+                    let freq = (a.freq + (b.freq - a.freq) * n / t) * transpose;
+                    let value = if exact {
+                        a.phase + w0 * n + alpha * n * n + beta * n * n * n
+                    } else {
+                        phase += omega(freq);
+                        if phase > 2. * PI {
+                            phase -= 2. * PI;
+                        }
+                        phase
+                    };
+                    if freq < sr / 2. && pos >= 0 && (pos as usize) < len {
+                        out[pos as usize] += amp * value.sin();
+                    }
+                });
+            });
+        });
+        out
+    }
+
+    /// Filtered noise with the residual envelope, overlap-added at `hop`
+    pub fn synthesize_noise(&self, hop: usize) -> Vec<f32> {
+        let fft: Arc<dyn Fft<f32>> = FftPlanner::new().plan_fft_inverse(FFT_SIZE);
+        let window = Window::Hann.coeffs(FFT_SIZE);
+        let window_energy: f32 = window.iter().map(|w| w * w).sum();
+        // restores the analysed noise power after windowing twice and
+        // overlap-adding at `hop`
+        let gain = (FFT_SIZE as f32 * hop as f32).sqrt() / window_energy / FFT_SIZE as f32;
+        let edges = band_edges(self.sample_rate);
+        let mut rng = XorShift(0x9e37_79b9_7f4a_7c15);
+
+        let mut out = vec![0.; self.frames * hop + FFT_SIZE];
+        let mut buf = vec![Complex::default(); FFT_SIZE];
+        self.noise.iter().enumerate().for_each(|(frame, bands)| {
+            buf.iter_mut().for_each(|c| *c = Complex::default());
+            (0..NOISE_BANDS).for_each(|band| {
+                (edges[band]..edges[band + 1].min(FFT_SIZE / 2)).for_each(|bin| {
+                    buf[bin] = Complex::from_polar(bands[band], rng.phase());
+                    buf[FFT_SIZE - bin] = buf[bin].conj();
+                });
+            });
+            fft.process(&mut buf);
+            let start = frame * hop;
+            out[start..start + FFT_SIZE]
+                .iter_mut()
+                .zip(buf.iter().zip(window.iter()))
+                .for_each(|(o, (c, w))| *o += c.re * w * gain);
+        });
+        // frames are centred on their hop
+        out.drain(..FFT_SIZE / 2);
+        out.truncate(self.frames * hop);
+        out
+    }
+
+    /// Sines plus noise; `stretch` lengthens the hop, `transpose` scales
+    /// every partial's frequency
+    pub fn synthesize(&self, stretch: f32, transpose: f32, noise: bool) -> Vec<f32> {
+        let hop = stretched_hop(stretch);
+        let mut out = self.synthesize_sines(hop, transpose);
+        if noise {
+            out.iter_mut()
+                .zip(self.synthesize_noise(hop))
+                .for_each(|(o, n)| *o += n);
+        }
+        out
+    }
+
+    /// Keeps the `count` loudest tracks
+    pub fn keep_loudest(&mut self, count: usize) {
+        self.tracks.sort_by(|a, b| {
+            let energy = |t: &Track| t.points.iter().map(|p| p.amp * p.amp).sum::<f32>();
+            energy(b).total_cmp(&energy(a))
+        });
+        self.tracks.truncate(count);
+        self.tracks.sort_by_key(|track| track.start);
+    }
+
+    pub fn scale_noise(&mut self, gain: f32) {
+        self.noise
+            .iter_mut()
+            .for_each(|bands| bands.iter_mut().for_each(|b| *b *= gain));
+    }
+
+    /// Interpolates towards `other` by `amount` (0 is self, 1 is other).
+    /// Tracks are paired by their rank in mean frequency, frequencies move
+    /// geometrically and amplitudes linearly; `other` is time-aligned to
+    /// this model's length. Both must share a sample rate.
+    pub fn morph(&self, other: &SmsModel, amount: f32) -> Result<SmsModel, String> {
+        if other.sample_rate != self.sample_rate {
+            return Err(format!(
+                "cannot morph {} Hz into {} Hz",
+                other.sample_rate, self.sample_rate
+            ));
+        }
+        let time_scale = other.frames as f32 / self.frames.max(1) as f32;
+        let by_freq = |model: &SmsModel| {
+            let mut idx: Vec<usize> = (0..model.tracks.len()).collect();
+            idx.sort_by(|a, b| {
+                model.tracks[*a]
+                    .mean_freq()
+                    .total_cmp(&model.tracks[*b].mean_freq())
+            });
+            idx
+        };
+        let (ours, theirs) = (by_freq(self), by_freq(other));
+
+        let mut tracks = self.tracks.clone();
+        ours.iter().enumerate().for_each(|(rank, idx)| {
+            let partner = theirs
+                .get(rank * theirs.len() / ours.len().max(1))
+                .map(|idx| &other.tracks[*idx]);
+            let track = &mut tracks[*idx];
+            let start = track.start;
+            track.points.iter_mut().enumerate().for_each(|(offset, p)| {
+                let frame = ((start + offset) as f32 * time_scale) as usize;
+                let target = partner
+                    .and_then(|t| t.at(frame))
+                    .copied()
+                    .unwrap_or(Peak { amp: 0., ..*p });
+                p.freq *= (target.freq / p.freq).powf(amount);
+                p.amp += (target.amp - p.amp) * amount;
+            });
+        });
+
+        let noise = (0..self.frames)
+            .map(|frame| {
+                let ours = self.noise[frame];
+                let other_frame = ((frame as f32 * time_scale) as usize).min(other.frames - 1);
+                let theirs = other.noise[other_frame];
+                std::array::from_fn(|band| ours[band] + (theirs[band] - ours[band]) * amount)
+            })
+            .collect();
+
+        Ok(SmsModel {
+            sample_rate: self.sample_rate,
+            frames: self.frames,
+            tracks,
+            noise,
+        })
+    }
+}
+
+/// Noise phases; reproducible and good enough for audio
+struct XorShift(u64);
+
+impl XorShift {
+    fn phase(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 40) as f32 / (1_u64 << 24) as f32 * 2. * PI
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SR: u32 = 44100;
+
+    fn rms(x: &[f32]) -> f32 {
+        (x.iter().map(|s| s * s).sum::<f32>() / x.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn two_partials_resynthesise() {
+        let input: Vec<f32> = (0..SR as usize)
+            .map(|i| {
+                let t = i as f32 / SR as f32;
+                0.4 * (2. * PI * 440. * t).sin() + 0.2 * (2. * PI * 1320. * t).sin()
+            })
+            .collect();
+        let model = SmsModel::analyze(&input, SR, &SmsConfig::default());
+
+        let mut freqs: Vec<f32> = model.tracks.iter().map(Track::mean_freq).collect();
+        freqs.sort_by(f32::total_cmp);
+        assert_eq!(freqs.len(), 2, "{freqs:?}");
+        assert!((freqs[0] - 440.).abs() < 1., "{freqs:?}");
+        assert!((freqs[1] - 1320.).abs() < 1., "{freqs:?}");
+
+        // away from the fades at either end
+        let sines = model.synthesize_sines(HOP, 1.);
+        let middle = FFT_SIZE..input.len() - FFT_SIZE;
+        let residual: Vec<f32> = input[middle.clone()]
+            .iter()
+            .zip(&sines[middle.clone()])
+            .map(|(x, s)| x - s)
+            .collect();
+        assert!(
+            rms(&residual) < 0.01 * rms(&input[middle]),
+            "{}",
+            rms(&residual)
+        );
+    }
+
+    #[test]
+    fn morph_needs_equal_rates() {
+        let model = SmsModel::analyze(&[0.; 4096], SR, &SmsConfig::default());
+        let other = SmsModel::analyze(&[0.; 4096], 48000, &SmsConfig::default());
+        assert!(model.morph(&other, 0.5).is_err());
+        assert!(model.morph(&model, 0.5).is_ok());
+    }
+}
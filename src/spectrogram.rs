@@ -0,0 +1,144 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use crate::spectrum::{Spectrum, Window, to_db};
+
+/// Lowest frequency shown on a log axis
+const LOG_LO: f32 = 20.;
+
+#[derive(Debug, Clone)]
+pub struct SpectrogramConfig {
+    pub fft_size: usize,
+    pub hop: usize,
+    pub window: Window,
+    pub log_freq: bool,
+    /// dB below the loudest cell that maps to black
+    pub range_db: f32,
+    /// Image rows for a log frequency axis; a linear axis has one per bin
+    pub height: usize,
+}
+
+impl Default for SpectrogramConfig {
+    fn default() -> Self {
+        Self {
+            fft_size: 2048,
+            hop: 512,
+            window: Window::Hann,
+            log_freq: false,
+            range_db: 90.,
+            height: 512,
+        }
+    }
+}
+
+/// Magnitudes in dB, one `Vec` of bins per frame
+pub struct Spectrogram {
+    frames: Vec<Vec<f32>>,
+    sample_rate: u32,
+    config: SpectrogramConfig,
+}
+
+impl Spectrogram {
+    pub fn new(samples: &[f32], sample_rate: u32, config: SpectrogramConfig) -> Self {
+        let mut spectrum = Spectrum::new(config.fft_size, config.window);
+        let frames = (0..=samples.len().saturating_sub(config.fft_size))
+            .step_by(config.hop.max(1))
+            .map(|start| {
+                let end = (start + config.fft_size).min(samples.len());
+                spectrum
+                    .magnitudes(&samples[start..end])
+                    .into_iter()
+                    .map(to_db)
+                    .collect()
+            })
+            .collect();
+        Self {
+            frames,
+            sample_rate,
+            config,
+        }
+    }
+
+    /// Fractional bin shown on image row `row`, counted from the bottom
+    fn row_bin(&self, row: usize, rows: usize) -> f32 {
+        let bins = self.config.fft_size / 2 + 1;
+        if self.config.log_freq {
+            let nyquist = self.sample_rate as f32 / 2.;
+            let freq = LOG_LO * (nyquist / LOG_LO).powf(row as f32 / (rows - 1) as f32);
+            freq * self.config.fft_size as f32 / self.sample_rate as f32
+        } else {
+            row as f32 * (bins - 1) as f32 / (rows - 1) as f32
+        }
+    }
+
+    /// Renders to 8-bit RGB, time left to right and frequency bottom to top
+    pub fn to_rgb(&self) -> (usize, usize, Vec<u8>) {
+        let width = self.frames.len();
+        let height = if self.config.log_freq {
+            self.config.height.max(2)
+        } else {
+            self.config.fft_size / 2 + 1
+        };
+        let max_db = self
+            .frames
+            .iter()
+            .flatten()
+            .fold(f32::MIN, |acc, db| acc.max(*db));
+        let min_db = max_db - self.config.range_db;
+
+        let mut rgb = vec![0_u8; width * height * 3];
+        (0..height).for_each(|row| {
+            let bin = self.row_bin(row, height);
+            let lo = bin.floor() as usize;
+            let frac = bin - lo as f32;
+            let y = height - 1 - row;
+            self.frames.iter().enumerate().for_each(|(x, frame)| {
+                let a = frame[lo.min(frame.len() - 1)];
+                let b = frame[(lo + 1).min(frame.len() - 1)];
+                let db = a + (b - a) * frac;
+                let level = ((db - min_db) / (max_db - min_db)).clamp(0., 1.);
+                let idx = (y * width + x) * 3;
+                rgb[idx..idx + 3].copy_from_slice(&colour(level));
+            });
+        });
+        (width, height, rgb)
+    }
+
+    /// Writes a PNG, or a binary PPM if the extension is `.ppm`
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn std::error::Error>> {
+        let (width, height, rgb) = self.to_rgb();
+        let mut out = BufWriter::new(File::create(&path)?);
+        match path.as_ref().extension().and_then(|e| e.to_str()) {
+            Some("ppm") => {
+                write!(out, "P6\n{width} {height}\n255\n")?;
+                out.write_all(&rgb)?;
+            }
+            _ => {
+                let mut encoder = png::Encoder::new(out, width as u32, height as u32);
+                encoder.set_color(png::ColorType::Rgb);
+                encoder.set_depth(png::BitDepth::Eight);
+                encoder.write_header()?.write_image_data(&rgb)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Black through purple, red and yellow to white
+fn colour(level: f32) -> [u8; 3] {
+    const STOPS: [[f32; 3]; 5] = [
+        [0., 0., 0.],
+        [0.35, 0.05, 0.5],
+        [0.85, 0.2, 0.2],
+        [1., 0.8, 0.1],
+        [1., 1., 1.],
+    ];
+    let pos = level * (STOPS.len() - 1) as f32;
+    let idx = (pos as usize).min(STOPS.len() - 2);
+    let frac = pos - idx as f32;
+    let (a, b) = (STOPS[idx], STOPS[idx + 1]);
+    [0, 1, 2].map(|c| ((a[c] + (b[c] - a[c]) * frac) * 255.) as u8)
+}
@@ -0,0 +1,136 @@
+use std::{f32::consts::PI, str::FromStr, sync::Arc};
+
+use rustfft::{Fft, FftPlanner, num_complex::Complex};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Window {
+    Rect,
+    Hann,
+    Hamming,
+    Blackman,
+}
+
+impl Window {
+    pub fn coeffs(&self, len: usize) -> Vec<f32> {
+        let n = len.max(2) as f32 - 1.;
+        (0..len)
+            .map(|idx| {
+                let w = 2. * PI * idx as f32 / n;
+                match self {
+                    Window::Rect => 1.,
+                    Window::Hann => 0.5 - 0.5 * w.cos(),
+                    Window::Hamming => 0.54 - 0.46 * w.cos(),
+                    Window::Blackman => 0.42 - 0.5 * w.cos() + 0.08 * (2. * w).cos(),
+                }
+            })
+            .collect()
+    }
+}
+
+impl FromStr for Window {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rect" => Ok(Window::Rect),
+            "hann" => Ok(Window::Hann),
+            "hamming" => Ok(Window::Hamming),
+            "blackman" => Ok(Window::Blackman),
+            _ => Err(format!("unknown window: {s}")),
+        }
+    }
+}
+
+/// Windowed FFT of fixed-size frames
+pub struct Spectrum {
+    size: usize,
+    fft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    gain: f32,
+    buf: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+}
+
+impl Spectrum {
+    pub fn new(size: usize, window: Window) -> Self {
+        let fft = FftPlanner::<f32>::new().plan_fft_forward(size);
+        let window = window.coeffs(size);
+        // scales a full-scale sine to a magnitude of 1
+        let gain = 2. / window.iter().sum::<f32>();
+        Self {
+            size,
+            scratch: vec![Complex::default(); fft.get_inplace_scratch_len()],
+            fft,
+            window,
+            gain,
+            buf: vec![Complex::default(); size],
+        }
+    }
+
+    pub fn bins(&self) -> usize {
+        self.size / 2 + 1
+    }
+
+    /// Windowed complex spectrum of `frame`, zero-padded or truncated to
+    /// the FFT size. Only the first `bins()` values are meaningful.
+    pub fn complex(&mut self, frame: &[f32]) -> &[Complex<f32>] {
+        self.buf
+            .iter_mut()
+            .enumerate()
+            .for_each(|(idx, c)| *c = Complex::new(frame.get(idx).copied().unwrap_or(0.), 0.));
+        self.buf
+            .iter_mut()
+            .zip(self.window.iter())
+            .for_each(|(c, w)| c.re *= w);
+        self.fft
+            .process_with_scratch(&mut self.buf, &mut self.scratch);
+        &self.buf[..self.size / 2 + 1]
+    }
+
+    /// Linear magnitudes, scaled so a full-scale sine reads 1.0
+    pub fn magnitudes(&mut self, frame: &[f32]) -> Vec<f32> {
+        let gain = self.gain;
+        self.complex(frame)
+            .iter()
+            .map(|c| c.norm() * gain)
+            .collect()
+    }
+}
+
+pub fn bin_freq(bin: f32, size: usize, sample_rate: u32) -> f32 {
+    bin * sample_rate as f32 / size as f32
+}
+
+pub fn to_db(mag: f32) -> f32 {
+    20. * mag.max(1e-9).log10()
+}
+
+/// Refines the peak at `bin` by fitting a parabola through the log
+/// magnitudes of its neighbours. Returns the fractional bin and magnitude.
+pub fn parabolic_peak(mags: &[f32], bin: usize) -> (f32, f32) {
+    if bin == 0 || bin + 1 >= mags.len() {
+        return (bin as f32, mags[bin]);
+    }
+    let (a, b, c) = (to_db(mags[bin - 1]), to_db(mags[bin]), to_db(mags[bin + 1]));
+    let denom = a - 2. * b + c;
+    if denom.abs() < 1e-9 {
+        return (bin as f32, mags[bin]);
+    }
+    let offset = (0.5 * (a - c) / denom).clamp(-0.5, 0.5);
+    let db = b - 0.25 * (a - c) * offset;
+    (bin as f32 + offset, 10.0_f32.powf(db / 20.))
+}
+
+/// Local maxima above `min_db`, loudest first
+pub fn peaks(mags: &[f32], count: usize, min_db: f32) -> Vec<usize> {
+    let threshold = 10.0_f32.powf(min_db / 20.);
+    let mut peaks: Vec<usize> = (1..mags.len().saturating_sub(1))
+        .filter(|idx| {
+            let m = mags[*idx];
+            m > threshold && m > mags[idx - 1] && m >= mags[idx + 1]
+        })
+        .collect();
+    peaks.sort_by(|a, b| mags[*b].total_cmp(&mags[*a]));
+    peaks.truncate(count);
+    peaks
+}
@@ -0,0 +1,323 @@
+use crate::{
+    onset::{self, PeakPicker},
+    sine_generator::{SineGenerator, note, note_name},
+    smf::Note,
+    spectrum::{Spectrum, Window, bin_freq, parabolic_peak, peaks, to_db},
+};
+
+const FFT_SIZE: usize = 4096;
+/// Matches the onset detector's hop so their frames line up
+const HOP: usize = 512;
+const LOWEST: u8 = 28;
+const HIGHEST: u8 = 108;
+const HARMONICS: usize = 8;
+/// Weight of each harmonic relative to the one below it
+const HARMONIC_DECAY: f32 = 0.8;
+
+#[derive(Debug, Clone)]
+pub struct TranscribeConfig {
+    pub max_polyphony: usize,
+    /// A pitch must reach this fraction of the frame's strongest salience
+    pub relative_threshold: f32,
+    /// Spectral peaks below this are ignored, in dB
+    pub min_db: f32,
+    /// Shortest note kept, in seconds
+    pub min_duration: f32,
+    /// Gap in a pitch that still counts as one note, in seconds
+    pub max_gap: f32,
+}
+
+impl Default for TranscribeConfig {
+    fn default() -> Self {
+        Self {
+            max_polyphony: 6,
+            relative_threshold: 0.15,
+            min_db: -60.,
+            min_duration: 0.05,
+            max_gap: 0.03,
+        }
+    }
+}
+
+/// Spectral peak: frequency and linear amplitude, a full-scale sine is 1
+#[derive(Debug, Clone, Copy)]
+struct Peak {
+    freq: f32,
+    amp: f32,
+}
+
+/// Harmonic-sum salience of `pitch`: the best peak near each harmonic,
+/// weighted down the series. A harmonic counts for no more than the
+/// fundamental, so a quiet note can't claim a loud one's peaks. Returns the
+/// salience and the peak used for each harmonic.
+fn salience(pitch: u8, peaks: &[Peak]) -> (f32, Vec<(usize, usize)>) {
+    let f0 = note(pitch as f32);
+    let mut used = Vec::new();
+    let mut fundamental = 0.;
+    let sum = (1..=HARMONICS)
+        .map(|h| {
+            let target = f0 * h as f32;
+            // within half a semitone of the harmonic
+            let best = peaks
+                .iter()
+                .enumerate()
+                .filter(|(_, p)| p.amp > 0. && (1200. * (p.freq / target).log2()).abs() < 50.)
+                .max_by(|a, b| a.1.amp.total_cmp(&b.1.amp));
+            match best {
+                Some((idx, p)) => {
+                    if h == 1 {
+                        fundamental = p.amp;
+                    }
+                    used.push((h, idx));
+                    p.amp.min(fundamental) * HARMONIC_DECAY.powi(h as i32 - 1)
+                }
+                None => 0.,
+            }
+        })
+        .sum();
+    (sum, used)
+}
+
+/// Pitches sounding in one frame by iterative estimation and cancellation
+/// (after Klapuri, 2006): take the most salient pitch, subtract its expected
+/// harmonics from the peaks, repeat. Only the expected share is removed so
+/// notes an octave or twelfth above survive.
+fn frame_pitches(mut peaks: Vec<Peak>, config: &TranscribeConfig) -> Vec<(u8, f32)> {
+    let mut found: Vec<(u8, f32)> = Vec::new();
+    let mut strongest = 0.0_f32;
+    while found.len() < config.max_polyphony {
+        let best = (LOWEST..=HIGHEST)
+            .map(|pitch| (pitch, salience(pitch, &peaks)))
+            // the fundamental itself must be present, or every note would
+            // also light up its sub-octaves
+            .filter(|(_, (_, used))| used.first().is_some_and(|(h, _)| *h == 1))
+            .max_by(|a, b| a.1.0.total_cmp(&b.1.0));
+        let Some((pitch, (value, used))) = best else {
+            break;
+        };
+        strongest = strongest.max(value);
+        if value <= 0. || value < strongest * config.relative_threshold {
+            break;
+        }
+        let fundamental = peaks[used[0].1].amp;
+        found.push((pitch, fundamental));
+        used.into_iter().for_each(|(h, idx)| {
+            let expected = fundamental * HARMONIC_DECAY.powi(h as i32 - 1);
+            peaks[idx].amp = if h == 1 || peaks[idx].amp < 2. * expected {
+                0.
+            } else {
+                peaks[idx].amp - expected
+            };
+        });
+    }
+    found
+}
+
+/// Velocity from the fundamental's level, 0 dBFS is 127 and -60 dBFS is 1
+fn velocity(amp: f32) -> u8 {
+    ((to_db(amp) + 60.) / 60. * 126. + 1.).clamp(1., 127.) as u8
+}
+
+pub fn transcribe(samples: &[f32], sample_rate: u32, config: &TranscribeConfig) -> Vec<Note> {
+    let mut spectrum = Spectrum::new(FFT_SIZE, Window::Hann);
+    let mut padded = vec![0.; FFT_SIZE / 2];
+    padded.extend_from_slice(samples);
+    padded.resize(padded.len() + FFT_SIZE / 2, 0.);
+    let frame_secs = HOP as f32 / sample_rate as f32;
+    let frames = samples.len() / HOP + 1;
+
+    // (pitch, fundamental amplitude) of each frame
+    let active: Vec<Vec<(u8, f32)>> = (0..frames)
+        .map(|frame| {
+            let start = frame * HOP;
+            let mags = spectrum.magnitudes(&padded[start..start + FFT_SIZE]);
+            let found = peaks(&mags, 60, config.min_db)
+                .into_iter()
+                .map(|bin| {
+                    let (bin, amp) = parabolic_peak(&mags, bin);
+                    Peak {
+                        freq: bin_freq(bin, FFT_SIZE, sample_rate),
+                        amp,
+                    }
+                })
+                .collect();
+            frame_pitches(found, config)
+        })
+        .collect();
+
+    let odf = onset::detection_function(samples, onset::Method::SpectralFlux);
+    let onsets = PeakPicker::default().pick(&odf);
+    let max_gap = (config.max_gap / frame_secs).round() as usize;
+    let min_len = (config.min_duration / frame_secs).round() as usize;
+
+    let mut notes = Vec::new();
+    (LOWEST..=HIGHEST).for_each(|pitch| {
+        let amps: Vec<f32> = active
+            .iter()
+            .map(|frame| {
+                frame
+                    .iter()
+                    .find(|(p, _)| *p == pitch)
+                    .map_or(0., |(_, amp)| *amp)
+            })
+            .collect();
+
+        let mut start: Option<usize> = None;
+        let mut last_on = 0;
+        let close = |from: usize, to: usize, notes: &mut Vec<Note>| {
+            if to - from >= min_len {
+                let peak = amps[from..to].iter().fold(0.0_f32, |acc, a| acc.max(*a));
+                notes.push(Note {
+                    pitch,
+                    velocity: velocity(peak),
+                    start: from as f32 * frame_secs,
+                    end: to as f32 * frame_secs,
+                });
+            }
+        };
+        (0..=frames).for_each(|frame| {
+            let on = amps.get(frame).is_some_and(|a| *a > 0.);
+            match start {
+                Some(from) if on => {
+                    // a repeated note: an onset where this pitch grows again
+                    let rises = onsets.contains(&frame)
+                        && frame >= 2
+                        && amps[frame..(frame + 3).min(frames)]
+                            .iter()
+                            .fold(0.0_f32, |acc, a| acc.max(*a))
+                            > 1.5 * amps[frame - 2];
+                    if rises && frame - from >= min_len {
+                        close(from, frame, &mut notes);
+                        start = Some(frame);
+                    }
+                    last_on = frame;
+                }
+                Some(from) if frame - last_on > max_gap || frame == frames => {
+                    close(from, last_on + 1, &mut notes);
+                    start = None;
+                }
+                Some(_) => (),
+                None if on => {
+                    start = Some(frame);
+                    last_on = frame;
+                }
+                None => (),
+            }
+        });
+    });
+    notes.sort_by(|a, b| a.start.total_cmp(&b.start).then(a.pitch.cmp(&b.pitch)));
+    notes
+}
+
+/// Renders `notes` with an offline `SineGenerator`
+pub fn render(notes: &[Note], sample_rate: u32, generator: &mut SineGenerator) -> Vec<f32> {
+    // (time, pitch, velocity), note-offs first at equal times
+    let mut events: Vec<(f32, u8, u8)> = notes
+        .iter()
+        .flat_map(|n| [(n.end, n.pitch, 0), (n.start, n.pitch, n.velocity)])
+        .collect();
+    events.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.2.cmp(&b.2)));
+
+    let mut out = Vec::new();
+    events.into_iter().for_each(|(time, pitch, velocity)| {
+        let target = (time * sample_rate as f32) as usize;
+        let seconds = target.saturating_sub(out.len()) as f32 / sample_rate as f32;
+        out.extend(generator.render(seconds));
+        generator.note(pitch, velocity);
+    });
+    out.extend(generator.render(0.5));
+    out
+}
+
+/// Note-level precision, recall and F-measure: a transcribed note is correct
+/// when it has the reference pitch and its onset is within `tolerance`
+/// seconds, each reference note matching at most once
+#[derive(Debug, Clone, Copy)]
+pub struct Score {
+    pub precision: f32,
+    pub recall: f32,
+    pub f_measure: f32,
+    /// Mean absolute onset error of the matches, in seconds
+    pub onset_error: f32,
+}
+
+pub fn evaluate(reference: &[Note], transcribed: &[Note], tolerance: f32) -> Score {
+    let mut matched = vec![false; reference.len()];
+    let mut errors = Vec::new();
+    transcribed.iter().for_each(|note| {
+        let best = reference
+            .iter()
+            .enumerate()
+            .filter(|(idx, r)| !matched[*idx] && r.pitch == note.pitch)
+            .map(|(idx, r)| (idx, (r.start - note.start).abs()))
+            .filter(|(_, err)| *err <= tolerance)
+            .min_by(|a, b| a.1.total_cmp(&b.1));
+        if let Some((idx, err)) = best {
+            matched[idx] = true;
+            errors.push(err);
+        }
+    });
+    let hits = errors.len() as f32;
+    let precision = if transcribed.is_empty() {
+        0.
+    } else {
+        hits / transcribed.len() as f32
+    };
+    let recall = if reference.is_empty() {
+        0.
+    } else {
+        hits / reference.len() as f32
+    };
+    let f_measure = if precision + recall > 0. {
+        2. * precision * recall / (precision + recall)
+    } else {
+        0.
+    };
+    Score {
+        precision,
+        recall,
+        f_measure,
+        onset_error: errors.iter().sum::<f32>() / hits.max(1.),
+    }
+}
+
+/// A reproducible test piece: a melody over two-note chords, with repeated
+/// notes and overlaps
+pub fn test_piece() -> Vec<Note> {
+    const CHORDS: [[u8; 2]; 4] = [[48, 55], [45, 52], [41, 48], [43, 50]];
+    const MELODY: [u8; 8] = [72, 74, 76, 76, 79, 77, 76, 74];
+    let mut notes = Vec::new();
+    (0..4).for_each(|bar| {
+        let bar_start = bar as f32 * 2.;
+        CHORDS[bar].iter().for_each(|pitch| {
+            notes.push(Note {
+                pitch: *pitch,
+                velocity: 80,
+                start: bar_start,
+                end: bar_start + 1.9,
+            })
+        });
+        MELODY.iter().enumerate().for_each(|(idx, pitch)| {
+            let start = bar_start + idx as f32 * 0.25;
+            notes.push(Note {
+                pitch: *pitch + (bar % 2) as u8 * 2,
+                velocity: 100,
+                start,
+                end: start + 0.2,
+            })
+        });
+    });
+    notes
+}
+
+/// One line of the printed note list
+pub fn describe(note: &Note) -> String {
+    format!(
+        "{:7.3}s {:7.3}s  {:<4} ({:3}) vel {:3}",
+        note.start,
+        note.end,
+        note_name(note.pitch),
+        note.pitch,
+        note.velocity,
+    )
+}
@@ -0,0 +1,370 @@
+use crate::midi::{MidiMessage, MidiParser};
+
+/// Virtual cables per USB-MIDI endpoint
+pub const CABLES: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsbMidiEvent {
+    pub cable: u8,
+    pub message: MidiMessage,
+}
+
+/// Number of MIDI bytes carried by a packet with Code Index Number `cin`
+fn packet_len(cin: u8) -> usize {
+    match cin {
+        0x5 | 0xf => 1,
+        0x2 | 0x6 | 0xc | 0xd => 2,
+        0x3 | 0x4 | 0x7 | 0x8..=0xb | 0xe => 3,
+        // 0x0 and 0x1 are reserved; 0x0 also pads short transfers
+        _ => 0,
+    }
+}
+
+/// Decoder for USB-MIDI 1.0 event packets (USB Device Class Definition for
+/// MIDI Devices, section 4). The bytes of each cable go through their own
+/// `MidiParser`, since a long SysEx message spans packets and transfers.
+#[derive(Debug, Clone)]
+pub struct UsbMidiDecoder {
+    parsers: [MidiParser; CABLES],
+}
+
+impl UsbMidiDecoder {
+    pub fn new() -> Self {
+        Self {
+            parsers: std::array::from_fn(|_| MidiParser::new()),
+        }
+    }
+
+    /// Decodes every 4-byte packet of a bulk or interrupt transfer
+    pub fn decode(&mut self, transfer: &[u8]) -> Vec<UsbMidiEvent> {
+        transfer
+            .chunks_exact(4)
+            .filter_map(|packet| self.packet(packet.try_into().unwrap()))
+            .collect()
+    }
+
+    pub fn packet(&mut self, packet: [u8; 4]) -> Option<UsbMidiEvent> {
+        let cable = packet[0] >> 4;
+        let cin = packet[0] & 0x0f;
+        let parser = &mut self.parsers[cable as usize];
+        // a packet holds at most one message; single-byte packets may also
+        // carry any byte of a stream sent without parsing
+        let message = packet[1..1 + packet_len(cin)]
+            .iter()
+            .filter_map(|b| parser.push(*b))
+            .last()?;
+        Some(UsbMidiEvent { cable, message })
+    }
+}
+
+/// Packs a message into event packets for `cable`: one packet, or a SysEx
+/// split three bytes at a time
+pub fn encode(cable: u8, message: &MidiMessage) -> Vec<[u8; 4]> {
+    let bytes = message.to_bytes();
+    let cin = |chunk: &[u8]| match (chunk[0], chunk.len()) {
+        (0x80..=0xef, _) => chunk[0] >> 4,
+        (0xf1 | 0xf3, _) => 0x2,
+        (0xf2, _) => 0x3,
+        (0xf6, _) => 0x5,
+        (0xf8..=0xff, 1) => 0xf,
+        // SysEx: ends with its last one, two or three bytes
+        (_, len) if chunk[len - 1] == 0xf7 => 0x4 + len as u8,
+        _ => 0x4,
+    };
+    let chunks: Vec<&[u8]> = match bytes[0] {
+        0xf0 => bytes.chunks(3).collect(),
+        _ => vec![&bytes],
+    };
+    chunks
+        .into_iter()
+        .map(|chunk| {
+            let mut packet = [cable << 4 | cin(chunk), 0, 0, 0];
+            packet[1..1 + chunk.len()].copy_from_slice(chunk);
+            packet
+        })
+        .collect()
+}
+
+impl Default for UsbMidiDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(transfer: &[u8]) -> Vec<MidiMessage> {
+        UsbMidiDecoder::new()
+            .decode(transfer)
+            .into_iter()
+            .map(|e| e.message)
+            .collect()
+    }
+
+    #[test]
+    fn keystation_note_and_volume() {
+        // middle C pressed and released, then the volume slider, padded to
+        // the 64-byte interrupt transfer
+        let mut transfer = vec![
+            0x09, 0x90, 0x3c, 0x64, 0x09, 0x90, 0x3c, 0x00, 0x0b, 0xb0, 0x07, 0x51,
+        ];
+        transfer.resize(64, 0);
+        assert_eq!(
+            decode(&transfer),
+            [
+                MidiMessage::NoteOn {
+                    channel: 0,
+                    note: 60,
+                    velocity: 100
+                },
+                MidiMessage::NoteOn {
+                    channel: 0,
+                    note: 60,
+                    velocity: 0
+                },
+                MidiMessage::ControlChange {
+                    channel: 0,
+                    controller: 7,
+                    value: 81
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn channel_voice() {
+        let transfer = [
+            0x08, 0x83, 0x40, 0x20, // note off, channel 4
+            0x0a, 0xa0, 0x40, 0x11, // poly pressure
+            0x0c, 0xc9, 0x05, 0x00, // program change, channel 10
+            0x0d, 0xd1, 0x33, 0x00, // channel pressure
+            0x0e, 0xe0, 0x00, 0x40, // pitch bend centre
+            0x0e, 0xe0, 0x00, 0x00, // pitch bend bottom
+            0x0e, 0xe0, 0x7f, 0x7f, // pitch bend top
+        ];
+        assert_eq!(
+            decode(&transfer),
+            [
+                MidiMessage::NoteOff {
+                    channel: 3,
+                    note: 64,
+                    velocity: 32
+                },
+                MidiMessage::PolyPressure {
+                    channel: 0,
+                    note: 64,
+                    pressure: 17
+                },
+                MidiMessage::ProgramChange {
+                    channel: 9,
+                    program: 5
+                },
+                MidiMessage::ChannelPressure {
+                    channel: 1,
+                    pressure: 51
+                },
+                MidiMessage::PitchBend {
+                    channel: 0,
+                    value: 0
+                },
+                MidiMessage::PitchBend {
+                    channel: 0,
+                    value: -8192
+                },
+                MidiMessage::PitchBend {
+                    channel: 0,
+                    value: 8191
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn system_common_and_realtime() {
+        let transfer = [
+            0x02, 0xf1, 0x23, 0x00, // MTC quarter frame
+            0x03, 0xf2, 0x10, 0x01, // song position 144
+            0x02, 0xf3, 0x02, 0x00, // song select
+            0x05, 0xf6, 0x00, 0x00, // tune request
+            0x0f, 0xfa, 0x00, 0x00, 0x0f, 0xf8, 0x00, 0x00, 0x0f, 0xfc, 0x00, 0x00, 0x0f, 0xfe,
+            0x00, 0x00,
+        ];
+        assert_eq!(
+            decode(&transfer),
+            [
+                MidiMessage::TimeCode(0x23),
+                MidiMessage::SongPosition(144),
+                MidiMessage::SongSelect(2),
+                MidiMessage::TuneRequest,
+                MidiMessage::Start,
+                MidiMessage::Clock,
+                MidiMessage::Stop,
+                MidiMessage::ActiveSensing,
+            ]
+        );
+    }
+
+    #[test]
+    fn sysex_across_transfers_with_realtime() {
+        // identity reply split over two transfers, a clock in the middle
+        let mut decoder = UsbMidiDecoder::new();
+        let first = decoder.decode(&[
+            0x04, 0xf0, 0x7e, 0x7f, 0x04, 0x06, 0x02, 0x00, 0x0f, 0xf8, 0x00, 0x00,
+        ]);
+        assert_eq!(
+            first,
+            [UsbMidiEvent {
+                cable: 0,
+                message: MidiMessage::Clock
+            }]
+        );
+        let second = decoder.decode(&[0x04, 0x20, 0x2b, 0x69, 0x06, 0x00, 0xf7, 0x00]);
+        assert_eq!(
+            second,
+            [UsbMidiEvent {
+                cable: 0,
+                message: MidiMessage::SysEx(vec![
+                    0x7e, 0x7f, 0x06, 0x02, 0x00, 0x20, 0x2b, 0x69, 0x00
+                ])
+            }]
+        );
+    }
+
+    #[test]
+    fn sysex_endings() {
+        // end packets carrying one, two and three bytes
+        let transfer = [
+            0x04, 0xf0, 0x01, 0x02, 0x05, 0xf7, 0x00, 0x00, // 0x5
+            0x06, 0xf0, 0xf7, 0x00, // 0x6, empty message
+            0x07, 0xf0, 0x03, 0xf7, // 0x7, short message
+        ];
+        assert_eq!(
+            decode(&transfer),
+            [
+                MidiMessage::SysEx(vec![0x01, 0x02]),
+                MidiMessage::SysEx(vec![]),
+                MidiMessage::SysEx(vec![0x03]),
+            ]
+        );
+    }
+
+    #[test]
+    fn cables_are_independent() {
+        let mut decoder = UsbMidiDecoder::new();
+        let events = decoder.decode(&[
+            0x04, 0xf0, 0x01, 0x02, // cable 0 SysEx starts
+            0x19, 0x91, 0x40, 0x7f, // cable 1 note on, channel 2
+            0x14, 0xf0, 0x0a, 0x0b, // cable 1 SysEx starts
+            0x05, 0xf7, 0x00, 0x00, // cable 0 ends
+            0x15, 0xf7, 0x00, 0x00, // cable 1 ends
+        ]);
+        assert_eq!(
+            events,
+            [
+                UsbMidiEvent {
+                    cable: 1,
+                    message: MidiMessage::NoteOn {
+                        channel: 1,
+                        note: 64,
+                        velocity: 127
+                    }
+                },
+                UsbMidiEvent {
+                    cable: 0,
+                    message: MidiMessage::SysEx(vec![0x01, 0x02])
+                },
+                UsbMidiEvent {
+                    cable: 1,
+                    message: MidiMessage::SysEx(vec![0x0a, 0x0b])
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_reserved_and_partial_packets() {
+        // CIN 0x0/0x1, a stray SysEx end and a truncated trailing packet
+        let transfer = [
+            0x00, 0x00, 0x00, 0x00, 0x01, 0x12, 0x34, 0x56, 0x05, 0xf7, 0x00, 0x00, 0x09, 0x90,
+        ];
+        assert!(decode(&transfer).is_empty());
+    }
+
+    #[test]
+    fn encodes_packets() {
+        assert_eq!(
+            encode(
+                1,
+                &MidiMessage::NoteOn {
+                    channel: 0,
+                    note: 60,
+                    velocity: 100
+                }
+            ),
+            [[0x19, 0x90, 0x3c, 0x64]]
+        );
+        assert_eq!(encode(0, &MidiMessage::Clock), [[0x0f, 0xf8, 0x00, 0x00]]);
+        assert_eq!(
+            encode(0, &MidiMessage::SongPosition(144)),
+            [[0x03, 0xf2, 0x10, 0x01]]
+        );
+        assert_eq!(
+            encode(0, &MidiMessage::SysEx(vec![0x7e, 0x7f, 0x06, 0x01])),
+            [[0x04, 0xf0, 0x7e, 0x7f], [0x07, 0x06, 0x01, 0xf7]]
+        );
+        assert_eq!(
+            encode(0, &MidiMessage::SysEx(vec![0x01, 0x02])),
+            [[0x04, 0xf0, 0x01, 0x02], [0x05, 0xf7, 0x00, 0x00]]
+        );
+    }
+
+    #[test]
+    fn encoded_packets_decode() {
+        let mut decoder = UsbMidiDecoder::new();
+        [
+            MidiMessage::ControlChange {
+                channel: 9,
+                controller: 64,
+                value: 127,
+            },
+            MidiMessage::ProgramChange {
+                channel: 3,
+                program: 12,
+            },
+            MidiMessage::SysEx(vec![0x41, 0x10, 0x42, 0x12, 0x40]),
+            MidiMessage::TuneRequest,
+            MidiMessage::Start,
+        ]
+        .into_iter()
+        .for_each(|message| {
+            let transfer: Vec<u8> = encode(2, &message).concat();
+            assert_eq!(
+                decoder.decode(&transfer),
+                [UsbMidiEvent { cable: 2, message }]
+            );
+        });
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        [
+            MidiMessage::NoteOn {
+                channel: 15,
+                note: 127,
+                velocity: 1,
+            },
+            MidiMessage::PitchBend {
+                channel: 2,
+                value: -1234,
+            },
+            MidiMessage::SongPosition(0x3fff),
+            MidiMessage::Reset,
+        ]
+        .into_iter()
+        .for_each(|message| {
+            assert_eq!(MidiMessage::from_bytes(&message.to_bytes()), Some(message))
+        });
+    }
+}
@@ -0,0 +1,319 @@
+use std::{collections::VecDeque, f32::consts::PI, sync::Arc};
+
+use rustfft::{Fft, FftPlanner, num_complex::Complex};
+
+use crate::{spectrum::Window, wav::resample};
+
+pub const FFT_SIZE: usize = 2048;
+/// Synthesis hop; a quarter of the frame keeps Hann overlap-add smooth
+const SYNTHESIS_HOP: usize = FFT_SIZE / 4;
+
+fn wrap(phase: f32) -> f32 {
+    phase - 2. * PI * (phase / (2. * PI)).round()
+}
+
+/// Pitch-shift ratio for `semitones`
+pub fn ratio(semitones: f32) -> f32 {
+    2.0_f32.powf(semitones / 12.)
+}
+
+/// Phase vocoder with identity phase locking (Laroche & Dolson, 1999).
+/// Frames are read every analysis hop and overlap-added every synthesis hop,
+/// so the output is longer by their ratio at the same pitch.
+#[derive(Clone)]
+pub struct PhaseVocoder {
+    fft: Arc<dyn Fft<f32>>,
+    ifft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    /// Overlap-add gain of the squared window at the synthesis hop
+    norm: f32,
+    buf: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+    prev_phase: Vec<f32>,
+    synth_phase: Vec<f32>,
+    mags: Vec<f32>,
+    first: bool,
+}
+
+impl PhaseVocoder {
+    pub fn new() -> Self {
+        let mut planner = FftPlanner::<f32>::new();
+        let fft = planner.plan_fft_forward(FFT_SIZE);
+        let ifft = planner.plan_fft_inverse(FFT_SIZE);
+        let window = Window::Hann.coeffs(FFT_SIZE);
+        let norm = window.iter().map(|w| w * w).sum::<f32>() / SYNTHESIS_HOP as f32;
+        let bins = FFT_SIZE / 2 + 1;
+        Self {
+            scratch: vec![
+                Complex::default();
+                fft.get_inplace_scratch_len()
+                    .max(ifft.get_inplace_scratch_len())
+            ],
+            fft,
+            ifft,
+            window,
+            norm,
+            buf: vec![Complex::default(); FFT_SIZE],
+            prev_phase: vec![0.; bins],
+            synth_phase: vec![0.; bins],
+            mags: vec![0.; bins],
+            first: true,
+        }
+    }
+
+    /// Transforms one `FFT_SIZE` frame read `analysis_hop` samples after
+    /// the previous one. Returns the windowed frame to overlap-add.
+    pub fn process(&mut self, frame: &[f32], analysis_hop: f32) -> &[Complex<f32>] {
+        let bins = FFT_SIZE / 2 + 1;
+        self.buf.iter_mut().enumerate().for_each(|(idx, c)| {
+            *c = Complex::new(frame.get(idx).copied().unwrap_or(0.) * self.window[idx], 0.)
+        });
+        self.fft
+            .process_with_scratch(&mut self.buf, &mut self.scratch);
+
+        let phase: Vec<f32> = self.buf[..bins].iter().map(|c| c.arg()).collect();
+        self.buf[..bins]
+            .iter()
+            .zip(self.mags.iter_mut())
+            .for_each(|(c, m)| *m = c.norm());
+
+        if self.first {
+            self.synth_phase.copy_from_slice(&phase);
+            self.first = false;
+        } else {
+            let peaks = local_peaks(&self.mags);
+            let mut advanced = vec![0.; peaks.len()];
+            peaks.iter().enumerate().for_each(|(idx, p)| {
+                let omega = 2. * PI * *p as f32 / FFT_SIZE as f32;
+                let deviation = wrap(phase[*p] - self.prev_phase[*p] - omega * analysis_hop);
+                let freq = omega + deviation / analysis_hop;
+                advanced[idx] = self.synth_phase[*p] + freq * SYNTHESIS_HOP as f32;
+            });
+            let regions = regions(&peaks, &self.mags, bins);
+            (0..bins).for_each(|bin| {
+                self.synth_phase[bin] = match regions[bin] {
+                    Some(idx) => advanced[idx] + phase[bin] - phase[peaks[idx]],
+                    None => phase[bin],
+                };
+            });
+        }
+        self.prev_phase.copy_from_slice(&phase);
+
+        (0..bins).for_each(|bin| {
+            self.buf[bin] = Complex::from_polar(self.mags[bin], self.synth_phase[bin]);
+        });
+        (1..FFT_SIZE / 2).for_each(|bin| self.buf[FFT_SIZE - bin] = self.buf[bin].conj());
+        self.ifft
+            .process_with_scratch(&mut self.buf, &mut self.scratch);
+        let gain = 1. / (FFT_SIZE as f32 * self.norm);
+        self.buf
+            .iter_mut()
+            .zip(self.window.iter())
+            .for_each(|(c, w)| c.re *= w * gain);
+        &self.buf
+    }
+}
+
+impl Default for PhaseVocoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn local_peaks(mags: &[f32]) -> Vec<usize> {
+    let max = mags.iter().fold(0.0_f32, |acc, m| acc.max(*m));
+    // ignore peaks far below the loudest so noise doesn't split regions
+    let floor = max * 1e-4;
+    (0..mags.len())
+        .filter(|idx| {
+            let m = mags[*idx];
+            m > floor
+                && (*idx < 2 || m > mags[idx - 1].max(mags[idx - 2]))
+                && (idx + 2 >= mags.len() || m >= mags[idx + 1].max(mags[idx + 2]))
+        })
+        .collect()
+}
+
+/// Peak whose region of influence contains each bin; region edges are the
+/// magnitude minima between neighbouring peaks
+fn regions(peaks: &[usize], mags: &[f32], bins: usize) -> Vec<Option<usize>> {
+    let mut owner = vec![None; bins];
+    if peaks.is_empty() {
+        return owner;
+    }
+    let mut start = 0;
+    peaks.iter().enumerate().for_each(|(idx, p)| {
+        let end = match peaks.get(idx + 1) {
+            Some(next) => (*p..=*next)
+                .min_by(|a, b| mags[*a].total_cmp(&mags[*b]))
+                .unwrap_or(*p),
+            None => bins,
+        };
+        owner[start..end].iter_mut().for_each(|o| *o = Some(idx));
+        start = end;
+    });
+    owner
+}
+
+/// Offline time-stretch by `factor` (2.0 is twice as long) at the same pitch
+pub fn time_stretch(samples: &[f32], factor: f32) -> Vec<f32> {
+    let mut vocoder = PhaseVocoder::new();
+    let analysis_hop = SYNTHESIS_HOP as f32 / factor;
+    let out_len = (samples.len() as f32 * factor) as usize;
+    // the leading padding comes out stretched too
+    let skip = (FFT_SIZE as f32 * factor).round() as usize;
+    let mut out = vec![0.; skip + out_len + FFT_SIZE];
+
+    let mut padded = vec![0.; FFT_SIZE];
+    padded.extend_from_slice(samples);
+    padded.resize(padded.len() + FFT_SIZE, 0.);
+
+    let mut pos = 0.0_f32;
+    let mut prev = 0_usize;
+    let mut write = 0;
+    while (pos as usize) + FFT_SIZE <= padded.len() && write < out.len() {
+        let start = pos.round() as usize;
+        let hop = if write == 0 {
+            analysis_hop
+        } else {
+            (start - prev).max(1) as f32
+        };
+        let frame = vocoder.process(&padded[start..start + FFT_SIZE], hop);
+        out.iter_mut()
+            .skip(write)
+            .zip(frame.iter())
+            .for_each(|(o, c)| *o += c.re);
+        prev = start;
+        pos += analysis_hop;
+        write += SYNTHESIS_HOP;
+    }
+    out.into_iter().skip(skip).take(out_len).collect()
+}
+
+/// Offline pitch shift by `semitones` at the same duration
+pub fn pitch_shift(samples: &[f32], semitones: f32) -> Vec<f32> {
+    let r = ratio(semitones);
+    let mut shifted = resample(&time_stretch(samples, r), 1. / r);
+    shifted.resize(samples.len(), 0.);
+    shifted
+}
+
+/// Real-time pitch shifter: a streaming phase vocoder stretches by the
+/// ratio and a linear resampler reads the result back at that ratio. The
+/// analysis hop is rounded to whole samples, so the shift is exact to a few
+/// cents. Latency is about two frames.
+#[derive(Clone)]
+pub struct PitchShifter {
+    vocoder: PhaseVocoder,
+    analysis_hop: usize,
+    /// Resampling step, `SYNTHESIS_HOP / analysis_hop`
+    step: f32,
+    input: VecDeque<f32>,
+    /// Overlap-add accumulator; the front `SYNTHESIS_HOP` samples are done
+    overlap: Vec<f32>,
+    stretched: VecDeque<f32>,
+    read_pos: f32,
+    output: VecDeque<f32>,
+}
+
+impl PitchShifter {
+    /// Shifts down past about two octaves would read frames further apart
+    /// than a frame is long, and are rejected
+    pub fn new(semitones: f32) -> Result<Self, Box<dyn std::error::Error>> {
+        let hop = (SYNTHESIS_HOP as f32 / ratio(semitones)).round();
+        if !hop.is_finite() || hop >= FFT_SIZE as f32 {
+            return Err(format!("can't shift by {semitones} semitones").into());
+        }
+        let analysis_hop = (hop as usize).max(1);
+        Ok(Self {
+            vocoder: PhaseVocoder::new(),
+            analysis_hop,
+            step: SYNTHESIS_HOP as f32 / analysis_hop as f32,
+            input: VecDeque::from(vec![0.; FFT_SIZE - analysis_hop]),
+            overlap: vec![0.; FFT_SIZE],
+            stretched: VecDeque::new(),
+            read_pos: 0.,
+            output: VecDeque::from(vec![0.; FFT_SIZE]),
+        })
+    }
+
+    pub fn process(&mut self, x: f32) -> f32 {
+        self.input.push_back(x);
+        if self.input.len() == FFT_SIZE {
+            self.run_frame();
+        }
+        self.output.pop_front().unwrap_or(0.)
+    }
+
+    fn run_frame(&mut self) {
+        let frame: Vec<f32> = self.input.iter().copied().collect();
+        self.input.drain(..self.analysis_hop);
+
+        let spectrum = self.vocoder.process(&frame, self.analysis_hop as f32);
+        self.overlap
+            .iter_mut()
+            .zip(spectrum.iter())
+            .for_each(|(o, c)| *o += c.re);
+        self.stretched.extend(self.overlap.drain(..SYNTHESIS_HOP));
+        self.overlap.resize(FFT_SIZE, 0.);
+
+        // SYNTHESIS_HOP stretched samples read back as `analysis_hop` output
+        while self.read_pos + 1. < self.stretched.len() as f32 {
+            let idx = self.read_pos as usize;
+            let frac = self.read_pos - idx as f32;
+            let (a, b) = (self.stretched[idx], self.stretched[idx + 1]);
+            self.output.push_back(a + (b - a) * frac);
+            self.read_pos += self.step;
+        }
+        let consumed = (self.read_pos as usize).min(self.stretched.len());
+        self.stretched.drain(..consumed);
+        self.read_pos -= consumed as f32;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pitch::Yin;
+    use std::f32::consts::TAU;
+
+    const SR: u32 = 48000;
+
+    fn sine(freq: f32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| 0.5 * (TAU * freq * i as f32 / SR as f32).sin())
+            .collect()
+    }
+
+    fn f0(samples: &[f32]) -> f32 {
+        let mut yin = Yin::new(SR, 50., 1000.);
+        let mid = samples.len() / 2;
+        yin.detect(&samples[mid..mid + yin.frame_len()])
+            .expect("pitch")
+            .freq
+    }
+
+    #[test]
+    fn time_stretch_keeps_pitch() {
+        let input = sine(220., SR as usize);
+        let output = time_stretch(&input, 2.);
+        let ratio = output.len() as f32 / input.len() as f32;
+        assert!((ratio - 2.).abs() < 0.05, "{ratio}");
+        assert!((f0(&output) - 220.).abs() < 2., "{}", f0(&output));
+    }
+
+    #[test]
+    fn pitch_shift_keeps_length() {
+        let input = sine(220., SR as usize);
+        let output = pitch_shift(&input, 12.);
+        assert_eq!(output.len(), input.len());
+        assert!((f0(&output) - 440.).abs() < 4., "{}", f0(&output));
+    }
+
+    #[test]
+    fn shifter_rejects_out_of_range() {
+        assert!(PitchShifter::new(-30.).is_err());
+        assert!(PitchShifter::new(f32::NAN).is_err());
+        assert!(PitchShifter::new(7.).is_ok());
+    }
+}
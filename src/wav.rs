@@ -0,0 +1,109 @@
+use std::path::Path;
+
+use hound::{SampleFormat, WavReader, WavSpec, WavWriter};
+
+/// De-interleaved audio, one `Vec` per channel
+#[derive(Debug, Clone)]
+pub struct Wav {
+    pub channels: Vec<Vec<f32>>,
+    pub sample_rate: u32,
+}
+
+impl Wav {
+    pub fn new(channels: Vec<Vec<f32>>, sample_rate: u32) -> Self {
+        Self {
+            channels,
+            sample_rate,
+        }
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, hound::Error> {
+        let mut reader = WavReader::open(path)?;
+        let spec = reader.spec();
+        let interleaved: Vec<f32> = match spec.sample_format {
+            SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+            SampleFormat::Int => {
+                let scale = 1. / (1_i64 << (spec.bits_per_sample - 1)) as f32;
+                reader
+                    .samples::<i32>()
+                    .map(|s| s.map(|s| s as f32 * scale))
+                    .collect::<Result<_, _>>()?
+            }
+        };
+
+        let n = spec.channels as usize;
+        let mut channels = vec![Vec::with_capacity(interleaved.len() / n); n];
+        interleaved.chunks_exact(n).for_each(|frame| {
+            frame
+                .iter()
+                .zip(channels.iter_mut())
+                .for_each(|(s, ch)| ch.push(*s))
+        });
+
+        Ok(Self::new(channels, spec.sample_rate))
+    }
+
+    /// Writes 32-bit float samples
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<(), hound::Error> {
+        let spec = WavSpec {
+            channels: self.channels.len() as u16,
+            sample_rate: self.sample_rate,
+            bits_per_sample: 32,
+            sample_format: SampleFormat::Float,
+        };
+        let mut writer = WavWriter::create(path, spec)?;
+        for idx in 0..self.len() {
+            for ch in self.channels.iter() {
+                writer.write_sample(ch[idx])?;
+            }
+        }
+        writer.finalize()
+    }
+
+    /// Length in frames
+    pub fn len(&self) -> usize {
+        self.channels.first().map_or(0, Vec::len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.len() as f32 / self.sample_rate as f32
+    }
+
+    /// Frames with their channels side by side
+    #[allow(dead_code)] // for the interpreter's meter
+    pub fn interleaved(&self) -> Vec<f32> {
+        (0..self.len())
+            .flat_map(|idx| self.channels.iter().map(move |ch| ch[idx]))
+            .collect()
+    }
+
+    /// Average of all channels
+    pub fn mono(&self) -> Vec<f32> {
+        let scale = 1. / self.channels.len().max(1) as f32;
+        (0..self.len())
+            .map(|idx| self.channels.iter().map(|ch| ch[idx]).sum::<f32>() * scale)
+            .collect()
+    }
+}
+
+/// Linear-interpolation resampling by `ratio` output samples per input sample
+pub fn resample(input: &[f32], ratio: f32) -> Vec<f32> {
+    if input.is_empty() || ratio <= 0. {
+        return Vec::new();
+    }
+    let len = (input.len() as f32 * ratio).round() as usize;
+    (0..len)
+        .map(|idx| {
+            let pos = idx as f32 / ratio;
+            let i = pos as usize;
+            let frac = pos - i as f32;
+            let a = input[i.min(input.len() - 1)];
+            let b = input[(i + 1).min(input.len() - 1)];
+            a + (b - a) * frac
+        })
+        .collect()
+}